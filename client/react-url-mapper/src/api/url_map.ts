@@ -3,6 +3,14 @@ export const API_HOST=process.env.REACT_APP_API_HOST
 export interface URLMap {
   key: string
   url: string
+  created_at?: string
+  updated_at?: string
+  created_by?: string | null
+  updated_by?: string | null
+  expires_at?: string | null
+  not_before?: string | null
+  max_clicks?: number | null
+  used_clicks?: number
 }
 
 const buildHeaders = () => {
@@ -13,12 +21,17 @@ const buildHeaders = () => {
   return headers
 }
 
+export interface URLMapPage {
+  url_maps: Array<URLMap>
+  next: string | null
+}
+
 export const getURLMaps = async () => {
   const response = await fetch(`${API_HOST}/api/url_maps`, {
     headers: buildHeaders()
   })
-  let urlMaps: Array<URLMap> = await response.json()
-  return urlMaps
+  let page: URLMapPage = await response.json()
+  return page.url_maps
 }
 
 export const getURLMap = async (key: string) => {
@@ -1,31 +1,89 @@
 (function () {
   const AUTH_KEY = 'authorization'
+  const USER_KEY = 'x-user'
+  const TENANT_KEY = 'tenant'
+
+  // The user is recorded as the author of changes made from this page. The
+  // token and tenant are also kept in cookies, which the pages themselves are
+  // authenticated with.
+  const tenant = () => localStorage.getItem(TENANT_KEY) || ''
+  const headers = () => Object.assign({
+    'authorization': localStorage.getItem(AUTH_KEY),
+    'x-user': localStorage.getItem(USER_KEY) || '',
+  }, tenant() ? {'x-tenant': tenant()} : {})
+
+  // Datetime inputs are entered in UTC, empty ones are left out.
+  const formJSON = (form) => {
+    const data = Object.fromEntries(new FormData(form))
+    form.querySelectorAll('input[type=datetime-local]').forEach((input) => {
+      if (input.value) {
+        data[input.name] = `${input.value}:00Z`
+      } else {
+        delete data[input.name]
+      }
+    })
+    form.querySelectorAll('input[type=number]').forEach((input) => {
+      if (input.value) {
+        data[input.name] = Number(input.value)
+      } else {
+        delete data[input.name]
+      }
+    })
+    form.querySelectorAll('input[type=checkbox]').forEach((input) => {
+      data[input.name] = input.checked
+    })
+    return JSON.stringify(data)
+  }
+
+  // Url maps outside the default domain are addressed with `?domain=`.
+  const domainQuery = (domain) => domain ? `?${new URLSearchParams({domain})}` : ''
 
   document.addEventListener('DOMContentLoaded', () => {
     const auth_token = localStorage.getItem(AUTH_KEY)
     document.getElementById(AUTH_KEY).value = auth_token
+    document.getElementById(USER_KEY).value = localStorage.getItem(USER_KEY) || ''
+    document.getElementById(TENANT_KEY).value = tenant()
+
+    // Only the operator token may list tenants, others type theirs in.
+    fetch('/api/tenants', { headers: headers() }).then((response) => {
+      if (response.status == 200) {
+        response.json().then((tenants) => {
+          const list = document.getElementById('tenants')
+          tenants.forEach(({name}) => list.appendChild(new Option(name, name)))
+        })
+      }
+    })
   })
 
   document.getElementById('save_auth_token_button')
     .addEventListener('click', () => {
       const auth_token = document.getElementById(AUTH_KEY).value
       localStorage.setItem(AUTH_KEY, auth_token)
+      localStorage.setItem(USER_KEY, document.getElementById(USER_KEY).value)
+      const name = document.getElementById(TENANT_KEY).value.trim().toLowerCase()
+      localStorage.setItem(TENANT_KEY, name)
+      document.cookie = `authorization=${auth_token}; path=/admin; SameSite=Strict`
+      document.cookie = `tenant=${encodeURIComponent(name)}; path=/admin; SameSite=Strict`
+      if (document.getElementById('login')) {
+        window.location.href = '/admin/url_maps'
+      } else {
+        window.location.reload()
+      }
     })
 
   const create_form = document.querySelector('#create_url_map_form')
   if (create_form) {
     create_form.addEventListener('submit', (event) => {
       event.preventDefault()
-      const formData = new FormData(event.target)
 
       fetch('/api/url_maps', {
         method: 'POST',
-        headers: {'authorization': localStorage.getItem(AUTH_KEY)},
-        body: JSON.stringify(Object.fromEntries(formData)),
+        headers: headers(),
+        body: formJSON(event.target),
       }).then((response) => {
         if (response.status == 200) {
           alert('Create Url Map successfully!')
-          window.location.href = '/admin/url_maps'
+          window.location.href = `/admin/url_maps${domainQuery(new FormData(event.target).get('domain'))}`
         }
       })
     })
@@ -37,27 +95,128 @@
       event.preventDefault()
       const formData = new FormData(event.target)
       const key = formData.get('key')
+      const domain = formData.get('domain')
 
-      fetch(`/api/url_maps/${key}`, {
+      fetch(`/api/url_maps/${key}${domainQuery(domain)}`, {
         method: 'PUT',
-        headers: {'authorization': localStorage.getItem(AUTH_KEY)},
-        body: JSON.stringify(Object.fromEntries(formData)),
+        headers: headers(),
+        body: formJSON(event.target),
       }).then((response) => {
         if (response.status == 200) {
           alert(`Updated Url Map for ${key} successfully!`)
-          window.location.href = '/admin/url_maps'
+          window.location.href = `/admin/url_maps${domainQuery(domain)}`
+        }
+      })
+    })
+  }
+
+  const import_form = document.getElementById('import_url_maps_form')
+  if (import_form) {
+    import_form.addEventListener('submit', (event) => {
+      event.preventDefault()
+      const formData = new FormData(event.target)
+      const file = formData.get('file')
+      const params = new URLSearchParams({
+        format: formData.get('format') || file.name.split('.').pop().toLowerCase(),
+        mode: formData.get('mode'),
+        dry_run: formData.get('dry_run') ? 'true' : 'false',
+      })
+
+      file.text().then((body) => fetch(`/api/url_maps/import?${params}`, {
+        method: 'POST',
+        headers: headers(),
+        body: body,
+      })).then((response) => response.json()).then((report) => {
+        const summary = document.getElementById('import_summary')
+        if (report.error) {
+          summary.textContent = report.error
+          return
         }
+        summary.textContent = `${report.dry_run ? 'Dry run' : 'Import'} ${report.applied ? 'applied' : 'not applied'}: ` +
+          `${report.created} created, ${report.updated} updated, ` +
+          `${report.skipped} skipped, ${report.rejected} rejected`
+        const rows = document.getElementById('import_report')
+        rows.innerHTML = ''
+        report.rows.forEach((row) => {
+          const tr = document.createElement('tr');
+          [row.row, row.domain || '', row.key || '', row.status, row.error || ''].forEach((value) => {
+            const td = document.createElement('td')
+            td.textContent = value
+            tr.appendChild(td)
+          })
+          rows.appendChild(tr)
+        })
       })
     })
   }
 
+  const revert_url_map_links = document.querySelectorAll('.revert-url-map')
+  Array.from(revert_url_map_links).forEach(link => {
+    link.addEventListener('click', (event) => {
+      event.preventDefault()
+      const key = link.getAttribute('data-key')
+      const domain = link.getAttribute('data-domain')
+      const version = Number(link.getAttribute('data-version'))
+      fetch(`/api/url_maps/${key}/revert${domainQuery(domain)}`, {
+        method: 'POST',
+        headers: headers(),
+        body: JSON.stringify({version}),
+      }).then((response) => {
+        if (response.status == 200) {
+          alert(`Reverted Url Map for ${key} to version ${version} successfully!`)
+          window.location.reload()
+        }
+      })
+    })
+  })
+
+  const restore_url_map_links = document.querySelectorAll('.restore-url-map')
+  Array.from(restore_url_map_links).forEach(link => {
+    link.addEventListener('click', (event) => {
+      event.preventDefault()
+      const key = link.getAttribute('data')
+      const domain = link.getAttribute('data-domain')
+      fetch(`/api/url_maps/trash/${key}/restore${domainQuery(domain)}`, {
+        method: 'POST',
+        headers: headers(),
+      }).then((response) => {
+        if (response.status == 200) {
+          alert(`Restored Url Map for ${key} successfully!`)
+          window.location.reload()
+        }
+      })
+    })
+  })
+
+  const purge_url_map_links = document.querySelectorAll('.purge-url-map')
+  Array.from(purge_url_map_links).forEach(link => {
+    link.addEventListener('click', (event) => {
+      event.preventDefault()
+      const key = link.getAttribute('data')
+      const domain = link.getAttribute('data-domain')
+      if (!confirm(`Permanently delete the Url Map for ${key}?`)) {
+        return
+      }
+      fetch(`/api/url_maps/trash/${key}${domainQuery(domain)}`, {
+        method: 'DELETE',
+        headers: headers(),
+      }).then((response) => {
+        if (response.status == 200) {
+          alert(`Purged Url Map for ${key} successfully!`)
+          window.location.reload()
+        }
+      })
+    })
+  })
+
   const delete_url_map_links = document.querySelectorAll(".delete-url-map")
   Array.from(delete_url_map_links).forEach(link => {
     link.addEventListener("click", () => {
       const key = link.getAttribute('data')
-      fetch(`/api/url_maps/${key}`, {
+      const domain = link.getAttribute('data-domain')
+      fetch(`/api/url_maps/${key}${domainQuery(domain)}`, {
         method: 'DELETE',
-        headers: {'authorization': localStorage.getItem(AUTH_KEY)},
+        headers: headers(),
       }).then((response) => {
         if (response.status == 200) {
           alert(`Deleted Url Map for ${key} successfully!`)
@@ -66,4 +225,125 @@
       })
     })
   })
+
+  const create_domain_form = document.getElementById('create_domain_form')
+  if (create_domain_form) {
+    create_domain_form.addEventListener('submit', (event) => {
+      event.preventDefault()
+
+      fetch('/api/domains', {
+        method: 'POST',
+        headers: headers(),
+        body: formJSON(event.target),
+      }).then((response) => response.json().then((body) => {
+        if (response.status == 200) {
+          alert(`Created Domain ${body.name} successfully!`)
+          window.location.reload()
+        } else {
+          alert(body.error || body)
+        }
+      }))
+    })
+  }
+
+  const delete_domain_links = document.querySelectorAll('.delete-domain')
+  Array.from(delete_domain_links).forEach(link => {
+    link.addEventListener('click', (event) => {
+      event.preventDefault()
+      const name = link.getAttribute('data')
+      fetch(`/api/domains/${name}`, {
+        method: 'DELETE',
+        headers: headers(),
+      }).then((response) => {
+        if (response.status == 200) {
+          alert(`Deleted Domain ${name} successfully!`)
+          window.location.reload()
+        } else {
+          response.json().then((body) => alert(body.error || body))
+        }
+      })
+    })
+  })
+
+  const create_tenant_form = document.getElementById('create_tenant_form')
+  if (create_tenant_form) {
+    create_tenant_form.addEventListener('submit', (event) => {
+      event.preventDefault()
+
+      fetch('/api/tenants', {
+        method: 'POST',
+        headers: headers(),
+        body: formJSON(event.target),
+      }).then((response) => response.json().then((body) => {
+        if (response.status == 200) {
+          alert(`Created Tenant ${body.name} successfully!`)
+          window.location.reload()
+        } else {
+          alert(body.error || body)
+        }
+      }))
+    })
+  }
+
+  const delete_tenant_links = document.querySelectorAll('.delete-tenant')
+  Array.from(delete_tenant_links).forEach(link => {
+    link.addEventListener('click', (event) => {
+      event.preventDefault()
+      const name = link.getAttribute('data')
+      fetch(`/api/tenants/${name}`, {
+        method: 'DELETE',
+        headers: headers(),
+      }).then((response) => {
+        if (response.status == 200) {
+          alert(`Deleted Tenant ${name} successfully!`)
+          window.location.reload()
+        } else {
+          response.text().then((body) => alert(body))
+        }
+      })
+    })
+  })
+
+  // The token is only ever shown here, once.
+  const create_api_token_form = document.getElementById('create_api_token_form')
+  if (create_api_token_form) {
+    create_api_token_form.addEventListener('submit', (event) => {
+      event.preventDefault()
+      const name = create_api_token_form.getAttribute('data')
+
+      fetch(`/api/tenants/${name}/tokens`, {
+        method: 'POST',
+        headers: headers(),
+        body: formJSON(event.target),
+      }).then((response) => response.text().then((body) => {
+        if (response.status == 200) {
+          const token = JSON.parse(body).token
+          alert(`Token: ${token}\nAuthorization: ${btoa(token)}\n\nCopy it now, it will not be shown again.`)
+          window.location.reload()
+        } else {
+          alert(body)
+        }
+      }))
+    })
+  }
+
+  const delete_api_token_links = document.querySelectorAll('.delete-api-token')
+  Array.from(delete_api_token_links).forEach(link => {
+    link.addEventListener('click', (event) => {
+      event.preventDefault()
+      const name = link.getAttribute('data-tenant')
+      const id = link.getAttribute('data')
+      fetch(`/api/tenants/${name}/tokens/${id}`, {
+        method: 'DELETE',
+        headers: headers(),
+      }).then((response) => {
+        if (response.status == 200) {
+          alert(`Deleted Token ${id} successfully!`)
+          window.location.reload()
+        } else {
+          response.text().then((body) => alert(body))
+        }
+      })
+    })
+  })
 })()
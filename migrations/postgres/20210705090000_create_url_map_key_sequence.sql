@@ -0,0 +1,2 @@
+-- Add migration script here
+CREATE SEQUENCE IF NOT EXISTS url_map_key_seq;
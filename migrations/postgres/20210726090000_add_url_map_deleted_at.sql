@@ -0,0 +1,4 @@
+-- Add migration script here
+ALTER TABLE url_maps
+  ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ,
+  ADD COLUMN IF NOT EXISTS deleted_by TEXT;
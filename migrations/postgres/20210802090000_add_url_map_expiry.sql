@@ -0,0 +1,5 @@
+-- Add migration script here
+ALTER TABLE url_maps
+  ADD COLUMN IF NOT EXISTS expires_at TIMESTAMPTZ,
+  ADD COLUMN IF NOT EXISTS not_before TIMESTAMPTZ;
+CREATE INDEX IF NOT EXISTS url_maps_expires_at ON url_maps (expires_at);
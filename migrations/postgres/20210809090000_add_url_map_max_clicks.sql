@@ -0,0 +1,4 @@
+-- Add migration script here
+ALTER TABLE url_maps
+  ADD COLUMN IF NOT EXISTS max_clicks BIGINT,
+  ADD COLUMN IF NOT EXISTS used_clicks BIGINT NOT NULL DEFAULT 0;
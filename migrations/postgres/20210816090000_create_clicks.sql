@@ -0,0 +1,10 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS clicks (
+  id BIGSERIAL PRIMARY KEY,
+  key VARCHAR(50) NOT NULL,
+  clicked_at TIMESTAMPTZ NOT NULL,
+  referrer TEXT,
+  user_agent TEXT,
+  ip TEXT
+);
+CREATE INDEX IF NOT EXISTS clicks_key_clicked_at ON clicks (key, clicked_at);
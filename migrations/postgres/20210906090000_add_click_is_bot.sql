@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE clicks ADD COLUMN is_bot BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,29 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS domains (
+  name VARCHAR(253) PRIMARY KEY,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  created_by TEXT
+);
+-- The default domain, serving every host that is not registered. Existing
+-- url maps and clicks belong to it.
+INSERT INTO domains (name) VALUES ('');
+
+ALTER TABLE url_maps ADD COLUMN domain VARCHAR(253) NOT NULL DEFAULT '' REFERENCES domains (name);
+ALTER TABLE url_maps DROP CONSTRAINT url_maps_pkey, ADD PRIMARY KEY (domain, key);
+
+ALTER TABLE url_map_history ADD COLUMN domain VARCHAR(253) NOT NULL DEFAULT '';
+DROP INDEX IF EXISTS url_map_history_key_changed_at;
+CREATE INDEX IF NOT EXISTS url_map_history_domain_key_changed_at ON url_map_history (domain, key, changed_at);
+
+ALTER TABLE clicks ADD COLUMN domain VARCHAR(253) NOT NULL DEFAULT '';
+DROP INDEX IF EXISTS clicks_key_clicked_at;
+CREATE INDEX IF NOT EXISTS clicks_domain_key_clicked_at ON clicks (domain, key, clicked_at);
+
+ALTER TABLE click_visitors ADD COLUMN domain VARCHAR(253) NOT NULL DEFAULT '';
+ALTER TABLE click_visitors DROP CONSTRAINT click_visitors_pkey, ADD PRIMARY KEY (domain, key, day);
+
+ALTER TABLE clicks_hourly ADD COLUMN domain VARCHAR(253) NOT NULL DEFAULT '';
+ALTER TABLE clicks_hourly DROP CONSTRAINT clicks_hourly_pkey, ADD PRIMARY KEY (domain, key, bucket, field, value, is_bot);
+
+ALTER TABLE clicks_daily ADD COLUMN domain VARCHAR(253) NOT NULL DEFAULT '';
+ALTER TABLE clicks_daily DROP CONSTRAINT clicks_daily_pkey, ADD PRIMARY KEY (domain, key, bucket, field, value, is_bot);
@@ -0,0 +1,40 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS tenants (
+  name VARCHAR(63) PRIMARY KEY,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  created_by TEXT
+);
+-- Everything created before tenants existed belongs to the default tenant.
+INSERT INTO tenants (name) VALUES ('default');
+
+-- Only a hash of each token is stored, the token itself is shown once when
+-- it is created.
+CREATE TABLE IF NOT EXISTS api_tokens (
+  id BIGSERIAL PRIMARY KEY,
+  tenant VARCHAR(63) NOT NULL REFERENCES tenants (name),
+  name TEXT NOT NULL,
+  token_hash CHAR(64) NOT NULL UNIQUE,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  created_by TEXT
+);
+
+-- The default domain is shared by every tenant and belongs to none.
+ALTER TABLE domains ADD COLUMN tenant VARCHAR(63) REFERENCES tenants (name);
+UPDATE domains SET tenant = 'default' WHERE name <> '';
+
+ALTER TABLE url_maps ADD COLUMN tenant VARCHAR(63) NOT NULL DEFAULT 'default' REFERENCES tenants (name);
+CREATE INDEX IF NOT EXISTS url_maps_tenant_domain_key ON url_maps (tenant, domain, key);
+
+ALTER TABLE url_map_history ADD COLUMN tenant VARCHAR(63) NOT NULL DEFAULT 'default';
+
+ALTER TABLE clicks ADD COLUMN tenant VARCHAR(63) NOT NULL DEFAULT 'default';
+CREATE INDEX IF NOT EXISTS clicks_tenant_clicked_at ON clicks (tenant, clicked_at);
+
+ALTER TABLE click_visitors ADD COLUMN tenant VARCHAR(63) NOT NULL DEFAULT 'default';
+ALTER TABLE click_visitors DROP CONSTRAINT click_visitors_pkey, ADD PRIMARY KEY (tenant, domain, key, day);
+
+ALTER TABLE clicks_hourly ADD COLUMN tenant VARCHAR(63) NOT NULL DEFAULT 'default';
+ALTER TABLE clicks_hourly DROP CONSTRAINT clicks_hourly_pkey, ADD PRIMARY KEY (tenant, domain, key, bucket, field, value, is_bot);
+
+ALTER TABLE clicks_daily ADD COLUMN tenant VARCHAR(63) NOT NULL DEFAULT 'default';
+ALTER TABLE clicks_daily DROP CONSTRAINT clicks_daily_pkey, ADD PRIMARY KEY (tenant, domain, key, bucket, field, value, is_bot);
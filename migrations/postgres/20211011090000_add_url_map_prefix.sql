@@ -0,0 +1,4 @@
+-- Add migration script here
+-- Prefix url maps also redirect every path below their key, with the rest of
+-- the path appended to their url.
+ALTER TABLE url_maps ADD COLUMN prefix BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,6 @@
+-- Add migration script here
+-- Clicks are flagged once counted in the rollups, so clicks written after the
+-- rollups passed their time are still rolled up later.
+ALTER TABLE clicks ADD COLUMN rolled_up BOOLEAN NOT NULL DEFAULT FALSE;
+UPDATE clicks SET rolled_up = TRUE WHERE clicked_at < (SELECT rolled_until FROM click_rollups WHERE id = 1);
+CREATE INDEX clicks_unrolled ON clicks (clicked_at) WHERE NOT rolled_up;
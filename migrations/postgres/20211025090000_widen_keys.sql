@@ -0,0 +1,8 @@
+-- Add migration script here
+-- Hierarchical keys hold a whole path, which 50 characters cut short.
+ALTER TABLE url_maps ALTER COLUMN key TYPE VARCHAR(255);
+ALTER TABLE url_map_history ALTER COLUMN key TYPE VARCHAR(255);
+ALTER TABLE clicks ALTER COLUMN key TYPE VARCHAR(255);
+ALTER TABLE click_visitors ALTER COLUMN key TYPE VARCHAR(255);
+ALTER TABLE clicks_hourly ALTER COLUMN key TYPE VARCHAR(255);
+ALTER TABLE clicks_daily ALTER COLUMN key TYPE VARCHAR(255);
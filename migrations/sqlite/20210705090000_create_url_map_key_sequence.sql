@@ -0,0 +1,4 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS url_map_key_sequence (
+  id INTEGER PRIMARY KEY AUTOINCREMENT
+);
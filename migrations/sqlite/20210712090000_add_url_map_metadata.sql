@@ -0,0 +1,9 @@
+-- Add migration script here
+-- Timestamps are stored as text in the format written by sqlx, so that they
+-- sort in chronological order. ALTER TABLE only accepts constant defaults,
+-- existing rows are stamped with the time of the migration afterwards.
+ALTER TABLE url_maps ADD COLUMN created_at TEXT NOT NULL DEFAULT '1970-01-01 00:00:00';
+ALTER TABLE url_maps ADD COLUMN updated_at TEXT NOT NULL DEFAULT '1970-01-01 00:00:00';
+ALTER TABLE url_maps ADD COLUMN created_by TEXT;
+ALTER TABLE url_maps ADD COLUMN updated_by TEXT;
+UPDATE url_maps SET created_at = strftime('%Y-%m-%d %H:%M:%f', 'now'), updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now');
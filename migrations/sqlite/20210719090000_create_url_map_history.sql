@@ -0,0 +1,15 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS url_map_history (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  key VARCHAR(50) NOT NULL,
+  action TEXT NOT NULL,
+  old_url TEXT,
+  new_url TEXT,
+  actor TEXT,
+  changed_at TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS url_map_history_key_changed_at ON url_map_history (key, changed_at);
+
+-- Url maps created before history was kept start with their creation.
+INSERT INTO url_map_history (key, action, new_url, actor, changed_at)
+  SELECT key, 'create', url, created_by, created_at FROM url_maps;
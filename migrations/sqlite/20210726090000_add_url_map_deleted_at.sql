@@ -0,0 +1,3 @@
+-- Add migration script here
+ALTER TABLE url_maps ADD COLUMN deleted_at TEXT;
+ALTER TABLE url_maps ADD COLUMN deleted_by TEXT;
@@ -0,0 +1,4 @@
+-- Add migration script here
+ALTER TABLE url_maps ADD COLUMN expires_at TEXT;
+ALTER TABLE url_maps ADD COLUMN not_before TEXT;
+CREATE INDEX IF NOT EXISTS url_maps_expires_at ON url_maps (expires_at);
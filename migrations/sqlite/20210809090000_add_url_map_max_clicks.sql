@@ -0,0 +1,3 @@
+-- Add migration script here
+ALTER TABLE url_maps ADD COLUMN max_clicks INTEGER;
+ALTER TABLE url_maps ADD COLUMN used_clicks INTEGER NOT NULL DEFAULT 0;
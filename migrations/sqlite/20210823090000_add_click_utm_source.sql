@@ -0,0 +1,3 @@
+-- Add migration script here
+ALTER TABLE clicks ADD COLUMN utm_source TEXT;
+CREATE INDEX IF NOT EXISTS clicks_clicked_at ON clicks (clicked_at);
@@ -0,0 +1,3 @@
+-- Add migration script here
+ALTER TABLE clicks ADD COLUMN country TEXT;
+ALTER TABLE clicks ADD COLUMN region TEXT;
@@ -0,0 +1,7 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS click_visitors (
+  key VARCHAR(50) NOT NULL,
+  day TEXT NOT NULL,
+  sketch BLOB NOT NULL,
+  PRIMARY KEY (key, day)
+);
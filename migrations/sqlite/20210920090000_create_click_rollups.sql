@@ -0,0 +1,29 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS clicks_hourly (
+  key VARCHAR(50) NOT NULL,
+  bucket TEXT NOT NULL,
+  field TEXT NOT NULL,
+  value TEXT NOT NULL,
+  is_bot BOOLEAN NOT NULL,
+  clicks BIGINT NOT NULL,
+  PRIMARY KEY (key, bucket, field, value, is_bot)
+);
+CREATE INDEX IF NOT EXISTS clicks_hourly_bucket ON clicks_hourly (bucket);
+
+CREATE TABLE IF NOT EXISTS clicks_daily (
+  key VARCHAR(50) NOT NULL,
+  bucket TEXT NOT NULL,
+  field TEXT NOT NULL,
+  value TEXT NOT NULL,
+  is_bot BOOLEAN NOT NULL,
+  clicks BIGINT NOT NULL,
+  PRIMARY KEY (key, bucket, field, value, is_bot)
+);
+CREATE INDEX IF NOT EXISTS clicks_daily_bucket ON clicks_daily (bucket);
+
+-- Single row holding the end of the clicks rolled up so far.
+CREATE TABLE IF NOT EXISTS click_rollups (
+  id INTEGER PRIMARY KEY,
+  rolled_until TEXT
+);
+INSERT INTO click_rollups (id, rolled_until) VALUES (1, NULL);
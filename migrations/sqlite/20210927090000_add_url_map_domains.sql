@@ -0,0 +1,82 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS domains (
+  name VARCHAR(253) PRIMARY KEY,
+  created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+  created_by TEXT
+);
+-- The default domain, serving every host that is not registered. Existing
+-- url maps and clicks belong to it.
+INSERT INTO domains (name) VALUES ('');
+
+-- SQLite cannot change a primary key in place, tables whose key gains the
+-- domain are rebuilt.
+CREATE TABLE url_maps_new (
+  domain VARCHAR(253) NOT NULL DEFAULT '' REFERENCES domains (name),
+  key VARCHAR(50) NOT NULL,
+  url TEXT NOT NULL,
+  created_at TEXT NOT NULL DEFAULT '1970-01-01 00:00:00',
+  updated_at TEXT NOT NULL DEFAULT '1970-01-01 00:00:00',
+  created_by TEXT,
+  updated_by TEXT,
+  deleted_at TEXT,
+  deleted_by TEXT,
+  expires_at TEXT,
+  not_before TEXT,
+  max_clicks INTEGER,
+  used_clicks INTEGER NOT NULL DEFAULT 0,
+  PRIMARY KEY (domain, key)
+);
+INSERT INTO url_maps_new (key, url, created_at, updated_at, created_by, updated_by, deleted_at, deleted_by, expires_at, not_before, max_clicks, used_clicks)
+  SELECT key, url, created_at, updated_at, created_by, updated_by, deleted_at, deleted_by, expires_at, not_before, max_clicks, used_clicks FROM url_maps;
+DROP TABLE url_maps;
+ALTER TABLE url_maps_new RENAME TO url_maps;
+CREATE INDEX IF NOT EXISTS url_maps_expires_at ON url_maps (expires_at);
+
+ALTER TABLE url_map_history ADD COLUMN domain VARCHAR(253) NOT NULL DEFAULT '';
+DROP INDEX IF EXISTS url_map_history_key_changed_at;
+CREATE INDEX IF NOT EXISTS url_map_history_domain_key_changed_at ON url_map_history (domain, key, changed_at);
+
+ALTER TABLE clicks ADD COLUMN domain VARCHAR(253) NOT NULL DEFAULT '';
+DROP INDEX IF EXISTS clicks_key_clicked_at;
+CREATE INDEX IF NOT EXISTS clicks_domain_key_clicked_at ON clicks (domain, key, clicked_at);
+
+CREATE TABLE click_visitors_new (
+  domain VARCHAR(253) NOT NULL DEFAULT '',
+  key VARCHAR(50) NOT NULL,
+  day TEXT NOT NULL,
+  sketch BLOB NOT NULL,
+  PRIMARY KEY (domain, key, day)
+);
+INSERT INTO click_visitors_new (key, day, sketch) SELECT key, day, sketch FROM click_visitors;
+DROP TABLE click_visitors;
+ALTER TABLE click_visitors_new RENAME TO click_visitors;
+
+CREATE TABLE clicks_hourly_new (
+  domain VARCHAR(253) NOT NULL DEFAULT '',
+  key VARCHAR(50) NOT NULL,
+  bucket TEXT NOT NULL,
+  field TEXT NOT NULL,
+  value TEXT NOT NULL,
+  is_bot BOOLEAN NOT NULL,
+  clicks BIGINT NOT NULL,
+  PRIMARY KEY (domain, key, bucket, field, value, is_bot)
+);
+INSERT INTO clicks_hourly_new (key, bucket, field, value, is_bot, clicks) SELECT key, bucket, field, value, is_bot, clicks FROM clicks_hourly;
+DROP TABLE clicks_hourly;
+ALTER TABLE clicks_hourly_new RENAME TO clicks_hourly;
+CREATE INDEX IF NOT EXISTS clicks_hourly_bucket ON clicks_hourly (bucket);
+
+CREATE TABLE clicks_daily_new (
+  domain VARCHAR(253) NOT NULL DEFAULT '',
+  key VARCHAR(50) NOT NULL,
+  bucket TEXT NOT NULL,
+  field TEXT NOT NULL,
+  value TEXT NOT NULL,
+  is_bot BOOLEAN NOT NULL,
+  clicks BIGINT NOT NULL,
+  PRIMARY KEY (domain, key, bucket, field, value, is_bot)
+);
+INSERT INTO clicks_daily_new (key, bucket, field, value, is_bot, clicks) SELECT key, bucket, field, value, is_bot, clicks FROM clicks_daily;
+DROP TABLE clicks_daily;
+ALTER TABLE clicks_daily_new RENAME TO clicks_daily;
+CREATE INDEX IF NOT EXISTS clicks_daily_bucket ON clicks_daily (bucket);
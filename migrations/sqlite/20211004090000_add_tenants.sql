@@ -0,0 +1,98 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS tenants (
+  name VARCHAR(63) PRIMARY KEY,
+  created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+  created_by TEXT
+);
+-- Everything created before tenants existed belongs to the default tenant.
+INSERT INTO tenants (name) VALUES ('default');
+
+-- Only a hash of each token is stored, the token itself is shown once when
+-- it is created.
+CREATE TABLE IF NOT EXISTS api_tokens (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  tenant VARCHAR(63) NOT NULL REFERENCES tenants (name),
+  name TEXT NOT NULL,
+  token_hash CHAR(64) NOT NULL UNIQUE,
+  created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+  created_by TEXT
+);
+
+-- The default domain is shared by every tenant and belongs to none.
+ALTER TABLE domains ADD COLUMN tenant VARCHAR(63) REFERENCES tenants (name);
+UPDATE domains SET tenant = 'default' WHERE name <> '';
+
+-- SQLite only adds columns with a foreign key when they default to NULL, and
+-- cannot change a primary key in place, so these tables are rebuilt.
+CREATE TABLE url_maps_new (
+  domain VARCHAR(253) NOT NULL DEFAULT '' REFERENCES domains (name),
+  key VARCHAR(50) NOT NULL,
+  url TEXT NOT NULL,
+  created_at TEXT NOT NULL DEFAULT '1970-01-01 00:00:00',
+  updated_at TEXT NOT NULL DEFAULT '1970-01-01 00:00:00',
+  created_by TEXT,
+  updated_by TEXT,
+  deleted_at TEXT,
+  deleted_by TEXT,
+  expires_at TEXT,
+  not_before TEXT,
+  max_clicks INTEGER,
+  used_clicks INTEGER NOT NULL DEFAULT 0,
+  tenant VARCHAR(63) NOT NULL DEFAULT 'default' REFERENCES tenants (name),
+  PRIMARY KEY (domain, key)
+);
+INSERT INTO url_maps_new (domain, key, url, created_at, updated_at, created_by, updated_by, deleted_at, deleted_by, expires_at, not_before, max_clicks, used_clicks)
+  SELECT domain, key, url, created_at, updated_at, created_by, updated_by, deleted_at, deleted_by, expires_at, not_before, max_clicks, used_clicks FROM url_maps;
+DROP TABLE url_maps;
+ALTER TABLE url_maps_new RENAME TO url_maps;
+CREATE INDEX IF NOT EXISTS url_maps_expires_at ON url_maps (expires_at);
+CREATE INDEX IF NOT EXISTS url_maps_tenant_domain_key ON url_maps (tenant, domain, key);
+
+ALTER TABLE url_map_history ADD COLUMN tenant VARCHAR(63) NOT NULL DEFAULT 'default';
+
+ALTER TABLE clicks ADD COLUMN tenant VARCHAR(63) NOT NULL DEFAULT 'default';
+CREATE INDEX IF NOT EXISTS clicks_tenant_clicked_at ON clicks (tenant, clicked_at);
+
+CREATE TABLE click_visitors_new (
+  tenant VARCHAR(63) NOT NULL DEFAULT 'default',
+  domain VARCHAR(253) NOT NULL DEFAULT '',
+  key VARCHAR(50) NOT NULL,
+  day TEXT NOT NULL,
+  sketch BLOB NOT NULL,
+  PRIMARY KEY (tenant, domain, key, day)
+);
+INSERT INTO click_visitors_new (domain, key, day, sketch) SELECT domain, key, day, sketch FROM click_visitors;
+DROP TABLE click_visitors;
+ALTER TABLE click_visitors_new RENAME TO click_visitors;
+
+CREATE TABLE clicks_hourly_new (
+  tenant VARCHAR(63) NOT NULL DEFAULT 'default',
+  domain VARCHAR(253) NOT NULL DEFAULT '',
+  key VARCHAR(50) NOT NULL,
+  bucket TEXT NOT NULL,
+  field TEXT NOT NULL,
+  value TEXT NOT NULL,
+  is_bot BOOLEAN NOT NULL,
+  clicks BIGINT NOT NULL,
+  PRIMARY KEY (tenant, domain, key, bucket, field, value, is_bot)
+);
+INSERT INTO clicks_hourly_new (domain, key, bucket, field, value, is_bot, clicks) SELECT domain, key, bucket, field, value, is_bot, clicks FROM clicks_hourly;
+DROP TABLE clicks_hourly;
+ALTER TABLE clicks_hourly_new RENAME TO clicks_hourly;
+CREATE INDEX IF NOT EXISTS clicks_hourly_bucket ON clicks_hourly (bucket);
+
+CREATE TABLE clicks_daily_new (
+  tenant VARCHAR(63) NOT NULL DEFAULT 'default',
+  domain VARCHAR(253) NOT NULL DEFAULT '',
+  key VARCHAR(50) NOT NULL,
+  bucket TEXT NOT NULL,
+  field TEXT NOT NULL,
+  value TEXT NOT NULL,
+  is_bot BOOLEAN NOT NULL,
+  clicks BIGINT NOT NULL,
+  PRIMARY KEY (tenant, domain, key, bucket, field, value, is_bot)
+);
+INSERT INTO clicks_daily_new (domain, key, bucket, field, value, is_bot, clicks) SELECT domain, key, bucket, field, value, is_bot, clicks FROM clicks_daily;
+DROP TABLE clicks_daily;
+ALTER TABLE clicks_daily_new RENAME TO clicks_daily;
+CREATE INDEX IF NOT EXISTS clicks_daily_bucket ON clicks_daily (bucket);
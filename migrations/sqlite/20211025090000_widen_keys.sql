@@ -0,0 +1,6 @@
+-- Add migration script here
+-- Hierarchical keys hold a whole path, which 50 characters cut short. SQLite
+-- does not enforce the length of VARCHAR columns, so keys of up to 255
+-- characters already fit and this only keeps the migrations of both backends
+-- in step.
+SELECT 1;
@@ -1,15 +1,128 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use config::{Config as RConfig, Environment, File};
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 use std::env;
 
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Postgres,
+    Sqlite,
+    Memory,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Database {
+    pub backend: Backend,
     pub url: String,
     pub max_connections: u32,
 }
 
+// Up to `exports` exports stream at a time, each holding a connection of its
+// own, and one is abandoned once its client has not read a row for
+// `export_timeout` seconds.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manager {
+    pub channel_capacity: usize,
+    pub timeout: u64,
+    pub exports: usize,
+    pub export_timeout: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Cache {
+    pub capacity: usize,
+    pub ttl: u64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyStrategy {
+    Random,
+    Sqids,
+    Hash,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Keys {
+    pub strategy: KeyStrategy,
+    pub length: usize,
+    pub max_attempts: u32,
+}
+
+// Both in seconds. Url maps are purged from the trash once they have been in
+// it for `purge_after`, 0 keeps them until purged by hand.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Trash {
+    pub purge_after: u64,
+    pub purge_interval: u64,
+}
+
+// Expired url maps redirect to `fallback_url` when set, otherwise they answer
+// 410 Gone. They are moved to the trash `archive_after` seconds after they
+// expire, checked every `archive_interval` seconds.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Expiry {
+    pub fallback_url: Option<String>,
+    pub archive_after: u64,
+    pub archive_interval: u64,
+}
+
+// Clicks are buffered in memory, up to `buffer` of them, and written
+// `batch_size` at a time or every `flush_interval` milliseconds, whichever
+// comes first.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Clicks {
+    pub buffer: usize,
+    pub batch_size: usize,
+    pub flush_interval: u64,
+}
+
+// Path to a MaxMind `.mmdb` file, GeoLite2 or GeoIP2, Country or City, used
+// to locate clicks. Nothing is looked up when unset.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GeoIp {
+    pub database: Option<String>,
+}
+
+// In privacy mode ips are truncated before clicks are recorded. Clicks are
+// purged `retention` seconds after they were recorded, rolled up or not, 0
+// keeps them forever, checked every `purge_interval` seconds. Unique visitor
+// counts are kept. Visitors are hashed with a daily salt derived from
+// `secret`, which every replica must share and which privacy mode requires.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Privacy {
+    pub enabled: bool,
+    pub secret: Option<String>,
+    pub retention: u64,
+    pub purge_interval: u64,
+}
+
+impl Privacy {
+    // A secret known to anyone, such as a default, would let visitor hashes
+    // be brute forced back to ips.
+    fn validate(&self) -> Result<()> {
+        match self.secret.as_deref() {
+            Some(secret) if !secret.is_empty() => Ok(()),
+            _ if self.enabled => Err(anyhow!("privacy.secret must be set when privacy is enabled")),
+            _ => Ok(()),
+        }
+    }
+}
+
+// All in seconds. Every `interval` raw clicks older than `delay` are rolled
+// up into hourly and daily aggregates. Raw clicks are then purged once rolled
+// up and older than `raw_retention`, hourly aggregates once older than
+// `hourly_retention`, 0 keeps them forever. Daily aggregates are kept.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Rollups {
+    pub interval: u64,
+    pub delay: u64,
+    pub raw_retention: u64,
+    pub hourly_retention: u64,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     pub env: String,
@@ -17,6 +130,15 @@ pub struct Config {
     pub port: i32,
     pub auth_token: String,
     pub database: Database,
+    pub manager: Manager,
+    pub cache: Cache,
+    pub keys: Keys,
+    pub trash: Trash,
+    pub expiry: Expiry,
+    pub clicks: Clicks,
+    pub geoip: GeoIp,
+    pub privacy: Privacy,
+    pub rollups: Rollups,
 }
 
 impl Config {
@@ -31,12 +153,31 @@ impl Config {
         s.merge(File::with_name(&format!("config/{}", env)).required(false))
             .context(format!("Unable to load config/{}.json", env))?;
 
-        s.merge(Environment::new().separator("_".into()))?;
+        s.merge(Environment::new().separator("_"))?;
 
-        s.try_into().context("Unable to instantiate Config struct")
+        let config: Self = s.try_into().context("Unable to instantiate Config struct")?;
+        config.privacy.validate()?;
+        Ok(config)
     }
 }
 
 lazy_static! {
     pub static ref CONFIG: Config = Config::new().unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn privacy(enabled: bool, secret: Option<&str>) -> Privacy {
+        Privacy { enabled, secret: secret.map(String::from), retention: 0, purge_interval: 1 }
+    }
+
+    #[test]
+    fn privacy_mode_requires_a_secret() {
+        assert!(privacy(true, None).validate().is_err());
+        assert!(privacy(true, Some("")).validate().is_err());
+        assert!(privacy(true, Some("secret")).validate().is_ok());
+        assert!(privacy(false, None).validate().is_ok());
+    }
+}
@@ -0,0 +1,181 @@
+use crate::db::{Transaction, UrlMap, is_foreign_key_violation};
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum BatchOperation {
+    Create {
+        #[serde(default)]
+        domain: String,
+        key: String,
+        url: String,
+        #[serde(default)]
+        expires_at: Option<DateTime<Utc>>,
+        #[serde(default)]
+        not_before: Option<DateTime<Utc>>,
+        #[serde(default)]
+        max_clicks: Option<i64>,
+        #[serde(default)]
+        prefix: bool,
+    },
+    Update {
+        #[serde(default)]
+        domain: String,
+        key: String,
+        url: String,
+        #[serde(default)]
+        expires_at: Option<DateTime<Utc>>,
+        #[serde(default)]
+        not_before: Option<DateTime<Utc>>,
+        #[serde(default)]
+        max_clicks: Option<i64>,
+        #[serde(default)]
+        prefix: bool,
+    },
+    Delete {
+        #[serde(default)]
+        domain: String,
+        key: String,
+    },
+}
+
+impl BatchOperation {
+    fn target(&self) -> (&str, &str) {
+        match self {
+            Self::Create { domain, key, .. } | Self::Update { domain, key, .. } | Self::Delete { domain, key } => (domain, key),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchRequest {
+    pub operations: Vec<BatchOperation>,
+    // Commit the operations that succeeded instead of rolling everything
+    // back on the first failure.
+    #[serde(default)]
+    pub partial: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BatchStatus {
+    Ok,
+    Failed,
+    Skipped,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchResult {
+    pub domain: String,
+    pub key: String,
+    pub status: BatchStatus,
+    pub url_map: Option<UrlMap>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchReport {
+    pub committed: bool,
+    pub results: Vec<BatchResult>,
+}
+
+impl BatchReport {
+    // Domains and keys of the url maps changed once the batch is committed.
+    pub fn changed_keys(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.results
+            .iter()
+            .filter(|result| result.status == BatchStatus::Ok)
+            .map(|result| (result.domain.as_str(), result.key.as_str()))
+    }
+}
+
+async fn apply(tx: &mut dyn Transaction, tenant: &str, operation: &BatchOperation, actor: Option<&str>) -> Result<UrlMap, String> {
+    let result = match operation {
+        BatchOperation::Create { domain, key, url, expires_at, not_before, max_clicks, prefix } => {
+            let url_map = UrlMap {
+                expires_at: *expires_at,
+                not_before: *not_before,
+                max_clicks: *max_clicks,
+                prefix: *prefix,
+                domain: domain.clone(),
+                tenant: tenant.into(),
+                ..UrlMap::new(key.clone(), url.clone())
+            };
+            url_map.validate()?;
+            tx.create_url_map(&url_map, actor).await
+        }
+        BatchOperation::Update { domain, key, url, expires_at, not_before, max_clicks, prefix } => {
+            let url_map = UrlMap {
+                expires_at: *expires_at,
+                not_before: *not_before,
+                max_clicks: *max_clicks,
+                prefix: *prefix,
+                domain: domain.clone(),
+                tenant: tenant.into(),
+                ..UrlMap::new(key.clone(), url.clone())
+            };
+            url_map.validate()?;
+            tx.update_url_map(&url_map, actor).await
+        }
+        BatchOperation::Delete { domain, key } => tx.delete_url_map(tenant, domain, key, actor).await,
+    };
+    // Creating only finds no row when the domain belongs to another tenant.
+    let creating = matches!(operation, BatchOperation::Create { .. });
+    result.map_err(|e| match e {
+        sqlx::Error::RowNotFound if creating => "domain does not exist".into(),
+        sqlx::Error::RowNotFound => "key does not exist".into(),
+        e if is_foreign_key_violation(&e) => "domain does not exist".into(),
+        e => e.to_string(),
+    })
+}
+
+// Applies the operations to the url maps of `tenant` in order within one
+// transaction. Unless the request is `partial`, the first failure rolls back
+// everything and the remaining operations are skipped. In partial mode every operation runs inside a
+// savepoint so a failure only undoes that operation.
+pub async fn apply_batch(
+    mut tx: Box<dyn Transaction>,
+    tenant: &str,
+    request: BatchRequest,
+    actor: Option<&str>,
+) -> Result<BatchReport, sqlx::Error> {
+    let mut results = Vec::with_capacity(request.operations.len());
+    let mut failed = false;
+
+    for operation in &request.operations {
+        let (domain, key) = operation.target();
+        let (domain, key) = (domain.to_string(), key.to_string());
+        if failed {
+            results.push(BatchResult { domain, key, status: BatchStatus::Skipped, url_map: None, error: None });
+            continue;
+        }
+        if request.partial {
+            tx.savepoint().await?;
+        }
+        let result = match apply(&mut *tx, tenant, operation, actor).await {
+            Ok(url_map) => {
+                if request.partial {
+                    tx.release_savepoint().await?;
+                }
+                BatchResult { domain, key, status: BatchStatus::Ok, url_map: Some(url_map), error: None }
+            }
+            Err(e) => {
+                if request.partial {
+                    tx.rollback_to_savepoint().await?;
+                } else {
+                    failed = true;
+                }
+                BatchResult { domain, key, status: BatchStatus::Failed, url_map: None, error: Some(e) }
+            }
+        };
+        results.push(result);
+    }
+
+    if failed {
+        tx.rollback().await?;
+    } else {
+        tx.commit().await?;
+    }
+    Ok(BatchReport { committed: !failed, results })
+}
@@ -0,0 +1,137 @@
+use crate::{config, db::UrlMap};
+use lru::LruCache;
+use serde::{Serialize, Deserialize};
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub capacity: usize,
+}
+
+#[derive(Debug)]
+struct Entry {
+    url_map: UrlMap,
+    expires_at: Instant,
+}
+
+// Names of the registered domains, which decide the namespace a request's host
+// resolves to.
+#[derive(Debug)]
+struct Domains {
+    names: Arc<HashSet<String>>,
+    expires_at: Instant,
+}
+
+#[derive(Debug)]
+struct Inner {
+    entries: Mutex<LruCache<(String, String), Entry>>,
+    domains: Mutex<Option<Domains>>,
+    ttl: Duration,
+    generation: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+// Read-through cache for redirect lookups, keyed by domain and key. Writes go
+// through the `Manager`, which invalidates the affected key once the storage
+// call succeeds and clears everything when domains change.
+#[derive(Debug, Clone)]
+pub struct Cache {
+    inner: Arc<Inner>,
+}
+
+impl Cache {
+    pub fn new(config: &config::Cache) -> Self {
+        let inner = Inner {
+            entries: Mutex::new(LruCache::new(config.capacity)),
+            domains: Mutex::new(None),
+            ttl: Duration::from_secs(config.ttl),
+            generation: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        };
+        Self { inner: Arc::new(inner) }
+    }
+
+    pub fn get(&self, domain: &str, key: &str) -> Option<UrlMap> {
+        let key = (domain.to_string(), key.to_string());
+        let mut entries = self.inner.entries.lock().unwrap();
+        let url_map = match entries.get(&key) {
+            Some(entry) if entry.expires_at > Instant::now() => Some(entry.url_map.clone()),
+            Some(_) => {
+                entries.pop(&key);
+                None
+            }
+            None => None,
+        };
+        let counter = match url_map {
+            Some(_) => &self.inner.hits,
+            None => &self.inner.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        url_map
+    }
+
+    // Snapshot to pass to `insert`, taken before querying the database so
+    // that a lookup racing with a write cannot cache the stale row.
+    pub fn generation(&self) -> u64 {
+        self.inner.generation.load(Ordering::SeqCst)
+    }
+
+    pub fn insert(&self, generation: u64, url_map: UrlMap) {
+        let mut entries = self.inner.entries.lock().unwrap();
+        if generation != self.generation() {
+            return;
+        }
+        let expires_at = Instant::now() + self.inner.ttl;
+        entries.put((url_map.domain.clone(), url_map.key.clone()), Entry { url_map, expires_at });
+    }
+
+    pub fn domains(&self) -> Option<Arc<HashSet<String>>> {
+        match &*self.inner.domains.lock().unwrap() {
+            Some(domains) if domains.expires_at > Instant::now() => Some(domains.names.clone()),
+            _ => None,
+        }
+    }
+
+    // Kept like any other entry, so a disabled cache looks domains up on
+    // every redirect too.
+    pub fn insert_domains(&self, generation: u64, names: HashSet<String>) {
+        let entries = self.inner.entries.lock().unwrap();
+        if generation != self.generation() || entries.cap() == 0 {
+            return;
+        }
+        let expires_at = Instant::now() + self.inner.ttl;
+        *self.inner.domains.lock().unwrap() = Some(Domains { names: Arc::new(names), expires_at });
+    }
+
+    pub fn invalidate(&self, domain: &str, key: &str) {
+        let mut entries = self.inner.entries.lock().unwrap();
+        self.inner.generation.fetch_add(1, Ordering::SeqCst);
+        entries.pop(&(domain.to_string(), key.to_string()));
+    }
+
+    pub fn clear(&self) {
+        let mut entries = self.inner.entries.lock().unwrap();
+        self.inner.generation.fetch_add(1, Ordering::SeqCst);
+        entries.clear();
+        *self.inner.domains.lock().unwrap() = None;
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let entries = self.inner.entries.lock().unwrap();
+        CacheStats {
+            hits: self.inner.hits.load(Ordering::Relaxed),
+            misses: self.inner.misses.load(Ordering::Relaxed),
+            entries: entries.len(),
+            capacity: entries.cap(),
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use crate::config;
+use chrono::{DateTime, NaiveDate, Utc};
+use hmac::{Hmac, Mac, NewMac};
+use rand::{Rng, distributions::Alphanumeric};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use sqlx::FromRow;
+use std::{net::IpAddr, sync::{Arc, Mutex}};
+use tokio::sync::mpsc::{self, Receiver, Sender, error::TrySendError};
+
+// One redirect, as recorded in the clicks table.
+#[derive(Debug, FromRow, Clone, Serialize, Deserialize)]
+pub struct Click {
+    pub tenant: String,
+    pub domain: String,
+    pub key: String,
+    pub clicked_at: DateTime<Utc>,
+    pub referrer: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub utm_source: Option<String>,
+    // ISO 3166 codes, filled in by the writer when a GeoIP database is
+    // configured.
+    pub country: Option<String>,
+    pub region: Option<String>,
+    pub is_bot: bool,
+    // Salted hash of the ip and user agent identifying the visitor for the
+    // day, only used to count unique visitors and never stored.
+    #[sqlx(default)]
+    #[serde(skip)]
+    pub visitor: Option<u64>,
+}
+
+// Salt for visitor hashes, replaced at midnight UTC. It is the HMAC of the
+// day keyed with the configured secret, so every replica and restart salts a
+// day alike, while hashes from different days cannot be linked without it.
+#[derive(Debug)]
+struct Salt {
+    day: NaiveDate,
+    bytes: [u8; 32],
+}
+
+impl Salt {
+    fn new(secret: &str, day: NaiveDate) -> Self {
+        let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("HMAC accepts keys of any length");
+        mac.update(day.to_string().as_bytes());
+        Self { day, bytes: mac.finalize().into_bytes().into() }
+    }
+}
+
+// Hands clicks over to the `Manager`, which buffers them and writes them in
+// batches. Recording never waits: when the buffer is full the click is
+// dropped rather than slowing down the redirect.
+#[derive(Debug, Clone)]
+pub struct ClickRecorder {
+    sender: Sender<Click>,
+    anonymize: bool,
+    secret: Arc<str>,
+    salt: Arc<Mutex<Salt>>,
+}
+
+impl ClickRecorder {
+    pub fn new(config: &config::Clicks, privacy: &config::Privacy) -> (Self, Receiver<Click>) {
+        let (sender, receiver) = mpsc::channel(config.buffer.max(1));
+        // Without a secret, which privacy mode requires, each process salts
+        // with one of its own and only counts its own visitors alike.
+        let secret: Arc<str> = match privacy.secret.as_deref() {
+            Some(secret) if !secret.is_empty() => Arc::from(secret),
+            _ => rand::thread_rng().sample_iter(&Alphanumeric).take(32).map(char::from).collect::<String>().into(),
+        };
+        let salt = Arc::new(Mutex::new(Salt::new(&secret, Utc::now().date_naive())));
+        (Self { sender, anonymize: privacy.enabled, secret, salt }, receiver)
+    }
+
+    // Identifies the visitor and, in privacy mode, truncates the ip before
+    // the click leaves the redirect.
+    pub fn record(&self, mut click: Click) {
+        click.visitor = Some(self.visitor(&click));
+        if self.anonymize {
+            click.ip = click.ip.as_deref().and_then(anonymize_ip);
+        }
+        match self.sender.try_send(click) {
+            Ok(()) => {}
+            Err(TrySendError::Full(click)) => {
+                tracing::warn!("Click buffer is full, dropping click on {}", click.key);
+            }
+            Err(TrySendError::Closed(click)) => {
+                tracing::error!("Click writer has stopped, dropping click on {}", click.key);
+            }
+        }
+    }
+
+    fn visitor(&self, click: &Click) -> u64 {
+        let day = click.clicked_at.date_naive();
+        let mut salt = self.salt.lock().unwrap();
+        if salt.day != day {
+            *salt = Salt::new(&self.secret, day);
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(salt.bytes);
+        hasher.update(click.ip.as_deref().unwrap_or(""));
+        hasher.update([0]);
+        hasher.update(click.user_agent.as_deref().unwrap_or(""));
+        let hash = hasher.finalize();
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&hash[..8]);
+        u64::from_be_bytes(bytes)
+    }
+}
+
+// Keeps the first 3 bytes of IPv4 and the first 6 bytes of IPv6 addresses,
+// enough to locate the network but not the host.
+fn anonymize_ip(ip: &str) -> Option<String> {
+    let ip = match ip.parse::<IpAddr>().ok()? {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::from([a, b, c, 0])
+        }
+        IpAddr::V6(ip) => {
+            let [a, b, c, ..] = ip.segments();
+            IpAddr::from([a, b, c, 0, 0, 0, 0, 0])
+        }
+    };
+    Some(ip.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn recorder(secret: &str) -> ClickRecorder {
+        let clicks = config::Clicks { buffer: 1, batch_size: 1, flush_interval: 1 };
+        let privacy = config::Privacy { enabled: false, secret: Some(secret.into()), retention: 0, purge_interval: 1 };
+        ClickRecorder::new(&clicks, &privacy).0
+    }
+
+    fn click(clicked_at: DateTime<Utc>) -> Click {
+        Click {
+            tenant: "default".into(),
+            domain: String::new(),
+            key: "gh".into(),
+            clicked_at,
+            referrer: None,
+            user_agent: Some("Mozilla/5.0".into()),
+            ip: Some("192.0.2.1".into()),
+            utm_source: None,
+            country: None,
+            region: None,
+            is_bot: false,
+            visitor: None,
+        }
+    }
+
+    #[test]
+    fn visitors_hash_alike_across_recorders_sharing_the_secret() {
+        let morning = click(Utc.with_ymd_and_hms(2021, 7, 19, 9, 0, 0).unwrap());
+        let evening = click(Utc.with_ymd_and_hms(2021, 7, 19, 21, 0, 0).unwrap());
+        assert_eq!(recorder("secret").visitor(&morning), recorder("secret").visitor(&evening));
+        assert_ne!(recorder("secret").visitor(&morning), recorder("other").visitor(&morning));
+    }
+
+    #[test]
+    fn visitors_hash_differently_every_day() {
+        let recorder = recorder("secret");
+        let today = click(Utc.with_ymd_and_hms(2021, 7, 19, 9, 0, 0).unwrap());
+        let tomorrow = click(Utc.with_ymd_and_hms(2021, 7, 20, 9, 0, 0).unwrap());
+        assert_ne!(recorder.visitor(&today), recorder.visitor(&tomorrow));
+    }
+
+    #[test]
+    fn anonymize_ip_keeps_the_network() {
+        assert_eq!(anonymize_ip("192.0.2.123").as_deref(), Some("192.0.2.0"));
+        assert_eq!(anonymize_ip("2001:db8:85a3:8d3:1319:8a2e:370:7348").as_deref(), Some("2001:db8:85a3::"));
+        assert_eq!(anonymize_ip("not an ip"), None);
+    }
+}
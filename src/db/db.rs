@@ -1,36 +1,294 @@
-use sqlx::{migrate, Pool, Postgres, postgres::PgPoolOptions, FromRow};
+use chrono::{DateTime, Utc};
+use sqlx::FromRow;
 use serde::{Serialize, Deserialize};
 use anyhow::Result;
-use crate::config::CONFIG;
+use std::sync::Arc;
+use crate::{config::{Backend, CONFIG}, db::{PostgresStorage, SqliteStorage, Storage}};
+
+// Keys cannot be the routes served next to redirects or next to keys in the
+// api, which would shadow them. Keys with a `/` cannot begin with those served
+// next to redirects either, nor end with what the api and admin routes put
+// after a key.
+const RESERVED_KEYS: &[&str] = &["admin", "api", "batch", "export", "import", "trash"];
+const RESERVED_FIRST_SEGMENTS: &[&str] = &["admin", "api", "trash"];
+const RESERVED_LAST_SEGMENTS: &[&str] = &["edit", "history", "restore", "revert", "stats", "version"];
+// Length of the key columns, which hold the whole path of hierarchical keys.
+const MAX_KEY_LENGTH: usize = 255;
 
 #[derive(Debug, FromRow, Clone, Serialize, Deserialize)]
 pub struct UrlMap {
     pub key: String,
     pub url: String,
+    // Set by storage on every write, the values given here are ignored.
+    #[serde(default = "Utc::now")]
+    pub created_at: DateTime<Utc>,
+    #[serde(default = "Utc::now")]
+    pub updated_at: DateTime<Utc>,
+    #[serde(default)]
+    pub created_by: Option<String>,
+    #[serde(default)]
+    pub updated_by: Option<String>,
+    // Only set on url maps in the trash.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_by: Option<String>,
+    // The url map only redirects from `not_before` until `expires_at`.
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub not_before: Option<DateTime<Utc>>,
+    // Number of redirects allowed before the link is used up, counted in
+    // `used_clicks` which is maintained by storage.
+    #[serde(default)]
+    pub max_clicks: Option<i64>,
+    #[serde(default)]
+    pub used_clicks: i64,
+    // Keys are only unique within a domain, the default domain is the empty
+    // string.
+    #[serde(default)]
+    pub domain: String,
+    // Owner of the url map, always taken from the credentials of the request
+    // and never from its body.
+    #[serde(skip)]
+    pub tenant: String,
+    // Also redirects every path below the key, see `UrlMap::target`.
+    #[serde(default)]
+    pub prefix: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NewUrlMap {
+    #[serde(skip)]
+    pub tenant: String,
+    #[serde(default)]
+    pub domain: String,
+    pub key: Option<String>,
+    pub url: String,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub not_before: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub max_clicks: Option<i64>,
+    #[serde(default)]
+    pub prefix: bool,
+}
+
+impl NewUrlMap {
+    pub fn to_url_map(&self, key: String) -> UrlMap {
+        UrlMap {
+            expires_at: self.expires_at,
+            not_before: self.not_before,
+            max_clicks: self.max_clicks,
+            domain: self.domain.clone(),
+            tenant: self.tenant.clone(),
+            prefix: self.prefix,
+            ..UrlMap::new(key, self.url.clone())
+        }
+    }
+
+    // Generated keys are valid by construction, a placeholder stands in for
+    // a missing key.
+    pub fn validate(&self) -> Result<(), String> {
+        let key = self.key.clone().unwrap_or_else(|| "generated".into());
+        self.to_url_map(key).validate()
+    }
 }
 
 impl UrlMap {
     pub fn new(key: String, url: String) -> Self {
-        Self { key, url }
+        let now = Utc::now();
+        Self {
+            key,
+            url,
+            created_at: now,
+            updated_at: now,
+            created_by: None,
+            updated_by: None,
+            deleted_at: None,
+            deleted_by: None,
+            expires_at: None,
+            not_before: None,
+            max_clicks: None,
+            used_clicks: 0,
+            domain: String::new(),
+            tenant: String::new(),
+            prefix: false,
+        }
+    }
+
+    // Keys that may redirect a request for `path`, longest first: the whole
+    // path, then the path cut at each of its slashes.
+    pub fn candidate_keys(path: &str) -> Vec<String> {
+        std::iter::once(path)
+            .chain(path.rmatch_indices('/').map(|(i, _)| &path[..i]))
+            .filter(|key| !key.is_empty())
+            .map(String::from)
+            .collect()
+    }
+
+    // Where a request for `path` is sent. Prefix url maps append the rest of
+    // the path below their key to the url, ahead of its query and fragment.
+    pub fn target(&self, path: &str) -> String {
+        let rest = match path.strip_prefix(self.key.as_str()) {
+            Some(rest) if self.prefix && rest.starts_with('/') => rest,
+            _ => return self.url.clone(),
+        };
+        let (base, suffix) = self.url.split_at(self.url.find(['?', '#']).unwrap_or(self.url.len()));
+        let mut target = base.trim_end_matches('/').to_string();
+        // The path arrives decoded, anything that is not allowed in a path
+        // is encoded again.
+        for byte in rest.bytes() {
+            if byte.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@/".contains(&byte) {
+                target.push(byte as char);
+            } else {
+                target.push_str(&format!("%{:02X}", byte));
+            }
+        }
+        target.push_str(suffix);
+        target
+    }
+
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    pub fn is_pending(&self, now: DateTime<Utc>) -> bool {
+        self.not_before.is_some_and(|not_before| now < not_before)
+    }
+
+    pub fn is_used_up(&self) -> bool {
+        self.max_clicks.is_some_and(|max_clicks| self.used_clicks >= max_clicks)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.key.is_empty() {
+            return Err("key must not be empty".into());
+        }
+        if self.key.chars().count() > MAX_KEY_LENGTH {
+            return Err(format!("key must be at most {} characters", MAX_KEY_LENGTH));
+        }
+        if RESERVED_KEYS.contains(&self.key.as_str()) {
+            return Err(format!("key must not be '{}'", self.key));
+        }
+        if self.key.contains('/') {
+            let segments = self.key.split('/').collect::<Vec<_>>();
+            if segments.iter().any(|segment| segment.is_empty()) {
+                return Err("key must not start or end with '/' or contain '//'".into());
+            }
+            if RESERVED_FIRST_SEGMENTS.contains(&segments[0]) {
+                return Err(format!("key must not start with '{}/'", segments[0]));
+            }
+            let last = segments[segments.len() - 1];
+            if RESERVED_LAST_SEGMENTS.contains(&last) {
+                return Err(format!("key must not end with '/{}'", last));
+            }
+        }
+        if self.url.is_empty() {
+            return Err("url must not be empty".into());
+        }
+        if self.max_clicks.is_some_and(|max_clicks| max_clicks < 1) {
+            return Err("max_clicks must be at least 1".into());
+        }
+        if let (Some(not_before), Some(expires_at)) = (self.not_before, self.expires_at) {
+            if expires_at <= not_before {
+                return Err("expires_at must be after not_before".into());
+            }
+        }
+        Ok(())
     }
 }
 
 pub struct DB {
-    pub pool: Pool<Postgres>,
+    pub storage: Arc<dyn Storage>,
+    // Size of the storage's connection pool, which the `Manager` sizes its
+    // concurrency to.
+    pub max_connections: u32,
 }
 
 impl DB {
     pub async fn new() -> Result<Self> {
-        let pool = PgPoolOptions::new()
-            .max_connections(CONFIG.database.max_connections)
-            .connect(&CONFIG.database.url)
-            .await?;
+        let (storage, max_connections): (Arc<dyn Storage>, _) = match CONFIG.database.backend {
+            Backend::Postgres => (Arc::new(PostgresStorage::new(&CONFIG.database).await?), CONFIG.database.max_connections),
+            Backend::Sqlite => (Arc::new(SqliteStorage::new(&CONFIG.database).await?), CONFIG.database.max_connections),
+            Backend::Memory => return Self::in_memory().await,
+        };
+        Ok(Self { storage, max_connections })
+    }
+
+    pub async fn in_memory() -> Result<Self> {
+        let storage = Arc::new(SqliteStorage::in_memory().await?);
+        Ok(Self { storage, max_connections: SqliteStorage::IN_MEMORY_CONNECTIONS })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate(key: &str) -> Result<(), String> {
+        UrlMap::new(key.into(), "https://example.com".into()).validate()
+    }
+
+    #[test]
+    fn validate_rejects_reserved_keys() {
+        for key in ["admin", "api", "batch", "export", "import", "trash"] {
+            assert!(validate(key).is_err(), "{}", key);
+        }
+        assert!(validate("admin/gh").is_err());
+        assert!(validate("trash/gh").is_err());
+        assert!(validate("docs/edit").is_err());
+        assert!(validate("docs/stats").is_err());
+    }
+
+    #[test]
+    fn validate_accepts_keys_around_reserved_ones() {
+        for key in ["gh", "exports", "docs/export", "export/csv", "batch/2021", "docs/api"] {
+            assert_eq!(validate(key), Ok(()), "{}", key);
+        }
+    }
+
+    #[test]
+    fn candidate_keys_are_cut_at_each_slash_longest_first() {
+        assert_eq!(UrlMap::candidate_keys("docs/api/v1"), vec!["docs/api/v1", "docs/api", "docs"]);
+        assert_eq!(UrlMap::candidate_keys("gh"), vec!["gh"]);
+        assert_eq!(UrlMap::candidate_keys("docs/"), vec!["docs/", "docs"]);
+    }
+
+    fn prefix(key: &str, url: &str) -> UrlMap {
+        UrlMap { prefix: true, ..UrlMap::new(key.into(), url.into()) }
+    }
 
-        let migrator = migrate!();
-        migrator
-            .run(&pool)
-            .await?;
+    #[test]
+    fn target_appends_the_rest_of_the_path_to_prefix_url_maps() {
+        let docs = prefix("docs", "https://example.com/manual/?lang=en#top");
+        assert_eq!(docs.target("docs"), "https://example.com/manual/?lang=en#top");
+        assert_eq!(docs.target("docs/install/linux"), "https://example.com/manual/install/linux?lang=en#top");
+        assert_eq!(docs.target("docs/a b/é"), "https://example.com/manual/a%20b/%C3%A9?lang=en#top");
+    }
+
+    #[test]
+    fn target_only_follows_whole_segments() {
+        let docs = prefix("docs", "https://example.com/manual");
+        assert_eq!(docs.target("docsearch"), "https://example.com/manual");
+        let exact = UrlMap::new("docs".into(), "https://example.com/manual".into());
+        assert_eq!(exact.target("docs/install"), "https://example.com/manual");
+    }
+
+    #[test]
+    fn validate_rejects_malformed_keys() {
+        for key in ["", "/docs", "docs/", "docs//api"] {
+            assert!(validate(key).is_err(), "{:?}", key);
+        }
+        assert!(validate(&"k".repeat(256)).is_err());
+    }
 
-        Ok(Self { pool })
+    #[test]
+    fn validate_accepts_long_hierarchical_keys() {
+        let key = vec!["segment"; 30].join("/");
+        assert_eq!(key.len(), 239);
+        assert_eq!(validate(&key), Ok(()));
+        assert_eq!(validate(&"k".repeat(255)), Ok(()));
     }
 }
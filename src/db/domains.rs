@@ -0,0 +1,54 @@
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+use sqlx::FromRow;
+
+// A host name with its own namespace of keys. Url maps of the default domain,
+// named by the empty string, answer on every host that is not registered.
+#[derive(Debug, FromRow, Clone, Serialize, Deserialize)]
+pub struct Domain {
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub created_by: Option<String>,
+    // Only this tenant can create url maps on the domain. The default domain
+    // has no tenant and is shared by all of them.
+    #[serde(skip)]
+    pub tenant: Option<String>,
+}
+
+impl Domain {
+    // Lower cases `name` and checks that it is a plain host name, without a
+    // scheme, port or path.
+    pub fn normalize(name: &str) -> Result<String, String> {
+        let name = name.trim().trim_end_matches('.').to_lowercase();
+        if name.is_empty() {
+            return Err("domain must not be empty".into());
+        }
+        if name.len() > 253 {
+            return Err("domain must be at most 253 characters".into());
+        }
+        let valid_label = |label: &str| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        };
+        if !name.split('.').all(valid_label) {
+            return Err("domain must be a host name such as go.example.com".into());
+        }
+        Ok(name)
+    }
+
+    // Host a request was made to, lower cased and without its port.
+    pub fn host(authority: &str) -> String {
+        let host = match authority.rsplit_once(':') {
+            Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+            _ => authority,
+        };
+        host.trim_end_matches('.').to_lowercase()
+    }
+
+    pub fn is_usable_by(&self, tenant: &str) -> bool {
+        self.tenant.as_deref().is_none_or(|owner| owner == tenant)
+    }
+}
@@ -0,0 +1,348 @@
+use crate::db::{ClickSource, UrlMap};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use parquet::{
+    basic::Compression,
+    data_type::{BoolType, ByteArray, ByteArrayType, Int64Type},
+    file::{properties::WriterProperties, writer::{SerializedFileWriter, SerializedRowGroupWriter}},
+    schema::parser::parse_message_type,
+};
+use serde::{Serialize, Deserialize};
+use sqlx::FromRow;
+use std::sync::Arc;
+use tokio::sync::mpsc::Receiver;
+
+// Rows buffered into each Parquet row group, and so between two chunks of a
+// Parquet export.
+const ROW_GROUP_SIZE: usize = 10000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Parquet,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Csv => "text/csv",
+            Self::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Parquet => "parquet",
+        }
+    }
+}
+
+// Clicks to export, every key and all time unless narrowed down. Url maps
+// have no tags to filter on, and unknown options such as `tag` are rejected
+// rather than ignored, which would export everything.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClickExport {
+    // `raw` exports the clicks themselves, `hourly` and `daily` their
+    // rollups.
+    pub granularity: ClickSource,
+    pub key: Option<String>,
+    // Only the clicks on this domain's url maps, `key` included. The default
+    // domain is the empty string.
+    pub domain: Option<String>,
+    // Inclusive.
+    pub from: Option<DateTime<Utc>>,
+    // Exclusive.
+    pub to: Option<DateTime<Utc>>,
+    pub format: ExportFormat,
+}
+
+impl ClickExport {
+    pub fn parse(query: Option<&str>) -> Result<Self> {
+        let export: Self = serde_urlencoded::from_str(query.unwrap_or(""))?;
+        if let (Some(from), Some(to)) = (export.from, export.to) {
+            if from >= to {
+                return Err(anyhow!("from must be before to"));
+            }
+        }
+        Ok(export)
+    }
+
+    // Name of the exported file, without its extension.
+    pub fn name(&self) -> &'static str {
+        self.granularity.table()
+    }
+}
+
+// One raw click. The ip is never exported.
+#[derive(Debug, FromRow, Clone, Serialize, Deserialize)]
+pub struct ExportedClick {
+    pub key: String,
+    pub clicked_at: DateTime<Utc>,
+    pub referrer: Option<String>,
+    pub user_agent: Option<String>,
+    pub utm_source: Option<String>,
+    pub country: Option<String>,
+    pub region: Option<String>,
+    pub is_bot: bool,
+    pub domain: String,
+}
+
+// Clicks on `key` in the hour or day starting at `bucket` with `value` as
+// their `field`, or all of them when `field` is `total`.
+#[derive(Debug, FromRow, Clone, Serialize, Deserialize)]
+pub struct ExportedRollup {
+    pub key: String,
+    pub bucket: DateTime<Utc>,
+    pub field: String,
+    pub value: Option<String>,
+    pub is_bot: bool,
+    pub clicks: i64,
+    pub domain: String,
+}
+
+// One url map. Exported CSV files are read back by the import, so the
+// columns are named after the fields of `UrlMap`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportedUrlMap {
+    pub key: String,
+    pub url: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub created_by: Option<String>,
+    pub updated_by: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub not_before: Option<DateTime<Utc>>,
+    pub max_clicks: Option<i64>,
+    pub used_clicks: i64,
+    pub domain: String,
+    pub prefix: bool,
+}
+
+impl ExportedUrlMap {
+    pub const HEADERS: &'static [&'static str] = &[
+        "key", "url", "created_at", "updated_at", "created_by", "updated_by",
+        "expires_at", "not_before", "max_clicks", "used_clicks", "domain", "prefix",
+    ];
+}
+
+impl From<UrlMap> for ExportedUrlMap {
+    fn from(url_map: UrlMap) -> Self {
+        Self {
+            key: url_map.key,
+            url: url_map.url,
+            created_at: url_map.created_at,
+            updated_at: url_map.updated_at,
+            created_by: url_map.created_by,
+            updated_by: url_map.updated_by,
+            expires_at: url_map.expires_at,
+            not_before: url_map.not_before,
+            max_clicks: url_map.max_clicks,
+            used_clicks: url_map.used_clicks,
+            domain: url_map.domain,
+            prefix: url_map.prefix,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ExportRows {
+    Clicks(Receiver<Result<ExportedClick, sqlx::Error>>),
+    Rollups(Receiver<Result<ExportedRollup, sqlx::Error>>),
+}
+
+// Rows that can be exported, the CSV headers and Parquet schema are part of
+// the documented export format and must only ever be extended.
+pub trait ExportRecord: Serialize + Send + Sized + 'static {
+    const HEADERS: &'static [&'static str];
+    const SCHEMA: &'static str;
+
+    fn write_columns(rows: &[Self], group: &mut SerializedRowGroupWriter<'_, Vec<u8>>) -> parquet::errors::Result<()>;
+}
+
+impl ExportRecord for ExportedClick {
+    const HEADERS: &'static [&'static str] = &["key", "clicked_at", "referrer", "user_agent", "utm_source", "country", "region", "is_bot", "domain"];
+    const SCHEMA: &'static str = "
+        message click {
+            required binary key (STRING);
+            required int64 clicked_at (TIMESTAMP(MICROS, true));
+            optional binary referrer (STRING);
+            optional binary user_agent (STRING);
+            optional binary utm_source (STRING);
+            optional binary country (STRING);
+            optional binary region (STRING);
+            required boolean is_bot;
+            required binary domain (STRING);
+        }
+    ";
+
+    fn write_columns(rows: &[Self], group: &mut SerializedRowGroupWriter<'_, Vec<u8>>) -> parquet::errors::Result<()> {
+        write_strings(group, rows.iter().map(|row| Some(row.key.as_str())), false)?;
+        write_timestamps(group, rows.iter().map(|row| row.clicked_at))?;
+        write_strings(group, rows.iter().map(|row| row.referrer.as_deref()), true)?;
+        write_strings(group, rows.iter().map(|row| row.user_agent.as_deref()), true)?;
+        write_strings(group, rows.iter().map(|row| row.utm_source.as_deref()), true)?;
+        write_strings(group, rows.iter().map(|row| row.country.as_deref()), true)?;
+        write_strings(group, rows.iter().map(|row| row.region.as_deref()), true)?;
+        write_bools(group, rows.iter().map(|row| row.is_bot))?;
+        write_strings(group, rows.iter().map(|row| Some(row.domain.as_str())), false)
+    }
+}
+
+impl ExportRecord for ExportedRollup {
+    const HEADERS: &'static [&'static str] = &["key", "bucket", "field", "value", "is_bot", "clicks", "domain"];
+    const SCHEMA: &'static str = "
+        message click_rollup {
+            required binary key (STRING);
+            required int64 bucket (TIMESTAMP(MICROS, true));
+            required binary field (STRING);
+            optional binary value (STRING);
+            required boolean is_bot;
+            required int64 clicks;
+            required binary domain (STRING);
+        }
+    ";
+
+    fn write_columns(rows: &[Self], group: &mut SerializedRowGroupWriter<'_, Vec<u8>>) -> parquet::errors::Result<()> {
+        write_strings(group, rows.iter().map(|row| Some(row.key.as_str())), false)?;
+        write_timestamps(group, rows.iter().map(|row| row.bucket))?;
+        write_strings(group, rows.iter().map(|row| Some(row.field.as_str())), false)?;
+        write_strings(group, rows.iter().map(|row| row.value.as_deref()), true)?;
+        write_bools(group, rows.iter().map(|row| row.is_bot))?;
+        write_int64s(group, rows.iter().map(|row| row.clicks))?;
+        write_strings(group, rows.iter().map(|row| Some(row.domain.as_str())), false)
+    }
+}
+
+// Encodes rows one at a time, handing back the bytes ready to be sent so
+// exports can be streamed. CSV rows come out as they are written, Parquet ones
+// a row group at a time.
+pub enum ExportWriter<R> {
+    // Bytes not handed back yet, only ever the headers.
+    Csv(Vec<u8>),
+    Parquet { writer: Box<SerializedFileWriter<Vec<u8>>>, rows: Vec<R> },
+}
+
+impl<R: ExportRecord> ExportWriter<R> {
+    pub fn new(format: ExportFormat) -> Result<Self> {
+        match format {
+            ExportFormat::Csv => {
+                // Headers are written up front so that empty exports still
+                // have them.
+                let mut writer = csv::Writer::from_writer(vec![]);
+                writer.write_record(R::HEADERS)?;
+                Ok(Self::Csv(writer.into_inner().map_err(|e| anyhow!("{}", e))?))
+            }
+            ExportFormat::Parquet => {
+                let schema = Arc::new(parse_message_type(R::SCHEMA)?);
+                let properties = WriterProperties::builder()
+                    .set_compression(Compression::SNAPPY)
+                    .build();
+                let writer = SerializedFileWriter::new(vec![], schema, Arc::new(properties))?;
+                Ok(Self::Parquet { writer: Box::new(writer), rows: Vec::with_capacity(ROW_GROUP_SIZE) })
+            }
+        }
+    }
+
+    pub fn write(&mut self, row: R) -> Result<Vec<u8>> {
+        match self {
+            Self::Csv(pending) => {
+                let mut writer = csv::WriterBuilder::new()
+                    .has_headers(false)
+                    .from_writer(std::mem::take(pending));
+                writer.serialize(row)?;
+                Ok(writer.into_inner().map_err(|e| anyhow!("{}", e))?)
+            }
+            Self::Parquet { writer, rows } => {
+                rows.push(row);
+                if rows.len() < ROW_GROUP_SIZE {
+                    return Ok(vec![]);
+                }
+                write_row_group(writer, rows)?;
+                Ok(std::mem::take(writer.inner_mut()))
+            }
+        }
+    }
+
+    // Returns the remaining bytes, including the Parquet footer.
+    pub fn finish(self) -> Result<Vec<u8>> {
+        match self {
+            Self::Csv(pending) => Ok(pending),
+            Self::Parquet { mut writer, mut rows } => {
+                if !rows.is_empty() {
+                    write_row_group(&mut writer, &mut rows)?;
+                }
+                Ok(writer.into_inner()?)
+            }
+        }
+    }
+}
+
+fn write_row_group<R: ExportRecord>(writer: &mut SerializedFileWriter<Vec<u8>>, rows: &mut Vec<R>) -> parquet::errors::Result<()> {
+    let mut group = writer.next_row_group()?;
+    R::write_columns(rows, &mut group)?;
+    group.close()?;
+    rows.clear();
+    Ok(())
+}
+
+// Optional columns are written with a definition level per row, 0 for nulls
+// which are left out of the values.
+fn write_strings<'a, I>(group: &mut SerializedRowGroupWriter<'_, Vec<u8>>, values: I, optional: bool) -> parquet::errors::Result<()>
+where
+    I: Iterator<Item = Option<&'a str>>,
+{
+    let values = values.collect::<Vec<_>>();
+    let levels = optional.then(|| values.iter().map(|value| i16::from(value.is_some())).collect::<Vec<_>>());
+    let values = values.into_iter().flatten().map(ByteArray::from).collect::<Vec<_>>();
+    let mut column = group.next_column()?.unwrap();
+    column.typed::<ByteArrayType>().write_batch(&values, levels.as_deref(), None)?;
+    column.close()
+}
+
+fn write_timestamps<I>(group: &mut SerializedRowGroupWriter<'_, Vec<u8>>, values: I) -> parquet::errors::Result<()>
+where
+    I: Iterator<Item = DateTime<Utc>>,
+{
+    write_int64s(group, values.map(|value| value.timestamp_micros()))
+}
+
+fn write_int64s<I>(group: &mut SerializedRowGroupWriter<'_, Vec<u8>>, values: I) -> parquet::errors::Result<()>
+where
+    I: Iterator<Item = i64>,
+{
+    let mut column = group.next_column()?.unwrap();
+    column.typed::<Int64Type>().write_batch(&values.collect::<Vec<_>>(), None, None)?;
+    column.close()
+}
+
+fn write_bools<I>(group: &mut SerializedRowGroupWriter<'_, Vec<u8>>, values: I) -> parquet::errors::Result<()>
+where
+    I: Iterator<Item = bool>,
+{
+    let mut column = group.next_column()?.unwrap();
+    column.typed::<BoolType>().write_batch(&values.collect::<Vec<_>>(), None, None)?;
+    column.close()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn url_map_headers_match_the_serialized_fields() {
+        let mut writer = csv::Writer::from_writer(vec![]);
+        writer.serialize(ExportedUrlMap::from(UrlMap::new("gh".into(), "https://github.com".into()))).unwrap();
+        let csv = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert_eq!(csv.lines().next().unwrap(), ExportedUrlMap::HEADERS.join(","));
+    }
+
+    #[test]
+    fn unknown_click_export_options_are_rejected() {
+        assert!(ClickExport::parse(Some("key=gh&format=parquet")).is_ok());
+        assert!(ClickExport::parse(Some("tag=summit")).is_err());
+    }
+}
@@ -0,0 +1,42 @@
+use crate::{config, db::Click};
+use maxminddb::{Reader, geoip2};
+use std::net::IpAddr;
+
+// Locates clicks offline against a local MaxMind database. Country databases
+// only give the country, city databases the region as well.
+pub struct GeoIp {
+    reader: Reader<Vec<u8>>,
+}
+
+impl GeoIp {
+    // `None` when no database is configured or it cannot be read, clicks are
+    // then recorded without a location.
+    pub fn new(config: &config::GeoIp) -> Option<Self> {
+        let path = config.database.as_deref()?;
+        match Reader::open_readfile(path) {
+            Ok(reader) => Some(Self { reader }),
+            Err(e) => {
+                tracing::error!("Failed to open GeoIP database {}, error: {}", path, e);
+                None
+            }
+        }
+    }
+
+    // Addresses missing from the database, such as private ones, are left
+    // without a location.
+    pub fn locate(&self, click: &mut Click) {
+        let ip = match click.ip.as_deref().and_then(|ip| ip.parse::<IpAddr>().ok()) {
+            Some(ip) => ip,
+            None => return,
+        };
+        if let Ok(city) = self.reader.lookup::<geoip2::City>(ip) {
+            click.country = city.country
+                .and_then(|country| country.iso_code)
+                .map(String::from);
+            click.region = city.subdivisions
+                .and_then(|subdivisions| subdivisions.into_iter().next())
+                .and_then(|subdivision| subdivision.iso_code)
+                .map(String::from);
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use crate::db::{Transaction, UrlMap};
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+use sqlx::FromRow;
+
+// One entry of the append-only history kept for every write. `action` is one
+// of `create`, `update`, `delete` (to the trash), `restore` (from the trash),
+// `purge` (out of the trash, by hand or once expired there) or `archive` (an
+// expired url map moved to the trash), and the id doubles as the version
+// number of the url map after the change.
+#[derive(Debug, FromRow, Clone, Serialize, Deserialize)]
+pub struct UrlMapChange {
+    pub id: i64,
+    pub domain: String,
+    pub key: String,
+    pub action: String,
+    pub old_url: Option<String>,
+    pub new_url: Option<String>,
+    pub actor: Option<String>,
+    pub changed_at: DateTime<Utc>,
+}
+
+// Puts `key` of `domain` back into the state change `version` left it in,
+// which means deleting it again when that change was a delete. A url map in
+// the trash is restored first. The revert is itself recorded in the history
+// like any other write. Both the change and the url map, if it still exists,
+// must belong to `tenant`.
+pub async fn revert_url_map(
+    mut tx: Box<dyn Transaction>,
+    tenant: &str,
+    domain: &str,
+    key: &str,
+    version: i64,
+    actor: Option<&str>,
+) -> Result<UrlMap, sqlx::Error> {
+    let change = tx.get_url_map_change(tenant, domain, key, version).await?.ok_or(sqlx::Error::RowNotFound)?;
+    let mut current = tx.get_url_map(domain, key).await?;
+    if current.as_ref().is_some_and(|url_map| url_map.tenant != tenant) {
+        return Err(sqlx::Error::RowNotFound);
+    }
+    if let Some(url_map) = current.as_ref().filter(|url_map| url_map.deleted_at.is_some()) {
+        if change.new_url.is_none() {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        current = Some(tx.restore_url_map(tenant, &url_map.domain, &url_map.key, actor).await?);
+    }
+    let url_map = match (change.new_url, current) {
+        (Some(url), Some(current)) => tx.update_url_map(&UrlMap { url, ..current }, actor).await?,
+        (Some(url), None) => tx.create_url_map(&UrlMap { domain: domain.into(), tenant: tenant.into(), ..UrlMap::new(key.into(), url) }, actor).await?,
+        (None, Some(_)) => tx.delete_url_map(tenant, domain, key, actor).await?,
+        (None, None) => return Err(sqlx::Error::RowNotFound),
+    };
+    tx.commit().await?;
+    Ok(url_map)
+}
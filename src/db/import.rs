@@ -0,0 +1,142 @@
+use crate::db::{Transaction, UrlMap};
+use serde::{Serialize, Deserialize};
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportMode {
+    Skip,
+    Overwrite,
+    Fail,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportOptions {
+    pub mode: ImportMode,
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportStatus {
+    Created,
+    Updated,
+    Skipped,
+    Rejected,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImportRow {
+    pub row: usize,
+    pub domain: Option<String>,
+    pub key: Option<String>,
+    pub status: ImportStatus,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub applied: bool,
+    pub created: usize,
+    pub updated: usize,
+    pub skipped: usize,
+    pub rejected: usize,
+    pub rows: Vec<ImportRow>,
+}
+
+impl ImportReport {
+    fn push(&mut self, row: usize, url_map: Option<&UrlMap>, status: ImportStatus, error: Option<String>) {
+        match status {
+            ImportStatus::Created => self.created += 1,
+            ImportStatus::Updated => self.updated += 1,
+            ImportStatus::Skipped => self.skipped += 1,
+            ImportStatus::Rejected => self.rejected += 1,
+        }
+        let (domain, key) = match url_map {
+            Some(url_map) => (Some(url_map.domain.clone()), Some(url_map.key.clone())),
+            None => (None, None),
+        };
+        self.rows.push(ImportRow { row, domain, key, status, error });
+    }
+
+    // Domains and keys whose destination changed once the import is
+    // committed.
+    pub fn changed_keys(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.rows
+            .iter()
+            .filter(|row| matches!(row.status, ImportStatus::Created | ImportStatus::Updated))
+            .filter_map(|row| Some((row.domain.as_deref()?, row.key.as_deref()?)))
+    }
+}
+
+// Applies `rows` to the url maps of `tenant` in a single transaction. Rows
+// that failed to parse or validate are rejected without affecting the others,
+// while an existing key in `Fail` mode rolls back the whole import. Row
+// numbers start at 1.
+pub async fn import_url_maps(
+    mut tx: Box<dyn Transaction>,
+    tenant: &str,
+    rows: Vec<Result<UrlMap, String>>,
+    options: ImportOptions,
+    actor: Option<&str>,
+) -> Result<ImportReport, sqlx::Error> {
+    let mut report = ImportReport { dry_run: options.dry_run, ..Default::default() };
+    let mut failed = false;
+    let mut domains = HashSet::new();
+
+    for (i, row) in rows.into_iter().enumerate() {
+        let url_map = match row.and_then(|url_map| url_map.validate().map(|_| url_map)) {
+            Ok(url_map) => UrlMap { tenant: tenant.into(), ..url_map },
+            Err(e) => {
+                report.push(i + 1, None, ImportStatus::Rejected, Some(e));
+                continue;
+            }
+        };
+        let row = Some(&url_map);
+        if failed {
+            report.push(i + 1, row, ImportStatus::Skipped, None);
+            continue;
+        }
+        if !domains.contains(&url_map.domain) {
+            if !tx.get_domain(&url_map.domain).await?.is_some_and(|domain| domain.is_usable_by(tenant)) {
+                report.push(i + 1, row, ImportStatus::Rejected, Some("domain does not exist".into()));
+                continue;
+            }
+            domains.insert(url_map.domain.clone());
+        }
+        let status = match (tx.get_url_map(&url_map.domain, &url_map.key).await?, options.mode) {
+            (Some(existing), _) if existing.tenant != tenant => {
+                report.push(i + 1, row, ImportStatus::Rejected, Some("key belongs to another tenant".into()));
+                continue;
+            }
+            (Some(existing), _) if existing.deleted_at.is_some() => {
+                report.push(i + 1, row, ImportStatus::Rejected, Some("key is in the trash".into()));
+                continue;
+            }
+            (None, _) => {
+                tx.create_url_map(&url_map, actor).await?;
+                ImportStatus::Created
+            }
+            (Some(_), ImportMode::Skip) => ImportStatus::Skipped,
+            (Some(_), ImportMode::Overwrite) => {
+                tx.update_url_map(&url_map, actor).await?;
+                ImportStatus::Updated
+            }
+            (Some(_), ImportMode::Fail) => {
+                failed = true;
+                report.push(i + 1, row, ImportStatus::Rejected, Some("key already exists".into()));
+                continue;
+            }
+        };
+        report.push(i + 1, row, status, None);
+    }
+
+    if failed || options.dry_run {
+        tx.rollback().await?;
+    } else {
+        tx.commit().await?;
+        report.applied = true;
+    }
+    Ok(report)
+}
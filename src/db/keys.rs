@@ -0,0 +1,157 @@
+use crate::{config, config::KeyStrategy, db::{NewUrlMap, Storage, UrlMap, storage::is_unique_violation}};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use sqids::Sqids;
+use std::convert::TryInto;
+
+const BASE62: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+#[derive(Debug)]
+pub struct KeyGenerator {
+    strategy: KeyStrategy,
+    length: usize,
+    max_attempts: u32,
+    sqids: Sqids,
+}
+
+impl KeyGenerator {
+    pub fn new(config: &config::Keys) -> Self {
+        let sqids = Sqids::builder()
+            .min_length(config.length.min(u8::MAX as usize) as u8)
+            .build()
+            .unwrap();
+        Self {
+            strategy: config.strategy,
+            length: config.length,
+            max_attempts: config.max_attempts,
+            sqids,
+        }
+    }
+
+    fn random(&self) -> String {
+        let mut rng = rand::thread_rng();
+        (0..self.length)
+            .map(|_| BASE62[rng.gen_range(0..BASE62.len())] as char)
+            .collect()
+    }
+
+    // The same url always gets the same keys, later attempts salt the url so
+    // that a collision with a different url can still be resolved.
+    fn hash(&self, url: &str, attempt: u32) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(url.as_bytes());
+        if attempt > 0 {
+            hasher.update(format!("#{}", attempt).as_bytes());
+        }
+        let digest = hasher.finalize();
+        let mut n = u128::from_be_bytes(digest[..16].try_into().unwrap());
+        let mut key = String::with_capacity(self.length);
+        while key.len() < self.length {
+            key.push(BASE62[(n % 62) as usize] as char);
+            n /= 62;
+        }
+        key
+    }
+
+    async fn generate<S: Storage + ?Sized>(&self, storage: &S, url: &str, attempt: u32) -> Result<String, sqlx::Error> {
+        let key = match self.strategy {
+            KeyStrategy::Random => self.random(),
+            KeyStrategy::Hash => self.hash(url, attempt),
+            KeyStrategy::Sqids => {
+                let n = storage.next_key_sequence().await?;
+                self.sqids.encode(&[n as u64]).map_err(|e| sqlx::Error::Configuration(Box::new(e)))?
+            }
+        };
+        Ok(key)
+    }
+}
+
+// Whether `existing`, found under a key hashed from the url of `url_map`, is
+// the very url map being created again.
+fn is_same(existing: &UrlMap, url_map: &NewUrlMap) -> bool {
+    existing.tenant == url_map.tenant
+        && existing.url == url_map.url
+        && existing.expires_at == url_map.expires_at
+        && existing.not_before == url_map.not_before
+        && existing.max_clicks == url_map.max_clicks
+        && existing.prefix == url_map.prefix
+}
+
+// Creates a url map under a freshly generated key, generating another one
+// whenever the key is already taken. With the `hash` strategy a key taken by
+// the same url map is returned as it is, so shortening a url twice gives the
+// same key.
+pub async fn create_url_map<S: Storage + ?Sized>(
+    storage: &S,
+    keys: &KeyGenerator,
+    url_map: NewUrlMap,
+    actor: Option<String>,
+) -> Result<UrlMap, sqlx::Error> {
+    let mut attempt = 0;
+    loop {
+        let key = keys.generate(storage, &url_map.url, attempt).await?;
+        match storage.create_url_map(url_map.to_url_map(key.clone()), actor.clone()).await {
+            Err(e) if is_unique_violation(&e) => {
+                if let KeyStrategy::Hash = keys.strategy {
+                    match storage.get_url_map(url_map.domain.clone(), key).await {
+                        Ok(existing) if is_same(&existing, &url_map) => return Ok(existing),
+                        Ok(_) | Err(sqlx::Error::RowNotFound) => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                if attempt + 1 >= keys.max_attempts {
+                    return Err(e);
+                }
+                tracing::warn!("Generated key collided on attempt {}, retrying", attempt + 1);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generator(strategy: KeyStrategy) -> KeyGenerator {
+        KeyGenerator::new(&config::Keys { strategy, length: 7, max_attempts: 5 })
+    }
+
+    #[test]
+    fn hash_is_deterministic_per_attempt() {
+        let keys = generator(KeyStrategy::Hash);
+        let key = keys.hash("https://github.com", 0);
+        assert_eq!(key.len(), 7);
+        assert!(key.bytes().all(|byte| BASE62.contains(&byte)));
+        assert_eq!(key, keys.hash("https://github.com", 0));
+        assert_ne!(key, keys.hash("https://github.com", 1));
+        assert_ne!(key, keys.hash("https://gitlab.com", 0));
+    }
+
+    fn new_url_map(url: &str) -> NewUrlMap {
+        NewUrlMap {
+            tenant: crate::db::DEFAULT_TENANT.into(),
+            domain: String::new(),
+            key: None,
+            url: url.into(),
+            expires_at: None,
+            not_before: None,
+            max_clicks: None,
+            prefix: false,
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn hash_returns_the_url_map_of_the_same_url() {
+        let storage = crate::db::SqliteStorage::in_memory().await.unwrap();
+        let keys = generator(KeyStrategy::Hash);
+        let first = create_url_map(&storage, &keys, new_url_map("https://github.com"), None).await.unwrap();
+        let again = create_url_map(&storage, &keys, new_url_map("https://github.com"), None).await.unwrap();
+        assert_eq!(again.key, first.key);
+
+        let limited = NewUrlMap { max_clicks: Some(1), ..new_url_map("https://github.com") };
+        let limited = create_url_map(&storage, &keys, limited, None).await.unwrap();
+        assert_eq!(limited.key, keys.hash("https://github.com", 1));
+    }
+}
@@ -1,91 +1,671 @@
-use crate::db::{DB, UrlMap};
-use sqlx::{Postgres, pool::PoolConnection};
-use tokio::sync::{mpsc::Receiver, oneshot::Sender};
+use crate::{config::CONFIG, db::{ApiToken, BatchReport, BatchRequest, Cache, Click, ClickExport, ClickSource, DailyVisitors, DB, Domain, ExportRows, GeoIp, ImportOptions, ImportReport, Interval, KeyGenerator, NewApiToken, NewUrlMap, StatsQuery, Storage, Tenant, TopLink, UrlMap, UrlMapChange, UrlMapPage, UrlMapQuery, UrlMapStats}};
+use chrono::{DateTime, Utc};
+use std::{future::Future, io, sync::Arc, time::Duration};
+use tokio::sync::{Semaphore, mpsc, mpsc::{Receiver, error::SendTimeoutError}, oneshot::Sender, watch};
 
 type Responder<T> = Sender<Result<T, sqlx::Error>>;
 
+// Rows buffered between the database and a slow export client.
+const EXPORT_BUFFER: usize = 64;
+
 #[derive(Debug)]
 pub enum Message {
-    GetUrlMaps { resp: Responder<Vec<UrlMap>> },
-    GetUrlMap { key: String, resp: Responder<UrlMap> },
-    CreateUrlMap { url_map: UrlMap, resp: Responder<UrlMap> },
-    UpdateUrlMap { url_map: UrlMap, resp: Responder<UrlMap> },
-    DeleteUrlMap { key: String, resp: Responder<UrlMap> },
+    GetUrlMaps { tenant: String, query: UrlMapQuery, resp: Responder<UrlMapPage> },
+    GetUrlMap { tenant: String, domain: String, key: String, resp: Responder<UrlMap> },
+    // Looks a url map up for a redirect, whichever tenant owns it.
+    ResolveUrlMap { domain: String, path: String, resp: Responder<UrlMap> },
+    UseClick { domain: String, key: String, resp: Responder<Option<UrlMap>> },
+    CreateUrlMap { tenant: String, url_map: NewUrlMap, actor: Option<String>, resp: Responder<UrlMap> },
+    UpdateUrlMap { tenant: String, url_map: UrlMap, actor: Option<String>, resp: Responder<UrlMap> },
+    DeleteUrlMap { tenant: String, domain: String, key: String, actor: Option<String>, resp: Responder<UrlMap> },
+    RestoreUrlMap { tenant: String, domain: String, key: String, actor: Option<String>, resp: Responder<UrlMap> },
+    PurgeUrlMap { tenant: String, domain: String, key: String, actor: Option<String>, resp: Responder<UrlMap> },
+    GetUrlMapHistory { tenant: String, domain: String, key: String, resp: Responder<Vec<UrlMapChange>> },
+    GetUrlMapVersion { tenant: String, domain: String, key: String, at: DateTime<Utc>, resp: Responder<UrlMapChange> },
+    RevertUrlMap { tenant: String, domain: String, key: String, version: i64, actor: Option<String>, resp: Responder<UrlMap> },
+    GetUrlMapStats { tenant: String, domain: String, key: String, query: StatsQuery, resp: Responder<UrlMapStats> },
+    GetTopLinks { tenant: String, query: StatsQuery, resp: Responder<Vec<TopLink>> },
+    ExportUrlMaps { tenant: String, resp: Responder<mpsc::Receiver<Result<UrlMap, sqlx::Error>>> },
+    ExportClicks { tenant: String, export: ClickExport, resp: Responder<ExportRows> },
+    ImportUrlMaps {
+        tenant: String,
+        rows: Vec<Result<UrlMap, String>>,
+        options: ImportOptions,
+        actor: Option<String>,
+        resp: Responder<ImportReport>,
+    },
+    ApplyBatch { tenant: String, request: BatchRequest, actor: Option<String>, resp: Responder<BatchReport> },
+    GetDomains { tenant: String, resp: Responder<Vec<Domain>> },
+    // Every registered domain, for resolving the host of a redirect.
+    ResolveDomains { resp: Responder<Vec<Domain>> },
+    CreateDomain { tenant: String, name: String, actor: Option<String>, resp: Responder<Domain> },
+    DeleteDomain { tenant: String, name: String, resp: Responder<Domain> },
+    GetTenants { resp: Responder<Vec<Tenant>> },
+    CreateTenant { name: String, actor: Option<String>, resp: Responder<Tenant> },
+    DeleteTenant { name: String, resp: Responder<Tenant> },
+    GetApiTokens { tenant: String, resp: Responder<Vec<ApiToken>> },
+    CreateApiToken { tenant: String, name: String, actor: Option<String>, resp: Responder<NewApiToken> },
+    DeleteApiToken { tenant: String, id: i64, resp: Responder<ApiToken> },
+    FindApiToken { token_hash: String, resp: Responder<Option<ApiToken>> },
 }
 
-pub struct Manager {
-    db: DB,
-    receiver: Receiver<Message>,
+#[derive(Clone)]
+struct Worker {
+    storage: Arc<dyn Storage>,
+    cache: Cache,
+    keys: Arc<KeyGenerator>,
+    geoip: Option<Arc<GeoIp>>,
+    timeout: Duration,
+    export_timeout: Duration,
+    // Taken by the background tasks for each round of their work.
+    background: Arc<Semaphore>,
 }
 
-type Connection = PoolConnection<Postgres>;
+impl Worker {
+    async fn run<T, F>(&self, f: F) -> Result<T, sqlx::Error>
+    where
+        F: Future<Output = Result<T, sqlx::Error>>,
+    {
+        match tokio::time::timeout(self.timeout, f).await {
+            Ok(result) => result,
+            Err(_) => Err(sqlx::Error::Io(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("database manager timed out after {:?}", self.timeout),
+            ))),
+        }
+    }
 
-impl Manager {
-    pub fn new(db: DB, receiver: Receiver<Message>) -> Self {
-        Self { db, receiver }
+    // Relays the rows streamed by `export` to the client in `sender`, which
+    // is given `export_timeout` to take each one. Once it gives up the relay
+    // stops, and so does the export, releasing its connection.
+    async fn export<T, F>(&self, sender: mpsc::Sender<Result<T, sqlx::Error>>, export: impl FnOnce(mpsc::Sender<Result<T, sqlx::Error>>) -> F)
+    where
+        F: Future<Output = ()>,
+    {
+        let (tx, mut rx) = mpsc::channel(1);
+        let relay = async move {
+            while let Some(row) = rx.recv().await {
+                match sender.send_timeout(row, self.export_timeout).await {
+                    Ok(_) => {}
+                    Err(SendTimeoutError::Timeout(_)) => {
+                        tracing::warn!("Abandoned an export after {:?} without a read", self.export_timeout);
+                        break;
+                    }
+                    Err(SendTimeoutError::Closed(_)) => break,
+                }
+            }
+        };
+        tokio::join!(export(tx), relay);
     }
 
-    async fn get_url_maps(conn: &mut Connection) -> Result<Vec<UrlMap>, sqlx::Error> {
-        sqlx::query_as::<_, UrlMap>("SELECT * FROM url_maps")
-            .fetch_all(conn)
-            .await
+    fn invalidate(&self, url_map: &Result<UrlMap, sqlx::Error>) {
+        if let Ok(url_map) = url_map {
+            self.cache.invalidate(&url_map.domain, &url_map.key);
+        }
+    }
+
+    // Registering or removing a domain changes which namespace hosts resolve
+    // to, so nothing cached can be trusted.
+    fn clear_domains(&self, domain: &Result<Domain, sqlx::Error>) {
+        if domain.is_ok() {
+            self.cache.clear();
+        }
+    }
+
+    async fn handle(&self, message: Message) {
+        match message {
+            Message::GetUrlMaps { tenant, query, resp } => {
+                let url_maps = self.run(self.storage.get_url_maps(tenant, query)).await;
+                resp_failed!(resp.send(url_maps), "GetUrlMaps");
+            }
+            // Keys are unique across tenants, so the url map is looked up by
+            // key alone and hidden when someone else owns it.
+            Message::GetUrlMap { tenant, domain, key, resp } => {
+                let url_map = self.run(self.storage.get_url_map(domain, key)).await
+                    .and_then(|url_map| match url_map.tenant == tenant {
+                        true => Ok(url_map),
+                        false => Err(sqlx::Error::RowNotFound),
+                    });
+                resp_failed!(resp.send(url_map), "GetUrlMap");
+            }
+            Message::ResolveUrlMap { domain, path, resp } => {
+                let url_map = self.run(self.storage.resolve_url_map(domain, path)).await;
+                resp_failed!(resp.send(url_map), "ResolveUrlMap");
+            }
+            Message::UseClick { domain, key, resp } => {
+                let url_map = self.run(self.storage.use_click(domain, key)).await;
+                resp_failed!(resp.send(url_map), "UseClick");
+            }
+            Message::CreateUrlMap { tenant, url_map, actor, resp } => {
+                let url_map = NewUrlMap { tenant, ..url_map };
+                let url_map = match url_map.key.clone() {
+                    Some(key) => self.run(self.storage.create_url_map(url_map.to_url_map(key), actor)).await,
+                    None => self.run(self.storage.generate_url_map(url_map, actor, &self.keys)).await,
+                };
+                self.invalidate(&url_map);
+                resp_failed!(resp.send(url_map), "CreateUrlMap");
+            }
+            Message::UpdateUrlMap { tenant, url_map, actor, resp } => {
+                let url_map = self.run(self.storage.update_url_map(UrlMap { tenant, ..url_map }, actor)).await;
+                self.invalidate(&url_map);
+                resp_failed!(resp.send(url_map), "UpdateUrlMap");
+            }
+            Message::DeleteUrlMap { tenant, domain, key, actor, resp } => {
+                let url_map = self.run(self.storage.delete_url_map(tenant, domain, key, actor)).await;
+                self.invalidate(&url_map);
+                resp_failed!(resp.send(url_map), "DeleteUrlMap");
+            }
+            Message::RestoreUrlMap { tenant, domain, key, actor, resp } => {
+                let url_map = self.run(self.storage.restore_url_map(tenant, domain, key, actor)).await;
+                self.invalidate(&url_map);
+                resp_failed!(resp.send(url_map), "RestoreUrlMap");
+            }
+            Message::PurgeUrlMap { tenant, domain, key, actor, resp } => {
+                let url_map = self.run(self.storage.purge_url_map(tenant, domain, key, actor)).await;
+                resp_failed!(resp.send(url_map), "PurgeUrlMap");
+            }
+            Message::GetUrlMapHistory { tenant, domain, key, resp } => {
+                let history = self.run(self.storage.get_url_map_history(tenant, domain, key)).await;
+                resp_failed!(resp.send(history), "GetUrlMapHistory");
+            }
+            Message::GetUrlMapVersion { tenant, domain, key, at, resp } => {
+                let change = self.run(self.storage.get_url_map_version(tenant, domain, key, at)).await;
+                resp_failed!(resp.send(change), "GetUrlMapVersion");
+            }
+            Message::RevertUrlMap { tenant, domain, key, version, actor, resp } => {
+                let url_map = self.run(self.storage.revert_url_map(tenant, domain, key, version, actor)).await;
+                self.invalidate(&url_map);
+                resp_failed!(resp.send(url_map), "RevertUrlMap");
+            }
+            Message::GetUrlMapStats { tenant, domain, key, query, resp } => {
+                let stats = self.run(self.storage.get_url_map_stats(tenant, domain, key, query)).await;
+                resp_failed!(resp.send(stats), "GetUrlMapStats");
+            }
+            Message::GetTopLinks { tenant, query, resp } => {
+                let links = self.run(self.storage.get_top_links(tenant, query)).await;
+                resp_failed!(resp.send(links), "GetTopLinks");
+            }
+            // Exports run for as long as the client keeps reading, so they
+            // are not subject to the message timeout, see `Manager::listen`.
+            Message::ExportUrlMaps { tenant, resp } => {
+                let (tx, rx) = mpsc::channel(EXPORT_BUFFER);
+                resp_failed!(resp.send(Ok(rx)), "ExportUrlMaps");
+                self.export(tx, |tx| self.storage.export_url_maps(tenant, tx)).await;
+            }
+            Message::ExportClicks { tenant, export, resp } => match export.granularity {
+                ClickSource::Raw => {
+                    let (tx, rx) = mpsc::channel(EXPORT_BUFFER);
+                    resp_failed!(resp.send(Ok(ExportRows::Clicks(rx))), "ExportClicks");
+                    self.export(tx, |tx| self.storage.export_clicks(tenant, &export, tx)).await;
+                }
+                ClickSource::Hourly | ClickSource::Daily => {
+                    let (tx, rx) = mpsc::channel(EXPORT_BUFFER);
+                    resp_failed!(resp.send(Ok(ExportRows::Rollups(rx))), "ExportClicks");
+                    self.export(tx, |tx| self.storage.export_rollups(tenant, &export, tx)).await;
+                }
+            },
+            Message::ImportUrlMaps { tenant, rows, options, actor, resp } => {
+                let report = self.run(self.storage.import_url_maps(tenant, rows, options, actor)).await;
+                if let Ok(report) = &report {
+                    if report.applied {
+                        report.changed_keys().for_each(|(domain, key)| self.cache.invalidate(domain, key));
+                    }
+                }
+                resp_failed!(resp.send(report), "ImportUrlMaps");
+            }
+            Message::ApplyBatch { tenant, request, actor, resp } => {
+                let report = self.run(self.storage.apply_batch(tenant, request, actor)).await;
+                if let Ok(report) = &report {
+                    if report.committed {
+                        report.changed_keys().for_each(|(domain, key)| self.cache.invalidate(domain, key));
+                    }
+                }
+                resp_failed!(resp.send(report), "ApplyBatch");
+            }
+            Message::GetDomains { tenant, resp } => {
+                let domains = self.run(self.storage.get_domains(tenant)).await;
+                resp_failed!(resp.send(domains), "GetDomains");
+            }
+            Message::ResolveDomains { resp } => {
+                let domains = self.run(self.storage.resolve_domains()).await;
+                resp_failed!(resp.send(domains), "ResolveDomains");
+            }
+            Message::CreateDomain { tenant, name, actor, resp } => {
+                let domain = self.run(self.storage.create_domain(tenant, name, actor)).await;
+                self.clear_domains(&domain);
+                resp_failed!(resp.send(domain), "CreateDomain");
+            }
+            Message::DeleteDomain { tenant, name, resp } => {
+                let domain = self.run(self.storage.delete_domain(tenant, name)).await;
+                self.clear_domains(&domain);
+                resp_failed!(resp.send(domain), "DeleteDomain");
+            }
+            Message::GetTenants { resp } => {
+                let tenants = self.run(self.storage.get_tenants()).await;
+                resp_failed!(resp.send(tenants), "GetTenants");
+            }
+            Message::CreateTenant { name, actor, resp } => {
+                let tenant = self.run(self.storage.create_tenant(name, actor)).await;
+                resp_failed!(resp.send(tenant), "CreateTenant");
+            }
+            Message::DeleteTenant { name, resp } => {
+                let tenant = self.run(self.storage.delete_tenant(name)).await;
+                resp_failed!(resp.send(tenant), "DeleteTenant");
+            }
+            Message::GetApiTokens { tenant, resp } => {
+                let api_tokens = self.run(self.storage.get_api_tokens(tenant)).await;
+                resp_failed!(resp.send(api_tokens), "GetApiTokens");
+            }
+            Message::CreateApiToken { tenant, name, actor, resp } => {
+                let token = ApiToken::generate();
+                let api_token = self.run(self.storage.create_api_token(tenant, name, ApiToken::hash(&token), actor)).await
+                    .map(|api_token| NewApiToken { token, api_token });
+                resp_failed!(resp.send(api_token), "CreateApiToken");
+            }
+            Message::DeleteApiToken { tenant, id, resp } => {
+                let api_token = self.run(self.storage.delete_api_token(tenant, id)).await;
+                resp_failed!(resp.send(api_token), "DeleteApiToken");
+            }
+            Message::FindApiToken { token_hash, resp } => {
+                let api_token = self.run(self.storage.find_api_token(token_hash)).await;
+                resp_failed!(resp.send(api_token), "FindApiToken");
+            }
+        }
     }
 
-    async fn get_url_map(conn: &mut Connection, key: String) -> Result<UrlMap, sqlx::Error> {
-        sqlx::query_as::<_, UrlMap>("SELECT * FROM url_maps WHERE key = $1")
-            .bind(key)
-            .fetch_one(conn)
-            .await
+    // Periodically purges url maps that have been in the trash for longer
+    // than `purge_after`.
+    async fn purge_trash(self, purge_after: Duration, interval: Duration, mut stop: watch::Receiver<()>) {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = stop.changed() => break,
+            }
+            let _slot = self.background.acquire().await.unwrap();
+            let deleted_before = Utc::now() - chrono::Duration::from_std(purge_after).unwrap();
+            match self.run(self.storage.purge_trash(deleted_before)).await {
+                Ok(url_maps) if !url_maps.is_empty() => {
+                    tracing::info!("Purged {} url maps from the trash", url_maps.len());
+                }
+                Ok(_) => {}
+                Err(e) => tracing::error!("Failed to purge the trash, error: {}", e),
+            }
+        }
     }
 
-    async fn create_url_map(conn: &mut Connection, url_map: UrlMap) -> Result<UrlMap, sqlx::Error> {
-        sqlx::query_as::<_, UrlMap>("INSERT INTO url_maps (key, url) VALUES ($1, $2) RETURNING *")
-            .bind(url_map.key)
-            .bind(url_map.url)
-            .fetch_one(conn)
-            .await
+    // Periodically moves url maps that expired more than `archive_after` ago
+    // to the trash.
+    async fn archive_expired(self, archive_after: Duration, interval: Duration, mut stop: watch::Receiver<()>) {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = stop.changed() => break,
+            }
+            let _slot = self.background.acquire().await.unwrap();
+            let expired_before = Utc::now() - chrono::Duration::from_std(archive_after).unwrap();
+            match self.run(self.storage.archive_expired(expired_before)).await {
+                Ok(url_maps) => {
+                    url_maps.iter().for_each(|url_map| self.cache.invalidate(&url_map.domain, &url_map.key));
+                    if !url_maps.is_empty() {
+                        tracing::info!("Archived {} expired url maps", url_maps.len());
+                    }
+                }
+                Err(e) => tracing::error!("Failed to archive expired url maps, error: {}", e),
+            }
+        }
     }
 
-    async fn update_url_map(conn: &mut Connection, url_map: UrlMap) -> Result<UrlMap, sqlx::Error> {
-        sqlx::query_as::<_, UrlMap>("UPDATE url_maps SET url=$1 WHERE key=$2 RETURNING *")
-            .bind(url_map.url)
-            .bind(url_map.key)
-            .fetch_one(conn)
-            .await
+    // Purges the raw clicks recorded before `clicked_before` that have been
+    // rolled up, the others would be lost without a trace.
+    async fn purge_rolled_up_clicks(&self, clicked_before: DateTime<Utc>) -> Result<u64, sqlx::Error> {
+        self.run(self.storage.purge_clicks(clicked_before, true)).await
     }
 
-    async fn delete_url_map(conn: &mut Connection, key: String) -> Result<UrlMap, sqlx::Error> {
-        sqlx::query_as::<_, UrlMap>("DELETE FROM url_maps WHERE key = $1 RETURNING *")
-            .bind(key)
-            .fetch_one(conn)
-            .await
+    // Periodically purges clicks recorded more than `retention` ago. Clicks
+    // not rolled up yet are purged as well, they are then missing from the
+    // stats.
+    async fn purge_clicks(self, retention: Duration, interval: Duration, mut stop: watch::Receiver<()>) {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = stop.changed() => break,
+            }
+            let _slot = self.background.acquire().await.unwrap();
+            let clicked_before = Utc::now() - chrono::Duration::from_std(retention).unwrap();
+            match self.purge_rolled_up_clicks(clicked_before).await {
+                Ok(0) => {}
+                Ok(purged) => tracing::info!("Purged {} clicks past their retention", purged),
+                Err(e) => tracing::error!("Failed to purge clicks, error: {}", e),
+            }
+            match self.run(self.storage.purge_clicks(clicked_before, false)).await {
+                Ok(0) => {}
+                Ok(purged) => tracing::error!("Purged {} clicks past their retention before they were rolled up, stats will miss them", purged),
+                Err(e) => tracing::error!("Failed to purge clicks, error: {}", e),
+            }
+        }
     }
 
-    pub async fn listen(&mut self) {
-        while let Some(message) = self.receiver.recv().await {
-            let mut connection = self.db.pool.acquire().await.unwrap();
-            match message {
-                Message::GetUrlMaps { resp } => {
-                    let url_maps = Self::get_url_maps(&mut connection).await;
-                    resp_failed!(resp.send(url_maps), "GetUrlMaps");
+    // Periodically rolls up the raw clicks older than `delay`, then purges
+    // the raw clicks and hourly aggregates past their retention. Raw clicks
+    // are only purged once rolled up.
+    async fn roll_up_clicks(self, delay: Duration, raw_retention: Duration, hourly_retention: Duration, interval: Duration, mut stop: watch::Receiver<()>) {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = stop.changed() => break,
+            }
+            let _slot = self.background.acquire().await.unwrap();
+            let now = Utc::now();
+            let until = Interval::Hour.truncate(now - chrono::Duration::from_std(delay).unwrap());
+            // Each call rolls up at most a day, so a backlog is caught up on
+            // in steps.
+            loop {
+                match self.run(self.storage.roll_up_clicks(until)).await {
+                    Ok(rolled_until) if rolled_until < until => continue,
+                    Ok(_) => break,
+                    Err(e) => {
+                        tracing::error!("Failed to roll up clicks, error: {}", e);
+                        break;
+                    }
                 }
-                Message::GetUrlMap { key, resp } => {
-                    let url_map = Self::get_url_map(&mut connection, key).await;
-                    resp_failed!(resp.send(url_map), "GetUrlMap");
+            }
+
+            if !raw_retention.is_zero() {
+                let clicked_before = now - chrono::Duration::from_std(raw_retention).unwrap();
+                match self.purge_rolled_up_clicks(clicked_before).await {
+                    Ok(0) => {}
+                    Ok(purged) => tracing::info!("Purged {} rolled up clicks", purged),
+                    Err(e) => tracing::error!("Failed to purge rolled up clicks, error: {}", e),
                 }
-                Message::CreateUrlMap { url_map, resp } => {
-                    let url_map = Self::create_url_map(&mut connection, url_map).await;
-                    resp_failed!(resp.send(url_map), "CreateUrlMap");
+            }
+            if !hourly_retention.is_zero() {
+                let before = now - chrono::Duration::from_std(hourly_retention).unwrap();
+                match self.run(self.storage.purge_hourly_clicks(before)).await {
+                    Ok(0) => {}
+                    Ok(purged) => tracing::info!("Purged {} hourly click aggregates", purged),
+                    Err(e) => tracing::error!("Failed to purge hourly click aggregates, error: {}", e),
                 }
-                Message::UpdateUrlMap { url_map, resp } => {
-                    let url_map = Self::update_url_map(&mut connection, url_map).await;
-                    resp_failed!(resp.send(url_map), "UpdateUrlMap");
+            }
+        }
+    }
+
+    // Buffers clicks and writes them once `batch_size` have been collected or
+    // `interval` has passed, locating them just before. Clicks are best
+    // effort, a batch that fails to be written is dropped. Once stopped, the
+    // clicks already recorded are still written.
+    async fn write_clicks(self, mut receiver: Receiver<Click>, batch_size: usize, interval: Duration, mut stop: watch::Receiver<()>) {
+        let mut clicks = Vec::with_capacity(batch_size);
+        let mut interval = tokio::time::interval(interval);
+        let mut stopped = false;
+        loop {
+            let closed = tokio::select! {
+                _ = stop.changed(), if !stopped => {
+                    receiver.close();
+                    stopped = true;
+                    continue;
                 }
-                Message::DeleteUrlMap { key, resp } => {
-                    let url_map = Self::delete_url_map(&mut connection, key).await;
-                    resp_failed!(resp.send(url_map), "DeleteUrlMap");
+                click = receiver.recv() => match click {
+                    Some(click) => {
+                        clicks.push(click);
+                        if clicks.len() < batch_size {
+                            continue;
+                        }
+                        false
+                    }
+                    None => true,
+                },
+                _ = interval.tick() => false,
+            };
+            if !clicks.is_empty() {
+                let _slot = self.background.acquire().await.unwrap();
+                if let Some(geoip) = &self.geoip {
+                    clicks.iter_mut().for_each(|click| geoip.locate(click));
                 }
+                if let Err(e) = self.run(self.storage.insert_clicks(&clicks)).await {
+                    tracing::error!("Failed to write {} clicks, error: {}", clicks.len(), e);
+                }
+                let visitors = DailyVisitors::from_clicks(&clicks);
+                if let Err(e) = self.run(self.storage.merge_visitors(visitors)).await {
+                    tracing::error!("Failed to count visitors of {} clicks, error: {}", clicks.len(), e);
+                }
+                clicks.clear();
             }
+            if closed {
+                break;
+            }
+        }
+    }
+}
+
+pub struct Manager {
+    worker: Worker,
+    receiver: Receiver<Message>,
+    clicks: Option<Receiver<Click>>,
+    permits: Arc<Semaphore>,
+    exports: Arc<Semaphore>,
+}
+
+// Splits a pool of `connections` into those for messages, exports and the
+// background tasks. The background tasks get one and exports up to `exports`,
+// as long as at least one is left for messages. Those getting none share the
+// connections of messages.
+fn split_connections(connections: usize, exports: usize) -> (usize, usize, usize) {
+    let connections = connections.max(1);
+    let background = 1.min(connections - 1);
+    let exports = exports.max(1).min(connections - 1 - background);
+    (connections - background - exports, exports, background)
+}
+
+// Spawns `task`, which holds a clone of `running` until it is over.
+fn spawn(running: &mpsc::Sender<()>, task: impl Future<Output = ()> + Send + 'static) {
+    let running = running.clone();
+    tokio::spawn(async move {
+        task.await;
+        drop(running);
+    });
+}
+
+impl Manager {
+    pub fn new(db: DB, receiver: Receiver<Message>, cache: Cache, clicks: Receiver<Click>) -> Self {
+        // One in-flight message per pooled connection, anything beyond that
+        // would only queue up inside the pool. Exports and the background
+        // tasks get connections of their own, so that neither slow clients
+        // nor a long rollup can hold up everything else.
+        let (messages, exports, background) = split_connections(db.max_connections as usize, CONFIG.manager.exports);
+        let permits = Arc::new(Semaphore::new(messages));
+        let share = |connections| match connections {
+            0 => permits.clone(),
+            connections => Arc::new(Semaphore::new(connections)),
+        };
+        let (exports, background) = (share(exports), share(background));
+        let worker = Worker {
+            storage: db.storage,
+            cache,
+            keys: Arc::new(KeyGenerator::new(&CONFIG.keys)),
+            geoip: GeoIp::new(&CONFIG.geoip).map(Arc::new),
+            timeout: Duration::from_secs(CONFIG.manager.timeout),
+            export_timeout: Duration::from_secs(CONFIG.manager.export_timeout.max(1)),
+            background,
+        };
+        Self { worker, receiver, clicks: Some(clicks), permits, exports }
+    }
+
+    pub async fn listen(&mut self) {
+        self.listen_until(std::future::pending()).await
+    }
+
+    // Handles messages until `shutdown` completes or every sender is gone.
+    // The messages already queued are still handled and the background tasks
+    // finish their current round, pending clicks are written, and only then
+    // is the storage closed.
+    pub async fn listen_until(&mut self, shutdown: impl Future<Output = ()>) {
+        if let Err(e) = self.worker.storage.watch(self.worker.cache.clone()).await {
+            tracing::error!("Failed to watch for url map changes, error: {}", e);
+        }
+
+        // Background tasks stop once `stop` is dropped, and every task holds
+        // a clone of `running` so that `done` tells when they are all over.
+        let (stop, stopped) = watch::channel(());
+        let (running, mut done) = mpsc::channel::<()>(1);
+
+        if CONFIG.trash.purge_after > 0 {
+            spawn(&running, self.worker.clone().purge_trash(
+                Duration::from_secs(CONFIG.trash.purge_after),
+                Duration::from_secs(CONFIG.trash.purge_interval.max(1)),
+                stopped.clone(),
+            ));
+        }
+        spawn(&running, self.worker.clone().archive_expired(
+            Duration::from_secs(CONFIG.expiry.archive_after),
+            Duration::from_secs(CONFIG.expiry.archive_interval.max(1)),
+            stopped.clone(),
+        ));
+
+        if CONFIG.privacy.retention > 0 {
+            spawn(&running, self.worker.clone().purge_clicks(
+                Duration::from_secs(CONFIG.privacy.retention),
+                Duration::from_secs(CONFIG.privacy.purge_interval.max(1)),
+                stopped.clone(),
+            ));
+        }
+        spawn(&running, self.worker.clone().roll_up_clicks(
+            Duration::from_secs(CONFIG.rollups.delay),
+            Duration::from_secs(CONFIG.rollups.raw_retention),
+            Duration::from_secs(CONFIG.rollups.hourly_retention),
+            Duration::from_secs(CONFIG.rollups.interval.max(1)),
+            stopped.clone(),
+        ));
+        if let Some(clicks) = self.clicks.take() {
+            spawn(&running, self.worker.clone().write_clicks(
+                clicks,
+                CONFIG.clicks.batch_size.max(1),
+                Duration::from_millis(CONFIG.clicks.flush_interval.max(1)),
+                stopped,
+            ));
+        }
+
+        tokio::pin!(shutdown);
+        let mut stopping = false;
+        loop {
+            let message = tokio::select! {
+                message = self.receiver.recv() => match message {
+                    Some(message) => message,
+                    None => break,
+                },
+                _ = &mut shutdown, if !stopping => {
+                    self.receiver.close();
+                    stopping = true;
+                    continue;
+                }
+            };
+            // Exports wait for a permit of their own without holding up the
+            // queue.
+            if matches!(message, Message::ExportUrlMaps { .. } | Message::ExportClicks { .. }) {
+                let exports = self.exports.clone();
+                let worker = self.worker.clone();
+                spawn(&running, async move {
+                    let permit = exports.acquire_owned().await.unwrap();
+                    worker.handle(message).await;
+                    drop(permit);
+                });
+                continue;
+            }
+            let permit = self.permits.clone().acquire_owned().await.unwrap();
+            let worker = self.worker.clone();
+            spawn(&running, async move {
+                worker.handle(message).await;
+                drop(permit);
+            });
+        }
+
+        drop(stop);
+        drop(running);
+        done.recv().await;
+        self.worker.storage.close().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{ClickRange, SqliteStorage};
+
+    async fn worker() -> Worker {
+        Worker {
+            storage: Arc::new(SqliteStorage::in_memory().await.unwrap()),
+            cache: Cache::new(&CONFIG.cache),
+            keys: Arc::new(KeyGenerator::new(&CONFIG.keys)),
+            geoip: None,
+            timeout: Duration::from_secs(CONFIG.manager.timeout),
+            export_timeout: Duration::from_secs(CONFIG.manager.export_timeout),
+            background: Arc::new(Semaphore::new(1)),
+        }
+    }
+
+    fn click(clicked_at: DateTime<Utc>) -> Click {
+        Click {
+            tenant: "default".into(),
+            domain: String::new(),
+            key: "gh".into(),
+            clicked_at,
+            referrer: None,
+            user_agent: None,
+            ip: None,
+            utm_source: None,
+            country: None,
+            region: None,
+            is_bot: false,
+            visitor: None,
+        }
+    }
+
+    #[test]
+    fn connections_are_split_between_messages_exports_and_background_tasks() {
+        assert_eq!(split_connections(16, 2), (13, 2, 1));
+        assert_eq!(split_connections(16, 0), (14, 1, 1));
+        assert_eq!(split_connections(3, 2), (1, 1, 1));
+        assert_eq!(split_connections(2, 2), (1, 0, 1));
+        assert_eq!(split_connections(1, 2), (1, 0, 0));
+        assert_eq!(split_connections(0, 2), (1, 0, 0));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn clicks_are_only_purged_once_rolled_up() {
+        let worker = worker().await;
+        let hour = Interval::Hour.truncate(Utc::now()) - chrono::Duration::hours(3);
+        let clicks = [click(hour), click(hour + chrono::Duration::minutes(90))];
+        worker.storage.insert_clicks(&clicks).await.unwrap();
+
+        assert_eq!(worker.purge_rolled_up_clicks(Utc::now()).await.unwrap(), 0);
+
+        let rolled_until = hour + chrono::Duration::hours(1);
+        assert_eq!(worker.storage.roll_up_clicks(rolled_until).await.unwrap(), rolled_until);
+        assert_eq!(worker.purge_rolled_up_clicks(Utc::now()).await.unwrap(), 1);
+        assert_eq!(worker.purge_rolled_up_clicks(hour).await.unwrap(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn clicks_written_late_are_still_rolled_up() {
+        let worker = worker().await;
+        let hour = Interval::Hour.truncate(Utc::now()) - chrono::Duration::hours(3);
+        let until = hour + chrono::Duration::hours(2);
+        worker.storage.insert_clicks(&[click(hour)]).await.unwrap();
+        assert_eq!(worker.storage.roll_up_clicks(until).await.unwrap(), until);
+
+        worker.storage.insert_clicks(&[click(hour + chrono::Duration::minutes(30))]).await.unwrap();
+        assert_eq!(worker.storage.roll_up_clicks(until).await.unwrap(), until);
+        assert_eq!(worker.storage.get_rolled_until().await.unwrap(), Some(until));
+
+        let day = Interval::Day.truncate(hour);
+        for (source, from, to) in [(ClickSource::Hourly, hour, until), (ClickSource::Daily, day, day + chrono::Duration::days(1))] {
+            let range = ClickRange { source, from, to, include_bots: true };
+            let links = worker.storage.get_link_clicks("default".into(), &range).await.unwrap();
+            assert_eq!(links.iter().map(|link| link.clicks).collect::<Vec<_>>(), vec![2]);
         }
+        assert_eq!(worker.purge_rolled_up_clicks(Utc::now()).await.unwrap(), 2);
     }
 }
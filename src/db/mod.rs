@@ -1,5 +1,36 @@
+mod batch;
+mod cache;
+mod clicks;
+#[allow(clippy::module_inception)]
 mod db;
+mod domains;
+mod export;
+mod geoip;
+mod history;
+mod hll;
+mod import;
+mod keys;
 mod manager;
+mod query;
+mod rollups;
+mod stats;
+mod storage;
+mod tenants;
 
-pub use db::{UrlMap, DB};
+pub use batch::{BatchOperation, BatchReport, BatchRequest, BatchResult, BatchStatus};
+pub use cache::{Cache, CacheStats};
+pub use clicks::{Click, ClickRecorder};
+pub use db::{NewUrlMap, UrlMap, DB};
+pub use domains::Domain;
+pub use export::{ClickExport, ExportFormat, ExportRecord, ExportRows, ExportWriter, ExportedClick, ExportedRollup, ExportedUrlMap};
+pub use geoip::GeoIp;
+pub use history::UrlMapChange;
+pub use hll::HyperLogLog;
+pub use import::{ImportMode, ImportOptions, ImportReport, ImportRow, ImportStatus};
+pub use keys::KeyGenerator;
 pub use manager::{Manager, Message};
+pub use query::{Bind, SortField, SortOrder, UrlMapPage, UrlMapQuery};
+pub use rollups::{ClickRange, ClickSource};
+pub use stats::{ClickBucket, ClickCount, ClickField, DailyVisitors, Interval, StatsQuery, TopLink, UrlMapStats};
+pub use storage::{PostgresStorage, SqliteStorage, Storage, Transaction, is_foreign_key_violation};
+pub use tenants::{ApiToken, DEFAULT_TENANT, NewApiToken, Tenant};
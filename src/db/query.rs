@@ -0,0 +1,294 @@
+use crate::db::UrlMap;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Serialize, Deserialize};
+
+const DEFAULT_LIMIT: i64 = 50;
+const MAX_LIMIT: i64 = 1000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortField {
+    #[default]
+    Key,
+    Url,
+    CreatedAt,
+    UpdatedAt,
+}
+
+impl SortField {
+    fn column(&self) -> &'static str {
+        match self {
+            Self::Key => "key",
+            Self::Url => "url",
+            Self::CreatedAt => "created_at",
+            Self::UpdatedAt => "updated_at",
+        }
+    }
+
+    fn value(&self, url_map: &UrlMap) -> String {
+        match self {
+            Self::Key => url_map.key.clone(),
+            Self::Url => url_map.url.clone(),
+            Self::CreatedAt => url_map.created_at.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+            Self::UpdatedAt => url_map.updated_at.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+        }
+    }
+
+    // Inverse of `value`, giving the typed value to compare the column with.
+    fn bind(&self, value: &str) -> Result<Bind> {
+        match self {
+            Self::Key | Self::Url => Ok(Bind::Text(value.into())),
+            Self::CreatedAt | Self::UpdatedAt => DateTime::parse_from_rfc3339(value)
+                .map(|value| Bind::Timestamp(value.with_timezone(&Utc)))
+                .map_err(|_| anyhow!("Invalid cursor")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    fn sql(&self) -> (&'static str, &'static str) {
+        match self {
+            Self::Asc => ("ASC", ">"),
+            Self::Desc => ("DESC", "<"),
+        }
+    }
+}
+
+// A value bound to a placeholder of the query built by `UrlMapQuery::to_sql`.
+// Timestamps are bound as such since Postgres won't compare them with text.
+#[derive(Debug, Clone)]
+pub enum Bind {
+    Text(String),
+    Timestamp(DateTime<Utc>),
+}
+
+// Position after the last row of a page. Rows are ordered by the sort column
+// with the key as a tie breaker, so the pair identifies a row uniquely.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Cursor {
+    sort: SortField,
+    order: SortOrder,
+    value: String,
+    key: String,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).unwrap();
+        base64::encode_config(json, base64::URL_SAFE_NO_PAD)
+    }
+
+    fn decode(cursor: &str) -> Result<Self> {
+        let json = base64::decode_config(cursor, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| anyhow!("Invalid cursor"))?;
+        serde_json::from_slice(&json).map_err(|_| anyhow!("Invalid cursor"))
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UrlMapQuery {
+    // Url maps are listed one domain at a time, the default domain unless
+    // given.
+    pub domain: String,
+    pub limit: Option<i64>,
+    pub cursor: Option<String>,
+    pub sort: SortField,
+    pub order: SortOrder,
+    pub key_prefix: Option<String>,
+    pub url_prefix: Option<String>,
+    pub created_by: Option<String>,
+    pub updated_by: Option<String>,
+    // Lower bounds are inclusive, upper bounds exclusive.
+    pub created_after: Option<DateTime<Utc>>,
+    pub created_before: Option<DateTime<Utc>>,
+    pub updated_after: Option<DateTime<Utc>>,
+    pub updated_before: Option<DateTime<Utc>>,
+    // List the url maps in the trash instead of the live ones.
+    #[serde(skip)]
+    pub trashed: bool,
+    // Sort value and key of the last row of the previous page.
+    #[serde(skip)]
+    after: Option<(Bind, String)>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UrlMapPage {
+    pub url_maps: Vec<UrlMap>,
+    pub next: Option<String>,
+}
+
+impl UrlMapQuery {
+    pub fn parse(query: Option<&str>) -> Result<Self> {
+        let mut query: Self = serde_urlencoded::from_str(query.unwrap_or(""))?;
+        if let Some(limit) = query.limit {
+            if !(1..=MAX_LIMIT).contains(&limit) {
+                return Err(anyhow!("limit must be between 1 and {}", MAX_LIMIT));
+            }
+        }
+        if let Some(cursor) = query.cursor.as_deref().filter(|c| !c.is_empty()) {
+            let cursor = Cursor::decode(cursor)?;
+            if cursor.sort != query.sort || cursor.order != query.order {
+                return Err(anyhow!("Cursor does not match sort and order"));
+            }
+            query.after = Some((cursor.sort.bind(&cursor.value)?, cursor.key));
+        }
+        Ok(query)
+    }
+
+    pub fn limit(&self) -> i64 {
+        self.limit.unwrap_or(DEFAULT_LIMIT)
+    }
+
+    // Query string for the page following `next`, used to build links.
+    pub fn to_query_string(&self, next: &str) -> String {
+        let query = Self { cursor: Some(next.into()), ..self.clone() };
+        serde_urlencoded::to_string(&query).unwrap()
+    }
+
+    // Builds the SELECT of the url maps of `tenant` with `$n` placeholders,
+    // which both Postgres and SQLite accept, along with the values to bind in
+    // order. One row more than the limit is fetched to tell whether there is
+    // a next page.
+    pub fn to_sql(&self, tenant: &str) -> (String, Vec<Bind>) {
+        let mut conditions = vec![match self.trashed {
+            true => "deleted_at IS NOT NULL".to_string(),
+            false => "deleted_at IS NULL".to_string(),
+        }];
+        let mut binds = vec![Bind::Text(tenant.into()), Bind::Text(self.domain.clone())];
+        conditions.push("tenant = $1".to_string());
+        conditions.push("domain = $2".to_string());
+
+        for (column, prefix) in [("key", &self.key_prefix), ("url", &self.url_prefix)].iter() {
+            if let Some(prefix) = prefix.as_ref().filter(|p| !p.is_empty()) {
+                binds.push(Bind::Text(prefix.clone()));
+                let n = binds.len();
+                conditions.push(format!("substr({}, 1, length(${})) = ${}", column, n, n));
+            }
+        }
+
+        for (column, actor) in [("created_by", &self.created_by), ("updated_by", &self.updated_by)].iter() {
+            if let Some(actor) = actor.as_ref().filter(|a| !a.is_empty()) {
+                binds.push(Bind::Text(actor.clone()));
+                conditions.push(format!("{} = ${}", column, binds.len()));
+            }
+        }
+
+        let ranges = [
+            ("created_at", ">=", &self.created_after),
+            ("created_at", "<", &self.created_before),
+            ("updated_at", ">=", &self.updated_after),
+            ("updated_at", "<", &self.updated_before),
+        ];
+        for (column, comparison, bound) in ranges.iter() {
+            if let Some(bound) = bound {
+                binds.push(Bind::Timestamp(*bound));
+                conditions.push(format!("{} {} ${}", column, comparison, binds.len()));
+            }
+        }
+
+        let (direction, comparison) = self.order.sql();
+        if let Some((value, key)) = &self.after {
+            match self.sort {
+                SortField::Key => {
+                    binds.push(Bind::Text(key.clone()));
+                    conditions.push(format!("key {} ${}", comparison, binds.len()));
+                }
+                _ => {
+                    let column = self.sort.column();
+                    binds.push(value.clone());
+                    binds.push(Bind::Text(key.clone()));
+                    let (v, k) = (binds.len() - 1, binds.len());
+                    conditions.push(format!(
+                        "({} {} ${} OR ({} = ${} AND key {} ${}))",
+                        column, comparison, v, column, v, comparison, k
+                    ));
+                }
+            }
+        }
+
+        let mut sql = String::from("SELECT * FROM url_maps WHERE ");
+        sql.push_str(&conditions.join(" AND "));
+        sql.push_str(&format!(
+            " ORDER BY {} {}, key {} LIMIT {}",
+            self.sort.column(), direction, direction, self.limit() + 1
+        ));
+        (sql, binds)
+    }
+
+    pub fn page(&self, mut url_maps: Vec<UrlMap>) -> UrlMapPage {
+        let limit = self.limit() as usize;
+        let next = if url_maps.len() > limit {
+            url_maps.truncate(limit);
+            url_maps.last().map(|last| {
+                Cursor {
+                    sort: self.sort,
+                    order: self.order,
+                    value: self.sort.value(last),
+                    key: last.key.clone(),
+                }.encode()
+            })
+        } else {
+            None
+        };
+        UrlMapPage { url_maps, next }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url_maps(keys: &[&str]) -> Vec<UrlMap> {
+        keys.iter().map(|key| UrlMap::new(key.to_string(), format!("https://example.com/{}", key))).collect()
+    }
+
+    #[test]
+    fn page_hands_out_a_cursor_only_when_rows_are_left() {
+        let query = UrlMapQuery::parse(Some("limit=2")).unwrap();
+        let page = query.page(url_maps(&["a", "b", "c"]));
+        assert_eq!(page.url_maps.len(), 2);
+        let next = page.next.unwrap();
+        let next = UrlMapQuery::parse(Some(&query.to_query_string(&next))).unwrap();
+        assert!(matches!(&next.after, Some((Bind::Text(value), key)) if value == "b" && key == "b"));
+
+        assert!(query.page(url_maps(&["a", "b"])).next.is_none());
+    }
+
+    #[test]
+    fn cursor_must_match_sort_and_order() {
+        let query = UrlMapQuery::parse(Some("limit=1&sort=url")).unwrap();
+        let next = query.page(url_maps(&["a", "b"])).next.unwrap();
+        assert!(UrlMapQuery::parse(Some(&format!("sort=url&cursor={}", next))).is_ok());
+        assert!(UrlMapQuery::parse(Some(&format!("sort=key&cursor={}", next))).is_err());
+        assert!(UrlMapQuery::parse(Some(&format!("sort=url&order=desc&cursor={}", next))).is_err());
+        assert!(UrlMapQuery::parse(Some("cursor=garbage")).is_err());
+    }
+
+    #[test]
+    fn limit_is_bounded() {
+        assert!(UrlMapQuery::parse(Some("limit=0")).is_err());
+        assert!(UrlMapQuery::parse(Some("limit=1001")).is_err());
+        assert_eq!(UrlMapQuery::parse(None).unwrap().limit(), DEFAULT_LIMIT);
+    }
+
+    #[test]
+    fn sql_continues_after_the_cursor_with_the_key_as_tie_breaker() {
+        let query = UrlMapQuery::parse(Some("limit=1&sort=updated_at&order=desc")).unwrap();
+        let next = query.page(url_maps(&["a", "b"])).next.unwrap();
+        let query = UrlMapQuery::parse(Some(&query.to_query_string(&next))).unwrap();
+        let (sql, binds) = query.to_sql("default");
+        assert!(sql.contains("(updated_at < $3 OR (updated_at = $3 AND key < $4))"), "{}", sql);
+        assert!(sql.ends_with("ORDER BY updated_at DESC, key DESC LIMIT 2"), "{}", sql);
+        assert_eq!(binds.len(), 4);
+    }
+}
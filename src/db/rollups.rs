@@ -0,0 +1,148 @@
+use crate::db::{ClickField, Interval, StatsQuery};
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+
+// Where clicks are counted from. Raw clicks are rolled up hour by hour into
+// hourly and daily aggregates, which keep one row per key, bucket and value
+// of each `ClickField`, split between bots and humans.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClickSource {
+    #[default]
+    Raw,
+    Hourly,
+    Daily,
+}
+
+impl ClickSource {
+    pub fn table(&self) -> &'static str {
+        match self {
+            Self::Raw => "clicks",
+            Self::Hourly => "clicks_hourly",
+            Self::Daily => "clicks_daily",
+        }
+    }
+
+    pub fn time_column(&self) -> &'static str {
+        match self {
+            Self::Raw => "clicked_at",
+            Self::Hourly | Self::Daily => "bucket",
+        }
+    }
+
+    pub fn count(&self) -> &'static str {
+        match self {
+            Self::Raw => "COUNT(*)",
+            Self::Hourly | Self::Daily => "CAST(SUM(clicks) AS BIGINT)",
+        }
+    }
+
+    // Condition selecting the rows counting every click, rollups keep those
+    // under an empty field.
+    pub fn totals(&self) -> &'static str {
+        match self {
+            Self::Raw => "TRUE",
+            Self::Hourly | Self::Daily => "field = ''",
+        }
+    }
+
+    // Column and condition giving the values of `field`, rollups store
+    // missing values as empty strings.
+    pub fn values(&self, field: ClickField) -> (String, String) {
+        match self {
+            Self::Raw => (field.column().into(), "TRUE".into()),
+            Self::Hourly | Self::Daily => ("NULLIF(value, '')".into(), format!("field = '{}'", field.column())),
+        }
+    }
+}
+
+// Part of a stats range read from a single source.
+#[derive(Debug, Clone)]
+pub struct ClickRange {
+    pub source: ClickSource,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub include_bots: bool,
+}
+
+impl ClickRange {
+    // Splits the range of `query` at `rolled_until`, the end of the clicks
+    // rolled up so far. Whole days before it are read from the daily
+    // aggregates unless the stats are hourly, the rest of it from the hourly
+    // ones and anything after it from the raw clicks.
+    pub fn plan(query: &StatsQuery, rolled_until: Option<DateTime<Utc>>) -> Vec<Self> {
+        let (from, to) = query.range();
+        let split = rolled_until.map_or(from, |rolled_until| rolled_until.max(from).min(to));
+        let days_until = match query.interval {
+            Interval::Hour => from,
+            Interval::Day | Interval::Week => Interval::Day.truncate(split).max(from),
+        };
+        let range = |source, from, to| Self { source, from, to, include_bots: query.include_bots };
+        let mut ranges = vec![];
+        if from < days_until {
+            ranges.push(range(ClickSource::Daily, from, days_until));
+        }
+        if days_until < split {
+            ranges.push(range(ClickSource::Hourly, days_until, split));
+        }
+        if split < to {
+            ranges.push(range(ClickSource::Raw, split, to));
+        }
+        ranges
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(day: u32, hour: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2021, 7, day, hour, 0, 0).unwrap()
+    }
+
+    fn query(interval: Interval, from: DateTime<Utc>, to: DateTime<Utc>) -> StatsQuery {
+        StatsQuery { interval, from: Some(from), to: Some(to), ..Default::default() }
+    }
+
+    fn sources(ranges: &[ClickRange]) -> Vec<(ClickSource, DateTime<Utc>, DateTime<Utc>)> {
+        ranges.iter().map(|range| (range.source, range.from, range.to)).collect()
+    }
+
+    #[test]
+    fn nothing_rolled_up_reads_raw_clicks() {
+        let ranges = ClickRange::plan(&query(Interval::Day, at(1, 0), at(5, 0)), None);
+        assert_eq!(sources(&ranges), vec![(ClickSource::Raw, at(1, 0), at(5, 0))]);
+    }
+
+    #[test]
+    fn whole_days_come_from_daily_rollups() {
+        let ranges = ClickRange::plan(&query(Interval::Day, at(1, 0), at(5, 0)), Some(at(3, 7)));
+        assert_eq!(sources(&ranges), vec![
+            (ClickSource::Daily, at(1, 0), at(3, 0)),
+            (ClickSource::Hourly, at(3, 0), at(3, 7)),
+            (ClickSource::Raw, at(3, 7), at(5, 0)),
+        ]);
+    }
+
+    #[test]
+    fn hourly_stats_skip_daily_rollups() {
+        let ranges = ClickRange::plan(&query(Interval::Hour, at(1, 0), at(2, 0)), Some(at(1, 12)));
+        assert_eq!(sources(&ranges), vec![
+            (ClickSource::Hourly, at(1, 0), at(1, 12)),
+            (ClickSource::Raw, at(1, 12), at(2, 0)),
+        ]);
+    }
+
+    #[test]
+    fn rolled_up_past_the_range_reads_no_raw_clicks() {
+        let ranges = ClickRange::plan(&query(Interval::Day, at(1, 0), at(3, 0)), Some(at(10, 0)));
+        assert_eq!(sources(&ranges), vec![(ClickSource::Daily, at(1, 0), at(3, 0))]);
+    }
+
+    #[test]
+    fn rolled_up_before_the_range_reads_raw_clicks() {
+        let ranges = ClickRange::plan(&query(Interval::Week, at(12, 0), at(26, 0)), Some(at(1, 0)));
+        assert_eq!(sources(&ranges), vec![(ClickSource::Raw, at(12, 0), at(26, 0))]);
+    }
+}
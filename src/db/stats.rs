@@ -0,0 +1,366 @@
+use crate::{config::CONFIG, db::{Click, ClickRange, HyperLogLog, Storage}};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Timelike, Utc};
+use serde::{Serialize, Deserialize};
+use sqlx::FromRow;
+use std::collections::HashMap;
+
+const DEFAULT_LIMIT: i64 = 10;
+const MAX_LIMIT: i64 = 100;
+const MAX_BUCKETS: i64 = 1000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Interval {
+    Hour,
+    #[default]
+    Day,
+    Week,
+}
+
+impl Interval {
+    fn duration(&self) -> Duration {
+        match self {
+            Self::Hour => Duration::hours(1),
+            Self::Day => Duration::days(1),
+            Self::Week => Duration::weeks(1),
+        }
+    }
+
+    // Range covered when the query gives no `from`.
+    fn default_range(&self) -> Duration {
+        match self {
+            Self::Hour => Duration::hours(48),
+            Self::Day => Duration::days(30),
+            Self::Week => Duration::weeks(12),
+        }
+    }
+
+    // Start of the bucket `at` falls in, weeks start on Monday.
+    pub fn truncate(&self, at: DateTime<Utc>) -> DateTime<Utc> {
+        match self {
+            Self::Hour => at.date_naive().and_hms_opt(at.hour(), 0, 0).unwrap().and_utc(),
+            Self::Day => at.date_naive().and_hms_opt(0, 0, 0).unwrap().and_utc(),
+            Self::Week => {
+                let monday = at.date_naive() - Duration::days(at.weekday().num_days_from_monday().into());
+                monday.and_hms_opt(0, 0, 0).unwrap().and_utc()
+            }
+        }
+    }
+}
+
+// Click columns stats are broken down by.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClickField {
+    Referrer,
+    UserAgent,
+    UtmSource,
+    Country,
+}
+
+impl ClickField {
+    pub const ALL: [Self; 4] = [Self::Referrer, Self::UserAgent, Self::UtmSource, Self::Country];
+
+    pub fn column(&self) -> &'static str {
+        match self {
+            Self::Referrer => "referrer",
+            Self::UserAgent => "user_agent",
+            Self::UtmSource => "utm_source",
+            Self::Country => "country",
+        }
+    }
+}
+
+#[derive(Debug, FromRow, Clone, Serialize, Deserialize)]
+pub struct ClickBucket {
+    pub at: DateTime<Utc>,
+    pub clicks: i64,
+    // Estimated unique visitors, only known for days and weeks.
+    #[sqlx(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub visitors: Option<i64>,
+}
+
+// Clicks sharing one value, `None` for clicks without it, such as direct
+// visits in the referrers.
+#[derive(Debug, FromRow, Clone, Serialize, Deserialize)]
+pub struct ClickCount {
+    pub value: Option<String>,
+    pub clicks: i64,
+}
+
+// Sketch of the distinct visitors of `key` on `day`, bots left out.
+#[derive(Debug, Clone)]
+pub struct DailyVisitors {
+    pub tenant: String,
+    pub domain: String,
+    pub key: String,
+    pub day: NaiveDate,
+    pub sketch: HyperLogLog,
+}
+
+impl DailyVisitors {
+    pub fn from_clicks(clicks: &[Click]) -> Vec<Self> {
+        let mut sketches: HashMap<(String, String, String, NaiveDate), HyperLogLog> = HashMap::new();
+        for click in clicks.iter().filter(|click| !click.is_bot) {
+            if let Some(visitor) = click.visitor {
+                let day = click.clicked_at.date_naive();
+                sketches.entry((click.tenant.clone(), click.domain.clone(), click.key.clone(), day)).or_default().insert(visitor);
+            }
+        }
+        sketches.into_iter()
+            .map(|((tenant, domain, key, day), sketch)| Self { tenant, domain, key, day, sketch })
+            .collect()
+    }
+}
+
+#[derive(Debug, FromRow, Clone, Serialize, Deserialize)]
+pub struct TopLink {
+    pub domain: String,
+    pub key: String,
+    pub clicks: i64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StatsQuery {
+    pub interval: Interval,
+    // Inclusive, moved back to the start of its bucket.
+    pub from: Option<DateTime<Utc>>,
+    // Exclusive, now by default.
+    pub to: Option<DateTime<Utc>>,
+    pub limit: Option<i64>,
+    // Clicks classified as bots are left out unless asked for.
+    pub include_bots: bool,
+}
+
+impl StatsQuery {
+    pub fn parse(query: Option<&str>) -> Result<Self> {
+        let mut query: Self = serde_urlencoded::from_str(query.unwrap_or(""))?;
+        if let Some(limit) = query.limit {
+            if !(1..=MAX_LIMIT).contains(&limit) {
+                return Err(anyhow!("limit must be between 1 and {}", MAX_LIMIT));
+            }
+        }
+        let to = query.to.unwrap_or_else(Utc::now);
+        let from = query.interval.truncate(query.from.unwrap_or(to - query.interval.default_range()));
+        if from >= to {
+            return Err(anyhow!("from must be before to"));
+        }
+        if (to - from).num_seconds() / query.interval.duration().num_seconds() >= MAX_BUCKETS {
+            return Err(anyhow!("Range spans more than {} buckets", MAX_BUCKETS));
+        }
+        // Hourly aggregates are purged after their retention, so older hours
+        // would come out empty rather than count their clicks.
+        if query.interval == Interval::Hour && CONFIG.rollups.hourly_retention > 0 {
+            let oldest = Utc::now() - Duration::seconds(CONFIG.rollups.hourly_retention as i64);
+            if from < oldest {
+                return Err(anyhow!("Hourly stats only go back to {}, use days or weeks before that", oldest.to_rfc3339()));
+            }
+        }
+        query.from = Some(from);
+        query.to = Some(to);
+        Ok(query)
+    }
+
+    pub fn range(&self) -> (DateTime<Utc>, DateTime<Utc>) {
+        let to = self.to.unwrap_or_else(Utc::now);
+        let from = self.from.unwrap_or(to - self.interval.default_range());
+        (from, to)
+    }
+
+    pub fn limit(&self) -> i64 {
+        self.limit.unwrap_or(DEFAULT_LIMIT)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UrlMapStats {
+    pub domain: String,
+    pub key: String,
+    pub interval: Interval,
+    pub include_bots: bool,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub total: i64,
+    // Estimated unique visitors over the whole range, bots left out.
+    pub visitors: i64,
+    // Every bucket of the range, including the ones without clicks.
+    pub series: Vec<ClickBucket>,
+    // Host names only, with any `www.` kept.
+    pub referrers: Vec<ClickCount>,
+    pub browsers: Vec<ClickCount>,
+    pub user_agents: Vec<ClickCount>,
+    pub utm_sources: Vec<ClickCount>,
+    // ISO 3166-1 alpha-2 codes, only known when a GeoIP database is
+    // configured.
+    pub countries: Vec<ClickCount>,
+}
+
+pub async fn url_map_stats<S: Storage + ?Sized>(
+    storage: &S,
+    tenant: String,
+    domain: String,
+    key: String,
+    query: StatsQuery,
+) -> Result<UrlMapStats, sqlx::Error> {
+    if storage.get_url_map(domain.clone(), key.clone()).await?.tenant != tenant {
+        return Err(sqlx::Error::RowNotFound);
+    }
+    let (from, to) = query.range();
+    let limit = query.limit() as usize;
+    let ranges = ClickRange::plan(&query, storage.get_rolled_until().await?);
+
+    let mut counts: HashMap<DateTime<Utc>, i64> = HashMap::new();
+    for range in &ranges {
+        for bucket in storage.get_click_series(tenant.clone(), domain.clone(), key.clone(), query.interval, range).await? {
+            *counts.entry(bucket.at).or_default() += bucket.clicks;
+        }
+    }
+    let mut series = vec![];
+    let mut at = from;
+    while at < to {
+        series.push(ClickBucket { at, clicks: counts.get(&at).copied().unwrap_or(0), visitors: None });
+        at += query.interval.duration();
+    }
+
+    // Visitors are counted per day, so the sketches of every day the range
+    // touches are read.
+    let last_day = (to - Duration::nanoseconds(1)).date_naive();
+    let days = storage.get_visitors(tenant.clone(), domain.clone(), key.clone(), from.date_naive(), last_day.succ_opt().unwrap()).await?;
+    let mut visitors = HyperLogLog::default();
+    days.iter().for_each(|day| visitors.merge(&day.sketch));
+    if query.interval != Interval::Hour {
+        for bucket in series.iter_mut() {
+            let mut sketch = HyperLogLog::default();
+            days.iter()
+                .filter(|day| query.interval.truncate(day.day.and_hms_opt(0, 0, 0).unwrap().and_utc()) == bucket.at)
+                .for_each(|day| sketch.merge(&day.sketch));
+            bucket.visitors = Some(sketch.estimate());
+        }
+    }
+
+    let referrers = click_counts(storage, &tenant, &domain, &key, ClickField::Referrer, &ranges).await?;
+    let user_agents = click_counts(storage, &tenant, &domain, &key, ClickField::UserAgent, &ranges).await?;
+    let utm_sources = click_counts(storage, &tenant, &domain, &key, ClickField::UtmSource, &ranges).await?;
+    let countries = click_counts(storage, &tenant, &domain, &key, ClickField::Country, &ranges).await?;
+    Ok(UrlMapStats {
+        domain,
+        key,
+        interval: query.interval,
+        include_bots: query.include_bots,
+        from,
+        to,
+        total: series.iter().map(|bucket| bucket.clicks).sum(),
+        visitors: visitors.estimate(),
+        series,
+        referrers: top(regroup(&referrers, referrer_host), limit),
+        browsers: top(regroup(&user_agents, |user_agent| Some(browser(user_agent).into())), limit),
+        user_agents: top(user_agents, limit),
+        utm_sources: top(utm_sources, limit),
+        countries: top(countries, limit),
+    })
+}
+
+pub async fn top_links<S: Storage + ?Sized>(storage: &S, tenant: String, query: StatsQuery) -> Result<Vec<TopLink>, sqlx::Error> {
+    let mut clicks: HashMap<(String, String), i64> = HashMap::new();
+    for range in ClickRange::plan(&query, storage.get_rolled_until().await?) {
+        for link in storage.get_link_clicks(tenant.clone(), &range).await? {
+            *clicks.entry((link.domain, link.key)).or_default() += link.clicks;
+        }
+    }
+    let mut links = clicks.into_iter()
+        .map(|((domain, key), clicks)| TopLink { domain, key, clicks })
+        .collect::<Vec<_>>();
+    links.sort_by(|a, b| b.clicks.cmp(&a.clicks).then_with(|| (&a.domain, &a.key).cmp(&(&b.domain, &b.key))));
+    links.truncate(query.limit() as usize);
+    Ok(links)
+}
+
+async fn click_counts<S: Storage + ?Sized>(
+    storage: &S,
+    tenant: &str,
+    domain: &str,
+    key: &str,
+    field: ClickField,
+    ranges: &[ClickRange],
+) -> Result<Vec<ClickCount>, sqlx::Error> {
+    let mut counts = vec![];
+    for range in ranges {
+        counts.extend(storage.get_click_counts(tenant.into(), domain.into(), key.into(), field, range).await?);
+    }
+    Ok(regroup(&counts, |value| Some(value.into())))
+}
+
+// Merges counts whose values map to the same group.
+fn regroup<F>(counts: &[ClickCount], group: F) -> Vec<ClickCount>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut groups: HashMap<Option<String>, i64> = HashMap::new();
+    for count in counts {
+        *groups.entry(count.value.as_deref().and_then(&group)).or_default() += count.clicks;
+    }
+    groups.into_iter().map(|(value, clicks)| ClickCount { value, clicks }).collect()
+}
+
+fn top(mut counts: Vec<ClickCount>, limit: usize) -> Vec<ClickCount> {
+    counts.sort_by(|a, b| b.clicks.cmp(&a.clicks).then_with(|| a.value.cmp(&b.value)));
+    counts.truncate(limit);
+    counts
+}
+
+fn referrer_host(referrer: &str) -> Option<String> {
+    let rest = referrer.split_once("://").map_or(referrer, |(_, rest)| rest);
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or("");
+    let host = authority.rsplit('@').next().unwrap_or("");
+    let host = match host.rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+        _ => host,
+    };
+    Some(host.to_lowercase()).filter(|host| !host.is_empty())
+}
+
+// Browser family of a user agent. Most browsers also claim to be the ones
+// they are derived from, so the more specific tokens are checked first.
+fn browser(user_agent: &str) -> &'static str {
+    const BROWSERS: &[(&str, &str)] = &[
+        ("Edg/", "Edge"),
+        ("OPR/", "Opera"),
+        ("SamsungBrowser/", "Samsung Internet"),
+        ("Firefox/", "Firefox"),
+        ("FxiOS/", "Firefox"),
+        ("CriOS/", "Chrome"),
+        ("Chrome/", "Chrome"),
+        ("Safari/", "Safari"),
+        ("curl/", "curl"),
+        ("Wget/", "Wget"),
+    ];
+    BROWSERS.iter()
+        .find(|(token, _)| user_agent.contains(token))
+        .map_or("Other", |(_, browser)| browser)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::SecondsFormat;
+
+    fn parse(interval: &str, from: DateTime<Utc>) -> Result<StatsQuery> {
+        let to = from + Duration::hours(12);
+        let query = format!(
+            "interval={}&from={}&to={}",
+            interval, from.to_rfc3339_opts(SecondsFormat::Secs, true), to.to_rfc3339_opts(SecondsFormat::Secs, true),
+        );
+        StatsQuery::parse(Some(&query))
+    }
+
+    #[test]
+    fn hourly_stats_past_their_retention_are_rejected() {
+        let retention = Duration::seconds(CONFIG.rollups.hourly_retention as i64);
+        let purged = Utc::now() - retention - Duration::days(1);
+        assert!(parse("hour", purged).is_err());
+        assert!(parse("day", purged).is_ok());
+        assert!(parse("hour", Utc::now() - Duration::days(1)).is_ok());
+    }
+}
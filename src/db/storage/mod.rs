@@ -0,0 +1,234 @@
+use crate::db::{ApiToken, BatchReport, BatchRequest, Cache, Click, ClickBucket, ClickCount, ClickField, ClickExport, ClickRange, DailyVisitors, Domain, ExportedClick, ExportedRollup, ImportOptions, ImportReport, Interval, KeyGenerator, NewUrlMap, StatsQuery, Tenant, TopLink, UrlMap, UrlMapChange, UrlMapPage, UrlMapQuery, UrlMapStats, batch, history, import, keys, stats};
+use chrono::{DateTime, NaiveDate, Utc};
+use async_trait::async_trait;
+use tokio::sync::mpsc::Sender;
+
+mod postgres;
+mod sqlite;
+
+pub use postgres::PostgresStorage;
+pub use sqlite::SqliteStorage;
+
+// Postgres reports unique violations with SQLSTATE 23505, SQLite with the
+// extended result codes SQLITE_CONSTRAINT_PRIMARYKEY (1555) and
+// SQLITE_CONSTRAINT_UNIQUE (2067).
+pub fn is_unique_violation(e: &sqlx::Error) -> bool {
+    match e {
+        sqlx::Error::Database(e) => matches!(e.code().as_deref(), Some("23505" | "1555" | "2067")),
+        _ => false,
+    }
+}
+
+// Postgres reports foreign key violations with SQLSTATE 23503, SQLite with
+// SQLITE_CONSTRAINT_FOREIGNKEY (787).
+pub fn is_foreign_key_violation(e: &sqlx::Error) -> bool {
+    match e {
+        sqlx::Error::Database(e) => matches!(e.code().as_deref(), Some("23503" | "787")),
+        _ => false,
+    }
+}
+
+// Writes made through a transaction are only visible to others once it is
+// committed, dropping it without committing rolls them back.
+#[async_trait]
+pub trait Transaction: Send {
+    // Unlike `Storage::get_url_map` this also finds url maps in the trash.
+    // Keys are unique across tenants, so this finds the url map whichever
+    // tenant owns it.
+    async fn get_url_map(&mut self, domain: &str, key: &str) -> Result<Option<UrlMap>, sqlx::Error>;
+    // Only the tenant, domain, key and url of `url_map` are written,
+    // timestamps are taken at the time of the write and `actor` is recorded
+    // as the author. Every write appends a change to the url map's history.
+    // Creating fails with `RowNotFound` when the domain belongs to another
+    // tenant, and updating when the url map does.
+    async fn create_url_map(&mut self, url_map: &UrlMap, actor: Option<&str>) -> Result<UrlMap, sqlx::Error>;
+    async fn update_url_map(&mut self, url_map: &UrlMap, actor: Option<&str>) -> Result<UrlMap, sqlx::Error>;
+    // Moves the url map to the trash, where it keeps its key until it is
+    // restored or purged.
+    async fn delete_url_map(&mut self, tenant: &str, domain: &str, key: &str, actor: Option<&str>) -> Result<UrlMap, sqlx::Error>;
+    async fn restore_url_map(&mut self, tenant: &str, domain: &str, key: &str, actor: Option<&str>) -> Result<UrlMap, sqlx::Error>;
+    async fn purge_url_map(&mut self, tenant: &str, domain: &str, key: &str, actor: Option<&str>) -> Result<UrlMap, sqlx::Error>;
+    // Purges every url map moved to the trash before `deleted_before`.
+    async fn purge_trash(&mut self, deleted_before: DateTime<Utc>) -> Result<Vec<UrlMap>, sqlx::Error>;
+    // Moves url maps that expired before `expired_before` to the trash.
+    async fn archive_expired(&mut self, expired_before: DateTime<Utc>) -> Result<Vec<UrlMap>, sqlx::Error>;
+    async fn get_url_map_change(&mut self, tenant: &str, domain: &str, key: &str, id: i64) -> Result<Option<UrlMapChange>, sqlx::Error>;
+    async fn get_domain(&mut self, name: &str) -> Result<Option<Domain>, sqlx::Error>;
+    // A single, non nested savepoint used to undo one step of a batch.
+    async fn savepoint(&mut self) -> Result<(), sqlx::Error>;
+    async fn release_savepoint(&mut self) -> Result<(), sqlx::Error>;
+    async fn rollback_to_savepoint(&mut self) -> Result<(), sqlx::Error>;
+    async fn commit(self: Box<Self>) -> Result<(), sqlx::Error>;
+    async fn rollback(self: Box<Self>) -> Result<(), sqlx::Error>;
+}
+
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn begin(&self) -> Result<Box<dyn Transaction>, sqlx::Error>;
+    async fn get_url_maps(&self, tenant: String, query: UrlMapQuery) -> Result<UrlMapPage, sqlx::Error>;
+    // Whichever tenant owns the url map, as needed by redirects. Callers
+    // acting for a tenant check its `tenant`.
+    async fn get_url_map(&self, domain: String, key: String) -> Result<UrlMap, sqlx::Error>;
+    // Url map a redirect for `path` goes to: the one keyed by the whole path,
+    // or else the prefix url map with the longest key the path starts with.
+    async fn resolve_url_map(&self, domain: String, path: String) -> Result<UrlMap, sqlx::Error>;
+    // Counts one redirect of a click limited url map, `None` once it is used
+    // up. The check and the increment are a single statement so concurrent
+    // redirects can never exceed the limit.
+    async fn use_click(&self, domain: String, key: String) -> Result<Option<UrlMap>, sqlx::Error>;
+    // Newest change first. Only the changes made while `tenant` owned the
+    // key are returned.
+    async fn get_url_map_history(&self, tenant: String, domain: String, key: String) -> Result<Vec<UrlMapChange>, sqlx::Error>;
+    // The change in effect at `at`, `RowNotFound` when the key did not exist
+    // at that time.
+    async fn get_url_map_version(&self, tenant: String, domain: String, key: String, at: DateTime<Utc>) -> Result<UrlMapChange, sqlx::Error>;
+    async fn next_key_sequence(&self) -> Result<i64, sqlx::Error>;
+    // Registered domains of `tenant` ordered by name, the default domain left
+    // out.
+    async fn get_domains(&self, tenant: String) -> Result<Vec<Domain>, sqlx::Error>;
+    // Registered domains of every tenant, for resolving the host of a
+    // redirect.
+    async fn resolve_domains(&self) -> Result<Vec<Domain>, sqlx::Error>;
+    async fn create_domain(&self, tenant: String, name: String, actor: Option<String>) -> Result<Domain, sqlx::Error>;
+    // Fails with a foreign key violation while the domain still has url maps,
+    // including trashed ones.
+    async fn delete_domain(&self, tenant: String, name: String) -> Result<Domain, sqlx::Error>;
+    // Ordered by name.
+    async fn get_tenants(&self) -> Result<Vec<Tenant>, sqlx::Error>;
+    async fn create_tenant(&self, name: String, actor: Option<String>) -> Result<Tenant, sqlx::Error>;
+    // Fails with a foreign key violation while the tenant still has url maps,
+    // domains or api tokens. The default tenant is never deleted.
+    async fn delete_tenant(&self, name: String) -> Result<Tenant, sqlx::Error>;
+    // Ordered by id.
+    async fn get_api_tokens(&self, tenant: String) -> Result<Vec<ApiToken>, sqlx::Error>;
+    async fn create_api_token(&self, tenant: String, name: String, token_hash: String, actor: Option<String>) -> Result<ApiToken, sqlx::Error>;
+    async fn delete_api_token(&self, tenant: String, id: i64) -> Result<ApiToken, sqlx::Error>;
+    // The token hashing to `token_hash`, if any.
+    async fn find_api_token(&self, token_hash: String) -> Result<Option<ApiToken>, sqlx::Error>;
+    // Streams every url map of `tenant` ordered by domain and key into
+    // `sender`, stopping early when the receiving end goes away.
+    async fn export_url_maps(&self, tenant: String, sender: Sender<Result<UrlMap, sqlx::Error>>);
+    // Stream the raw clicks or the rollups of `export` into `sender` in time
+    // order, stopping early when the receiving end goes away.
+    async fn export_clicks(&self, tenant: String, export: &ClickExport, sender: Sender<Result<ExportedClick, sqlx::Error>>);
+    async fn export_rollups(&self, tenant: String, export: &ClickExport, sender: Sender<Result<ExportedRollup, sqlx::Error>>);
+    // Writes a batch of clicks with a single statement.
+    async fn insert_clicks(&self, clicks: &[Click]) -> Result<(), sqlx::Error>;
+    // Deletes the clicks recorded before `clicked_before` that are rolled up,
+    // or the ones that are not, returning how many.
+    async fn purge_clicks(&self, clicked_before: DateTime<Utc>, rolled_up: bool) -> Result<u64, sqlx::Error>;
+    // Merges each sketch into the one already stored for its tenant, domain,
+    // key and day.
+    async fn merge_visitors(&self, visitors: Vec<DailyVisitors>) -> Result<(), sqlx::Error>;
+    // Sketches of the days from `from` (inclusive) to `to` (exclusive).
+    async fn get_visitors(&self, tenant: String, domain: String, key: String, from: NaiveDate, to: NaiveDate) -> Result<Vec<DailyVisitors>, sqlx::Error>;
+    // End of the clicks rolled up so far, `None` before the first rollup.
+    // Clicks written late can still be waiting before it.
+    async fn get_rolled_until(&self) -> Result<Option<DateTime<Utc>>, sqlx::Error>;
+    // Rolls up the clicks before `until` not rolled up yet, which must be the
+    // start of an hour, at most a day of them from the earliest one. Returns
+    // how far it got, `until` once nothing is left before it.
+    async fn roll_up_clicks(&self, until: DateTime<Utc>) -> Result<DateTime<Utc>, sqlx::Error>;
+    // Deletes the hourly aggregates of the hours before `before`.
+    async fn purge_hourly_clicks(&self, before: DateTime<Utc>) -> Result<u64, sqlx::Error>;
+    // Clicks on `key` counted from a single source, bots only counted when
+    // the range includes them. Only buckets with clicks are returned.
+    async fn get_click_series(&self, tenant: String, domain: String, key: String, interval: Interval, range: &ClickRange) -> Result<Vec<ClickBucket>, sqlx::Error>;
+    async fn get_click_counts(&self, tenant: String, domain: String, key: String, field: ClickField, range: &ClickRange) -> Result<Vec<ClickCount>, sqlx::Error>;
+    // Clicks on every key of `tenant`, trashed ones included.
+    async fn get_link_clicks(&self, tenant: String, range: &ClickRange) -> Result<Vec<TopLink>, sqlx::Error>;
+    // Waits for the connections in use to be given back, then closes them
+    // all.
+    async fn close(&self);
+
+    async fn create_url_map(&self, url_map: UrlMap, actor: Option<String>) -> Result<UrlMap, sqlx::Error> {
+        let mut tx = self.begin().await?;
+        let url_map = tx.create_url_map(&url_map, actor.as_deref()).await?;
+        tx.commit().await?;
+        Ok(url_map)
+    }
+
+    async fn update_url_map(&self, url_map: UrlMap, actor: Option<String>) -> Result<UrlMap, sqlx::Error> {
+        let mut tx = self.begin().await?;
+        let url_map = tx.update_url_map(&url_map, actor.as_deref()).await?;
+        tx.commit().await?;
+        Ok(url_map)
+    }
+
+    async fn delete_url_map(&self, tenant: String, domain: String, key: String, actor: Option<String>) -> Result<UrlMap, sqlx::Error> {
+        let mut tx = self.begin().await?;
+        let url_map = tx.delete_url_map(&tenant, &domain, &key, actor.as_deref()).await?;
+        tx.commit().await?;
+        Ok(url_map)
+    }
+
+    async fn restore_url_map(&self, tenant: String, domain: String, key: String, actor: Option<String>) -> Result<UrlMap, sqlx::Error> {
+        let mut tx = self.begin().await?;
+        let url_map = tx.restore_url_map(&tenant, &domain, &key, actor.as_deref()).await?;
+        tx.commit().await?;
+        Ok(url_map)
+    }
+
+    async fn purge_url_map(&self, tenant: String, domain: String, key: String, actor: Option<String>) -> Result<UrlMap, sqlx::Error> {
+        let mut tx = self.begin().await?;
+        let url_map = tx.purge_url_map(&tenant, &domain, &key, actor.as_deref()).await?;
+        tx.commit().await?;
+        Ok(url_map)
+    }
+
+    async fn purge_trash(&self, deleted_before: DateTime<Utc>) -> Result<Vec<UrlMap>, sqlx::Error> {
+        let mut tx = self.begin().await?;
+        let url_maps = tx.purge_trash(deleted_before).await?;
+        tx.commit().await?;
+        Ok(url_maps)
+    }
+
+    async fn archive_expired(&self, expired_before: DateTime<Utc>) -> Result<Vec<UrlMap>, sqlx::Error> {
+        let mut tx = self.begin().await?;
+        let url_maps = tx.archive_expired(expired_before).await?;
+        tx.commit().await?;
+        Ok(url_maps)
+    }
+
+    async fn revert_url_map(&self, tenant: String, domain: String, key: String, version: i64, actor: Option<String>) -> Result<UrlMap, sqlx::Error> {
+        history::revert_url_map(self.begin().await?, &tenant, &domain, &key, version, actor.as_deref()).await
+    }
+
+    async fn generate_url_map(
+        &self,
+        url_map: NewUrlMap,
+        actor: Option<String>,
+        keys: &KeyGenerator,
+    ) -> Result<UrlMap, sqlx::Error> {
+        keys::create_url_map(self, keys, url_map, actor).await
+    }
+
+    async fn import_url_maps(
+        &self,
+        tenant: String,
+        rows: Vec<Result<UrlMap, String>>,
+        options: ImportOptions,
+        actor: Option<String>,
+    ) -> Result<ImportReport, sqlx::Error> {
+        import::import_url_maps(self.begin().await?, &tenant, rows, options, actor.as_deref()).await
+    }
+
+    async fn apply_batch(&self, tenant: String, request: BatchRequest, actor: Option<String>) -> Result<BatchReport, sqlx::Error> {
+        batch::apply_batch(self.begin().await?, &tenant, request, actor.as_deref()).await
+    }
+
+    async fn get_url_map_stats(&self, tenant: String, domain: String, key: String, query: StatsQuery) -> Result<UrlMapStats, sqlx::Error> {
+        stats::url_map_stats(self, tenant, domain, key, query).await
+    }
+
+    async fn get_top_links(&self, tenant: String, query: StatsQuery) -> Result<Vec<TopLink>, sqlx::Error> {
+        stats::top_links(self, tenant, query).await
+    }
+
+    // Evicts keys from `cache` when they, or the domains, are changed by
+    // other instances sharing the same database. Backends local to one
+    // process have nothing to watch.
+    async fn watch(&self, _cache: Cache) -> Result<(), sqlx::Error> {
+        Ok(())
+    }
+}
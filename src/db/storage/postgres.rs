@@ -0,0 +1,691 @@
+use crate::{config::Database, db::{ApiToken, Bind, Cache, Click, ClickBucket, ClickCount, ClickField, ClickExport, ClickRange, DailyVisitors, Domain, ExportedClick, ExportedRollup, HyperLogLog, Interval, DEFAULT_TENANT, Tenant, TopLink, UrlMap, UrlMapChange, UrlMapPage, UrlMapQuery}};
+use super::{Storage, Transaction};
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use futures::StreamExt;
+use sqlx::{migrate, Pool, Postgres, postgres::{PgListener, PgPoolOptions}};
+use std::time::Duration;
+use tokio::sync::mpsc::Sender;
+
+const CHANGES_CHANNEL: &str = "url_map_changes";
+
+pub struct PostgresStorage {
+    pool: Pool<Postgres>,
+    // Listening for changes takes a connection for good, which is made
+    // outside the pool so as not to take one from the manager.
+    url: String,
+}
+
+impl PostgresStorage {
+    pub async fn new(config: &Database) -> Result<Self> {
+        let pool = PgPoolOptions::new()
+            .max_connections(config.max_connections)
+            .connect(&config.url)
+            .await?;
+
+        let migrator = migrate!("./migrations/postgres");
+        migrator
+            .run(&pool)
+            .await?;
+
+        Ok(Self { pool, url: config.url.clone() })
+    }
+}
+
+// Url map changes are published as `domain/key`, which is unambiguous since
+// domains never contain a `/`. A change to the domains themselves is
+// published as the bare domain name and clears the whole cache.
+fn change_payload(domain: &str, key: &str) -> String {
+    format!("{}/{}", domain, key)
+}
+
+async fn notify_domains(tx: &mut sqlx::Transaction<'static, Postgres>, name: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("SELECT pg_notify($1, $2)")
+        .bind(CHANGES_CHANNEL)
+        .bind(name)
+        .execute(&mut *tx)
+        .await?;
+    Ok(())
+}
+
+pub struct PostgresTransaction {
+    tx: sqlx::Transaction<'static, Postgres>,
+}
+
+impl PostgresTransaction {
+    // Delivered to every listener once the transaction commits.
+    async fn notify(&mut self, url_map: &UrlMap) -> Result<(), sqlx::Error> {
+        sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(CHANGES_CHANNEL)
+            .bind(change_payload(&url_map.domain, &url_map.key))
+            .execute(&mut self.tx)
+            .await?;
+        Ok(())
+    }
+
+    async fn record(
+        &mut self,
+        action: &str,
+        url_map: &UrlMap,
+        old_url: Option<&str>,
+        new_url: Option<&str>,
+        actor: Option<&str>,
+        changed_at: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("INSERT INTO url_map_history (tenant, domain, key, action, old_url, new_url, actor, changed_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)")
+            .bind(&url_map.tenant)
+            .bind(&url_map.domain)
+            .bind(&url_map.key)
+            .bind(action)
+            .bind(old_url)
+            .bind(new_url)
+            .bind(actor)
+            .bind(changed_at)
+            .execute(&mut self.tx)
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Transaction for PostgresTransaction {
+    async fn get_url_map(&mut self, domain: &str, key: &str) -> Result<Option<UrlMap>, sqlx::Error> {
+        sqlx::query_as::<_, UrlMap>("SELECT * FROM url_maps WHERE domain = $1 AND key = $2")
+            .bind(domain)
+            .bind(key)
+            .fetch_optional(&mut self.tx)
+            .await
+    }
+
+    async fn create_url_map(&mut self, url_map: &UrlMap, actor: Option<&str>) -> Result<UrlMap, sqlx::Error> {
+        let now = Utc::now();
+        let url_map = sqlx::query_as::<_, UrlMap>("INSERT INTO url_maps (domain, key, url, created_at, updated_at, created_by, updated_by, expires_at, not_before, max_clicks, tenant, prefix) SELECT $1, $2, $3, $4, $4, $5, $5, $6, $7, $8, $9, $10 WHERE EXISTS (SELECT 1 FROM domains WHERE name = $1 AND (tenant IS NULL OR tenant = $9)) RETURNING *")
+            .bind(&url_map.domain)
+            .bind(&url_map.key)
+            .bind(&url_map.url)
+            .bind(now)
+            .bind(actor)
+            .bind(url_map.expires_at)
+            .bind(url_map.not_before)
+            .bind(url_map.max_clicks)
+            .bind(&url_map.tenant)
+            .bind(url_map.prefix)
+            .fetch_one(&mut self.tx)
+            .await?;
+        self.record("create", &url_map, None, Some(&url_map.url), actor, now).await?;
+        self.notify(&url_map).await?;
+        Ok(url_map)
+    }
+
+    async fn update_url_map(&mut self, url_map: &UrlMap, actor: Option<&str>) -> Result<UrlMap, sqlx::Error> {
+        let now = Utc::now();
+        let old_url: String = sqlx::query_scalar("SELECT url FROM url_maps WHERE tenant = $1 AND domain = $2 AND key = $3 AND deleted_at IS NULL FOR UPDATE")
+            .bind(&url_map.tenant)
+            .bind(&url_map.domain)
+            .bind(&url_map.key)
+            .fetch_one(&mut self.tx)
+            .await?;
+        let url_map = sqlx::query_as::<_, UrlMap>("UPDATE url_maps SET url=$1, updated_at=$2, updated_by=$3, expires_at=$4, not_before=$5, max_clicks=$6, prefix=$7 WHERE domain=$8 AND key=$9 AND tenant=$10 AND deleted_at IS NULL RETURNING *")
+            .bind(&url_map.url)
+            .bind(now)
+            .bind(actor)
+            .bind(url_map.expires_at)
+            .bind(url_map.not_before)
+            .bind(url_map.max_clicks)
+            .bind(url_map.prefix)
+            .bind(&url_map.domain)
+            .bind(&url_map.key)
+            .bind(&url_map.tenant)
+            .fetch_one(&mut self.tx)
+            .await?;
+        self.record("update", &url_map, Some(&old_url), Some(&url_map.url), actor, now).await?;
+        self.notify(&url_map).await?;
+        Ok(url_map)
+    }
+
+    async fn delete_url_map(&mut self, tenant: &str, domain: &str, key: &str, actor: Option<&str>) -> Result<UrlMap, sqlx::Error> {
+        let now = Utc::now();
+        let url_map = sqlx::query_as::<_, UrlMap>("UPDATE url_maps SET deleted_at=$1, deleted_by=$2 WHERE domain=$3 AND key=$4 AND tenant=$5 AND deleted_at IS NULL RETURNING *")
+            .bind(now)
+            .bind(actor)
+            .bind(domain)
+            .bind(key)
+            .bind(tenant)
+            .fetch_one(&mut self.tx)
+            .await?;
+        self.record("delete", &url_map, Some(&url_map.url), None, actor, now).await?;
+        self.notify(&url_map).await?;
+        Ok(url_map)
+    }
+
+    async fn restore_url_map(&mut self, tenant: &str, domain: &str, key: &str, actor: Option<&str>) -> Result<UrlMap, sqlx::Error> {
+        let url_map = sqlx::query_as::<_, UrlMap>("UPDATE url_maps SET deleted_at=NULL, deleted_by=NULL WHERE domain=$1 AND key=$2 AND tenant=$3 AND deleted_at IS NOT NULL RETURNING *")
+            .bind(domain)
+            .bind(key)
+            .bind(tenant)
+            .fetch_one(&mut self.tx)
+            .await?;
+        self.record("restore", &url_map, None, Some(&url_map.url), actor, Utc::now()).await?;
+        self.notify(&url_map).await?;
+        Ok(url_map)
+    }
+
+    async fn purge_url_map(&mut self, tenant: &str, domain: &str, key: &str, actor: Option<&str>) -> Result<UrlMap, sqlx::Error> {
+        let url_map = sqlx::query_as::<_, UrlMap>("DELETE FROM url_maps WHERE domain = $1 AND key = $2 AND tenant = $3 AND deleted_at IS NOT NULL RETURNING *")
+            .bind(domain)
+            .bind(key)
+            .bind(tenant)
+            .fetch_one(&mut self.tx)
+            .await?;
+        self.record("purge", &url_map, Some(&url_map.url), None, actor, Utc::now()).await?;
+        Ok(url_map)
+    }
+
+    async fn purge_trash(&mut self, deleted_before: DateTime<Utc>) -> Result<Vec<UrlMap>, sqlx::Error> {
+        let now = Utc::now();
+        let url_maps = sqlx::query_as::<_, UrlMap>("DELETE FROM url_maps WHERE deleted_at < $1 RETURNING *")
+            .bind(deleted_before)
+            .fetch_all(&mut self.tx)
+            .await?;
+        for url_map in &url_maps {
+            self.record("purge", url_map, Some(&url_map.url), None, None, now).await?;
+        }
+        Ok(url_maps)
+    }
+
+    async fn archive_expired(&mut self, expired_before: DateTime<Utc>) -> Result<Vec<UrlMap>, sqlx::Error> {
+        let now = Utc::now();
+        let url_maps = sqlx::query_as::<_, UrlMap>("UPDATE url_maps SET deleted_at=$1 WHERE expires_at < $2 AND deleted_at IS NULL RETURNING *")
+            .bind(now)
+            .bind(expired_before)
+            .fetch_all(&mut self.tx)
+            .await?;
+        for url_map in &url_maps {
+            self.record("archive", url_map, Some(&url_map.url), None, None, now).await?;
+            self.notify(url_map).await?;
+        }
+        Ok(url_maps)
+    }
+
+    async fn get_url_map_change(&mut self, tenant: &str, domain: &str, key: &str, id: i64) -> Result<Option<UrlMapChange>, sqlx::Error> {
+        sqlx::query_as::<_, UrlMapChange>("SELECT * FROM url_map_history WHERE tenant = $1 AND domain = $2 AND key = $3 AND id = $4")
+            .bind(tenant)
+            .bind(domain)
+            .bind(key)
+            .bind(id)
+            .fetch_optional(&mut self.tx)
+            .await
+    }
+
+    async fn get_domain(&mut self, name: &str) -> Result<Option<Domain>, sqlx::Error> {
+        sqlx::query_as::<_, Domain>("SELECT * FROM domains WHERE name = $1")
+            .bind(name)
+            .fetch_optional(&mut self.tx)
+            .await
+    }
+
+    async fn savepoint(&mut self) -> Result<(), sqlx::Error> {
+        sqlx::query("SAVEPOINT batch_step").execute(&mut self.tx).await?;
+        Ok(())
+    }
+
+    async fn release_savepoint(&mut self) -> Result<(), sqlx::Error> {
+        sqlx::query("RELEASE SAVEPOINT batch_step").execute(&mut self.tx).await?;
+        Ok(())
+    }
+
+    async fn rollback_to_savepoint(&mut self) -> Result<(), sqlx::Error> {
+        sqlx::query("ROLLBACK TO SAVEPOINT batch_step").execute(&mut self.tx).await?;
+        Ok(())
+    }
+
+    async fn commit(self: Box<Self>) -> Result<(), sqlx::Error> {
+        self.tx.commit().await
+    }
+
+    async fn rollback(self: Box<Self>) -> Result<(), sqlx::Error> {
+        self.tx.rollback().await
+    }
+}
+
+#[async_trait]
+impl Storage for PostgresStorage {
+    async fn begin(&self) -> Result<Box<dyn Transaction>, sqlx::Error> {
+        let tx = self.pool.begin().await?;
+        Ok(Box::new(PostgresTransaction { tx }))
+    }
+
+    async fn get_url_maps(&self, tenant: String, query: UrlMapQuery) -> Result<UrlMapPage, sqlx::Error> {
+        let (sql, binds) = query.to_sql(&tenant);
+        let mut url_maps = sqlx::query_as::<_, UrlMap>(&sql);
+        for bind in binds {
+            url_maps = match bind {
+                Bind::Text(value) => url_maps.bind(value),
+                Bind::Timestamp(value) => url_maps.bind(value),
+            };
+        }
+        let url_maps = url_maps.fetch_all(&self.pool).await?;
+        Ok(query.page(url_maps))
+    }
+
+    async fn get_url_map(&self, domain: String, key: String) -> Result<UrlMap, sqlx::Error> {
+        sqlx::query_as::<_, UrlMap>("SELECT * FROM url_maps WHERE domain = $1 AND key = $2 AND deleted_at IS NULL")
+            .bind(domain)
+            .bind(key)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn resolve_url_map(&self, domain: String, path: String) -> Result<UrlMap, sqlx::Error> {
+        sqlx::query_as::<_, UrlMap>("SELECT * FROM url_maps WHERE domain = $1 AND key = ANY($2) AND (key = $3 OR prefix) AND deleted_at IS NULL ORDER BY length(key) DESC LIMIT 1")
+            .bind(domain)
+            .bind(UrlMap::candidate_keys(&path))
+            .bind(&path)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn use_click(&self, domain: String, key: String) -> Result<Option<UrlMap>, sqlx::Error> {
+        sqlx::query_as::<_, UrlMap>("UPDATE url_maps SET used_clicks = used_clicks + 1 WHERE domain = $1 AND key = $2 AND deleted_at IS NULL AND (max_clicks IS NULL OR used_clicks < max_clicks) RETURNING *")
+            .bind(domain)
+            .bind(key)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    async fn get_url_map_history(&self, tenant: String, domain: String, key: String) -> Result<Vec<UrlMapChange>, sqlx::Error> {
+        sqlx::query_as::<_, UrlMapChange>("SELECT * FROM url_map_history WHERE domain = $1 AND key = $2 AND tenant = $3 ORDER BY id DESC")
+            .bind(domain)
+            .bind(key)
+            .bind(tenant)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn get_url_map_version(&self, tenant: String, domain: String, key: String, at: DateTime<Utc>) -> Result<UrlMapChange, sqlx::Error> {
+        sqlx::query_as::<_, UrlMapChange>("SELECT * FROM url_map_history WHERE domain = $1 AND key = $2 AND tenant = $3 AND changed_at <= $4 ORDER BY changed_at DESC, id DESC LIMIT 1")
+            .bind(domain)
+            .bind(key)
+            .bind(tenant)
+            .bind(at)
+            .fetch_optional(&self.pool)
+            .await?
+            .filter(|change| change.new_url.is_some())
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    async fn next_key_sequence(&self) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar("SELECT nextval('url_map_key_seq')")
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn get_domains(&self, tenant: String) -> Result<Vec<Domain>, sqlx::Error> {
+        sqlx::query_as::<_, Domain>("SELECT * FROM domains WHERE name <> '' AND tenant = $1 ORDER BY name")
+            .bind(tenant)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn resolve_domains(&self) -> Result<Vec<Domain>, sqlx::Error> {
+        sqlx::query_as::<_, Domain>("SELECT * FROM domains WHERE name <> ''")
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn create_domain(&self, tenant: String, name: String, actor: Option<String>) -> Result<Domain, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let domain = sqlx::query_as::<_, Domain>("INSERT INTO domains (name, created_at, created_by, tenant) VALUES ($1, $2, $3, $4) RETURNING *")
+            .bind(name)
+            .bind(Utc::now())
+            .bind(actor)
+            .bind(tenant)
+            .fetch_one(&mut tx)
+            .await?;
+        notify_domains(&mut tx, &domain.name).await?;
+        tx.commit().await?;
+        Ok(domain)
+    }
+
+    async fn delete_domain(&self, tenant: String, name: String) -> Result<Domain, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let domain = sqlx::query_as::<_, Domain>("DELETE FROM domains WHERE name = $1 AND tenant = $2 RETURNING *")
+            .bind(name)
+            .bind(tenant)
+            .fetch_one(&mut tx)
+            .await?;
+        notify_domains(&mut tx, &domain.name).await?;
+        tx.commit().await?;
+        Ok(domain)
+    }
+
+    async fn get_tenants(&self) -> Result<Vec<Tenant>, sqlx::Error> {
+        sqlx::query_as::<_, Tenant>("SELECT * FROM tenants ORDER BY name")
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn create_tenant(&self, name: String, actor: Option<String>) -> Result<Tenant, sqlx::Error> {
+        sqlx::query_as::<_, Tenant>("INSERT INTO tenants (name, created_at, created_by) VALUES ($1, $2, $3) RETURNING *")
+            .bind(name)
+            .bind(Utc::now())
+            .bind(actor)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn delete_tenant(&self, name: String) -> Result<Tenant, sqlx::Error> {
+        sqlx::query_as::<_, Tenant>("DELETE FROM tenants WHERE name = $1 AND name <> $2 RETURNING *")
+            .bind(name)
+            .bind(DEFAULT_TENANT)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn get_api_tokens(&self, tenant: String) -> Result<Vec<ApiToken>, sqlx::Error> {
+        sqlx::query_as::<_, ApiToken>("SELECT id, tenant, name, created_at, created_by FROM api_tokens WHERE tenant = $1 ORDER BY id")
+            .bind(tenant)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn create_api_token(&self, tenant: String, name: String, token_hash: String, actor: Option<String>) -> Result<ApiToken, sqlx::Error> {
+        sqlx::query_as::<_, ApiToken>("INSERT INTO api_tokens (tenant, name, token_hash, created_at, created_by) VALUES ($1, $2, $3, $4, $5) RETURNING id, tenant, name, created_at, created_by")
+            .bind(tenant)
+            .bind(name)
+            .bind(token_hash)
+            .bind(Utc::now())
+            .bind(actor)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn delete_api_token(&self, tenant: String, id: i64) -> Result<ApiToken, sqlx::Error> {
+        sqlx::query_as::<_, ApiToken>("DELETE FROM api_tokens WHERE tenant = $1 AND id = $2 RETURNING id, tenant, name, created_at, created_by")
+            .bind(tenant)
+            .bind(id)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn find_api_token(&self, token_hash: String) -> Result<Option<ApiToken>, sqlx::Error> {
+        sqlx::query_as::<_, ApiToken>("SELECT id, tenant, name, created_at, created_by FROM api_tokens WHERE token_hash = $1")
+            .bind(token_hash)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    async fn export_url_maps(&self, tenant: String, sender: Sender<Result<UrlMap, sqlx::Error>>) {
+        let mut url_maps = sqlx::query_as::<_, UrlMap>("SELECT * FROM url_maps WHERE tenant = $1 AND deleted_at IS NULL ORDER BY domain, key")
+            .bind(tenant)
+            .fetch(&self.pool);
+        while let Some(url_map) = url_maps.next().await {
+            if sender.send(url_map).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    async fn export_clicks(&self, tenant: String, export: &ClickExport, sender: Sender<Result<ExportedClick, sqlx::Error>>) {
+        let mut clicks = sqlx::query_as::<_, ExportedClick>("SELECT key, clicked_at, referrer, user_agent, utm_source, country, region, is_bot, domain FROM clicks WHERE tenant = $5 AND ($1::VARCHAR IS NULL OR key = $1) AND ($2::TIMESTAMPTZ IS NULL OR clicked_at >= $2) AND ($3::TIMESTAMPTZ IS NULL OR clicked_at < $3) AND ($4::VARCHAR IS NULL OR domain = $4) ORDER BY clicked_at, id")
+            .bind(&export.key)
+            .bind(export.from)
+            .bind(export.to)
+            .bind(&export.domain)
+            .bind(tenant)
+            .fetch(&self.pool);
+        while let Some(click) = clicks.next().await {
+            if sender.send(click).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    async fn export_rollups(&self, tenant: String, export: &ClickExport, sender: Sender<Result<ExportedRollup, sqlx::Error>>) {
+        let sql = format!("SELECT key, bucket, CASE field WHEN '' THEN 'total' ELSE field END AS field, NULLIF(value, '') AS value, is_bot, clicks, domain FROM {} WHERE tenant = $5 AND ($1::VARCHAR IS NULL OR key = $1) AND ($2::TIMESTAMPTZ IS NULL OR bucket >= $2) AND ($3::TIMESTAMPTZ IS NULL OR bucket < $3) AND ($4::VARCHAR IS NULL OR domain = $4) ORDER BY bucket, domain, key, field, value, is_bot", export.granularity.table());
+        let mut rollups = sqlx::query_as::<_, ExportedRollup>(&sql)
+            .bind(&export.key)
+            .bind(export.from)
+            .bind(export.to)
+            .bind(&export.domain)
+            .bind(tenant)
+            .fetch(&self.pool);
+        while let Some(rollup) = rollups.next().await {
+            if sender.send(rollup).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    async fn insert_clicks(&self, clicks: &[Click]) -> Result<(), sqlx::Error> {
+        if clicks.is_empty() {
+            return Ok(());
+        }
+        let values = (0..clicks.len())
+            .map(|i| format!("(${})", (i * 11 + 1..=i * 11 + 11).map(|n| n.to_string()).collect::<Vec<_>>().join(", $")))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let sql = format!("INSERT INTO clicks (tenant, domain, key, clicked_at, referrer, user_agent, ip, utm_source, country, region, is_bot) VALUES {}", values);
+        let mut query = sqlx::query(&sql);
+        for click in clicks {
+            query = query
+                .bind(&click.tenant)
+                .bind(&click.domain)
+                .bind(&click.key)
+                .bind(click.clicked_at)
+                .bind(&click.referrer)
+                .bind(&click.user_agent)
+                .bind(&click.ip)
+                .bind(&click.utm_source)
+                .bind(&click.country)
+                .bind(&click.region)
+                .bind(click.is_bot);
+        }
+        query.execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn purge_clicks(&self, clicked_before: DateTime<Utc>, rolled_up: bool) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM clicks WHERE clicked_at < $1 AND rolled_up = $2")
+            .bind(clicked_before)
+            .bind(rolled_up)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn merge_visitors(&self, mut visitors: Vec<DailyVisitors>) -> Result<(), sqlx::Error> {
+        // Rows are locked in the same order by every writer.
+        visitors.sort_by(|a, b| (&a.tenant, &a.domain, &a.key, a.day).cmp(&(&b.tenant, &b.domain, &b.key, b.day)));
+        let mut tx = self.pool.begin().await?;
+        for DailyVisitors { tenant, domain, key, day, sketch } in visitors {
+            sqlx::query("INSERT INTO click_visitors (tenant, domain, key, day, sketch) VALUES ($1, $2, $3, $4, $5) ON CONFLICT (tenant, domain, key, day) DO NOTHING")
+                .bind(&tenant)
+                .bind(&domain)
+                .bind(&key)
+                .bind(day)
+                .bind(HyperLogLog::default().as_bytes())
+                .execute(&mut tx)
+                .await?;
+            let stored: Vec<u8> = sqlx::query_scalar("SELECT sketch FROM click_visitors WHERE tenant = $1 AND domain = $2 AND key = $3 AND day = $4 FOR UPDATE")
+                .bind(&tenant)
+                .bind(&domain)
+                .bind(&key)
+                .bind(day)
+                .fetch_one(&mut tx)
+                .await?;
+            let mut stored = HyperLogLog::from_bytes(stored);
+            stored.merge(&sketch);
+            sqlx::query("UPDATE click_visitors SET sketch = $1 WHERE tenant = $2 AND domain = $3 AND key = $4 AND day = $5")
+                .bind(stored.as_bytes())
+                .bind(&tenant)
+                .bind(&domain)
+                .bind(&key)
+                .bind(day)
+                .execute(&mut tx)
+                .await?;
+        }
+        tx.commit().await
+    }
+
+    async fn get_visitors(&self, tenant: String, domain: String, key: String, from: NaiveDate, to: NaiveDate) -> Result<Vec<DailyVisitors>, sqlx::Error> {
+        let rows: Vec<(NaiveDate, Vec<u8>)> = sqlx::query_as("SELECT day, sketch FROM click_visitors WHERE tenant = $1 AND domain = $2 AND key = $3 AND day >= $4 AND day < $5 ORDER BY day")
+            .bind(&tenant)
+            .bind(&domain)
+            .bind(&key)
+            .bind(from)
+            .bind(to)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.into_iter()
+            .map(|(day, sketch)| DailyVisitors { tenant: tenant.clone(), domain: domain.clone(), key: key.clone(), day, sketch: HyperLogLog::from_bytes(sketch) })
+            .collect())
+    }
+
+    async fn get_rolled_until(&self) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+        sqlx::query_scalar("SELECT rolled_until FROM click_rollups WHERE id = 1")
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn roll_up_clicks(&self, until: DateTime<Utc>) -> Result<DateTime<Utc>, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        // Locking the state row keeps concurrent rollups from counting the
+        // same clicks twice.
+        let rolled_until: Option<DateTime<Utc>> = sqlx::query_scalar("SELECT rolled_until FROM click_rollups WHERE id = 1 FOR UPDATE")
+            .fetch_one(&mut tx)
+            .await?;
+        let first: Option<DateTime<Utc>> = sqlx::query_scalar("SELECT MIN(clicked_at) FROM clicks WHERE NOT rolled_up AND clicked_at < $1")
+            .bind(until)
+            .fetch_one(&mut tx)
+            .await?;
+        let to = match first {
+            Some(first) => until.min(Interval::Hour.truncate(first) + chrono::Duration::days(1)),
+            None => until,
+        };
+        if first.is_some() {
+            // A single statement flags and counts the same clicks, even while
+            // late ones are being written.
+            let fields = std::iter::once(("''".to_string(), "''".to_string()))
+                .chain(ClickField::ALL.iter().map(|field| {
+                    (format!("'{}'", field.column()), format!("LEFT(COALESCE({}, ''), 512)", field.column()))
+                }))
+                .map(|(field, value)| format!("SELECT tenant, domain, key, clicked_at, {} AS field, {} AS value, is_bot FROM rolled", field, value))
+                .collect::<Vec<_>>()
+                .join(" UNION ALL ");
+            let sql = format!(
+                "WITH rolled AS (UPDATE clicks SET rolled_up = TRUE WHERE NOT rolled_up AND clicked_at < $1 RETURNING tenant, domain, key, clicked_at, referrer, user_agent, utm_source, country, is_bot), \
+                fields AS ({}), \
+                hourly AS (INSERT INTO clicks_hourly (tenant, domain, key, bucket, field, value, is_bot, clicks) SELECT tenant, domain, key, date_trunc('hour', clicked_at AT TIME ZONE 'UTC') AT TIME ZONE 'UTC', field, value, is_bot, COUNT(*) FROM fields GROUP BY 1, 2, 3, 4, 5, 6, 7 ON CONFLICT (tenant, domain, key, bucket, field, value, is_bot) DO UPDATE SET clicks = clicks_hourly.clicks + EXCLUDED.clicks) \
+                INSERT INTO clicks_daily (tenant, domain, key, bucket, field, value, is_bot, clicks) SELECT tenant, domain, key, date_trunc('day', clicked_at AT TIME ZONE 'UTC') AT TIME ZONE 'UTC', field, value, is_bot, COUNT(*) FROM fields GROUP BY 1, 2, 3, 4, 5, 6, 7 ON CONFLICT (tenant, domain, key, bucket, field, value, is_bot) DO UPDATE SET clicks = clicks_daily.clicks + EXCLUDED.clicks",
+                fields,
+            );
+            sqlx::query(&sql)
+                .bind(to)
+                .execute(&mut tx)
+                .await?;
+        }
+        sqlx::query("UPDATE click_rollups SET rolled_until = $1 WHERE id = 1")
+            .bind(rolled_until.map_or(to, |rolled_until| rolled_until.max(to)))
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(to)
+    }
+
+    async fn purge_hourly_clicks(&self, before: DateTime<Utc>) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM clicks_hourly WHERE bucket < $1")
+            .bind(before)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn get_click_series(&self, tenant: String, domain: String, key: String, interval: Interval, range: &ClickRange) -> Result<Vec<ClickBucket>, sqlx::Error> {
+        let unit = match interval {
+            Interval::Hour => "hour",
+            Interval::Day => "day",
+            Interval::Week => "week",
+        };
+        let source = range.source;
+        let sql = format!(
+            "SELECT date_trunc($1, {0} AT TIME ZONE 'UTC') AT TIME ZONE 'UTC' AS at, {1} AS clicks FROM {2} WHERE domain = $2 AND key = $3 AND tenant = $7 AND {3} AND {0} >= $4 AND {0} < $5 AND ($6 OR NOT is_bot) GROUP BY at ORDER BY at",
+            source.time_column(), source.count(), source.table(), source.totals(),
+        );
+        sqlx::query_as::<_, ClickBucket>(&sql)
+            .bind(unit)
+            .bind(domain)
+            .bind(key)
+            .bind(range.from)
+            .bind(range.to)
+            .bind(range.include_bots)
+            .bind(tenant)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn get_click_counts(&self, tenant: String, domain: String, key: String, field: ClickField, range: &ClickRange) -> Result<Vec<ClickCount>, sqlx::Error> {
+        let source = range.source;
+        let (value, condition) = source.values(field);
+        let sql = format!(
+            "SELECT {0} AS value, {1} AS clicks FROM {2} WHERE domain = $1 AND key = $2 AND tenant = $6 AND {3} AND {4} >= $3 AND {4} < $4 AND ($5 OR NOT is_bot) GROUP BY 1",
+            value, source.count(), source.table(), condition, source.time_column(),
+        );
+        sqlx::query_as::<_, ClickCount>(&sql)
+            .bind(domain)
+            .bind(key)
+            .bind(range.from)
+            .bind(range.to)
+            .bind(range.include_bots)
+            .bind(tenant)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn get_link_clicks(&self, tenant: String, range: &ClickRange) -> Result<Vec<TopLink>, sqlx::Error> {
+        let source = range.source;
+        let sql = format!(
+            "SELECT domain, key, {0} AS clicks FROM {1} WHERE tenant = $4 AND {2} AND {3} >= $1 AND {3} < $2 AND ($3 OR NOT is_bot) GROUP BY domain, key",
+            source.count(), source.table(), source.totals(), source.time_column(),
+        );
+        sqlx::query_as::<_, TopLink>(&sql)
+            .bind(range.from)
+            .bind(range.to)
+            .bind(range.include_bots)
+            .bind(tenant)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn close(&self) {
+        self.pool.close().await
+    }
+
+    async fn watch(&self, cache: Cache) -> Result<(), sqlx::Error> {
+        let mut listener = PgListener::connect(&self.url).await?;
+        listener.listen(CHANGES_CHANNEL).await?;
+        tokio::spawn(async move {
+            loop {
+                match listener.try_recv().await {
+                    Ok(Some(notification)) => match notification.payload().split_once('/') {
+                        Some((domain, key)) => cache.invalidate(domain, key),
+                        None => cache.clear(),
+                    },
+                    // Notifications sent while reconnecting are lost, so
+                    // nothing cached up to this point can be trusted.
+                    Ok(None) => {
+                        tracing::warn!("Lost connection listening for url map changes, clearing cache");
+                        cache.clear();
+                    }
+                    Err(e) => {
+                        tracing::error!("Failed to receive url map changes, error: {}", e);
+                        cache.clear();
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,682 @@
+use crate::{config::Database, db::{ApiToken, Bind, Click, ClickBucket, ClickCount, ClickField, ClickExport, ClickRange, DailyVisitors, Domain, ExportedClick, ExportedRollup, HyperLogLog, Interval, DEFAULT_TENANT, Tenant, TopLink, UrlMap, UrlMapChange, UrlMapPage, UrlMapQuery}};
+use super::{Storage, Transaction};
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use futures::StreamExt;
+use sqlx::{migrate, Pool, Sqlite, sqlite::{SqliteConnectOptions, SqlitePoolOptions}};
+use std::str::FromStr;
+use tokio::sync::mpsc::Sender;
+
+pub struct SqliteStorage {
+    pool: Pool<Sqlite>,
+}
+
+impl SqliteStorage {
+    pub async fn new(config: &Database) -> Result<Self> {
+        let options = SqliteConnectOptions::from_str(&config.url)?
+            .create_if_missing(true);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(config.max_connections)
+            .connect_with(options)
+            .await?;
+        Self::migrate(pool).await
+    }
+
+    // Every connection to `sqlite::memory:` opens its own empty database, so
+    // the pool is pinned to a single connection that is never recycled.
+    pub const IN_MEMORY_CONNECTIONS: u32 = 1;
+
+    pub async fn in_memory() -> Result<Self> {
+        let options = SqliteConnectOptions::from_str("sqlite::memory:")?;
+        let pool = SqlitePoolOptions::new()
+            .min_connections(Self::IN_MEMORY_CONNECTIONS)
+            .max_connections(Self::IN_MEMORY_CONNECTIONS)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect_with(options)
+            .await?;
+        Self::migrate(pool).await
+    }
+
+    async fn migrate(pool: Pool<Sqlite>) -> Result<Self> {
+        let migrator = migrate!("./migrations/sqlite");
+        migrator
+            .run(&pool)
+            .await?;
+
+        Ok(Self { pool })
+    }
+}
+
+pub struct SqliteTransaction {
+    tx: sqlx::Transaction<'static, Sqlite>,
+}
+
+impl SqliteTransaction {
+    async fn record(
+        &mut self,
+        action: &str,
+        url_map: &UrlMap,
+        old_url: Option<&str>,
+        new_url: Option<&str>,
+        actor: Option<&str>,
+        changed_at: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("INSERT INTO url_map_history (tenant, domain, key, action, old_url, new_url, actor, changed_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?)")
+            .bind(&url_map.tenant)
+            .bind(&url_map.domain)
+            .bind(&url_map.key)
+            .bind(action)
+            .bind(old_url)
+            .bind(new_url)
+            .bind(actor)
+            .bind(changed_at)
+            .execute(&mut self.tx)
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Transaction for SqliteTransaction {
+    async fn get_url_map(&mut self, domain: &str, key: &str) -> Result<Option<UrlMap>, sqlx::Error> {
+        sqlx::query_as::<_, UrlMap>("SELECT * FROM url_maps WHERE domain = ? AND key = ?")
+            .bind(domain)
+            .bind(key)
+            .fetch_optional(&mut self.tx)
+            .await
+    }
+
+    async fn create_url_map(&mut self, url_map: &UrlMap, actor: Option<&str>) -> Result<UrlMap, sqlx::Error> {
+        let now = Utc::now();
+        // SQLite checks foreign keys once the statement completes, which a
+        // RETURNING row fetched with `fetch_one` never lets it do, so an
+        // unknown domain would go unnoticed.
+        let result = sqlx::query("INSERT INTO url_maps (domain, key, url, created_at, updated_at, created_by, updated_by, expires_at, not_before, max_clicks, tenant, prefix) SELECT ?1, ?2, ?3, ?4, ?4, ?5, ?5, ?6, ?7, ?8, ?9, ?10 WHERE EXISTS (SELECT 1 FROM domains WHERE name = ?1 AND (tenant IS NULL OR tenant = ?9))")
+            .bind(&url_map.domain)
+            .bind(&url_map.key)
+            .bind(&url_map.url)
+            .bind(now)
+            .bind(actor)
+            .bind(url_map.expires_at)
+            .bind(url_map.not_before)
+            .bind(url_map.max_clicks)
+            .bind(&url_map.tenant)
+            .bind(url_map.prefix)
+            .execute(&mut self.tx)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        let url_map = sqlx::query_as::<_, UrlMap>("SELECT * FROM url_maps WHERE domain = ? AND key = ?")
+            .bind(&url_map.domain)
+            .bind(&url_map.key)
+            .fetch_one(&mut self.tx)
+            .await?;
+        self.record("create", &url_map, None, Some(&url_map.url), actor, now).await?;
+        Ok(url_map)
+    }
+
+    async fn update_url_map(&mut self, url_map: &UrlMap, actor: Option<&str>) -> Result<UrlMap, sqlx::Error> {
+        let now = Utc::now();
+        let old_url: String = sqlx::query_scalar("SELECT url FROM url_maps WHERE tenant = ? AND domain = ? AND key = ? AND deleted_at IS NULL")
+            .bind(&url_map.tenant)
+            .bind(&url_map.domain)
+            .bind(&url_map.key)
+            .fetch_one(&mut self.tx)
+            .await?;
+        let url_map = sqlx::query_as::<_, UrlMap>("UPDATE url_maps SET url = ?, updated_at = ?, updated_by = ?, expires_at = ?, not_before = ?, max_clicks = ?, prefix = ? WHERE domain = ? AND key = ? AND tenant = ? AND deleted_at IS NULL RETURNING *")
+            .bind(&url_map.url)
+            .bind(now)
+            .bind(actor)
+            .bind(url_map.expires_at)
+            .bind(url_map.not_before)
+            .bind(url_map.max_clicks)
+            .bind(url_map.prefix)
+            .bind(&url_map.domain)
+            .bind(&url_map.key)
+            .bind(&url_map.tenant)
+            .fetch_one(&mut self.tx)
+            .await?;
+        self.record("update", &url_map, Some(&old_url), Some(&url_map.url), actor, now).await?;
+        Ok(url_map)
+    }
+
+    async fn delete_url_map(&mut self, tenant: &str, domain: &str, key: &str, actor: Option<&str>) -> Result<UrlMap, sqlx::Error> {
+        let now = Utc::now();
+        let url_map = sqlx::query_as::<_, UrlMap>("UPDATE url_maps SET deleted_at = ?, deleted_by = ? WHERE domain = ? AND key = ? AND tenant = ? AND deleted_at IS NULL RETURNING *")
+            .bind(now)
+            .bind(actor)
+            .bind(domain)
+            .bind(key)
+            .bind(tenant)
+            .fetch_one(&mut self.tx)
+            .await?;
+        self.record("delete", &url_map, Some(&url_map.url), None, actor, now).await?;
+        Ok(url_map)
+    }
+
+    async fn restore_url_map(&mut self, tenant: &str, domain: &str, key: &str, actor: Option<&str>) -> Result<UrlMap, sqlx::Error> {
+        let url_map = sqlx::query_as::<_, UrlMap>("UPDATE url_maps SET deleted_at = NULL, deleted_by = NULL WHERE domain = ? AND key = ? AND tenant = ? AND deleted_at IS NOT NULL RETURNING *")
+            .bind(domain)
+            .bind(key)
+            .bind(tenant)
+            .fetch_one(&mut self.tx)
+            .await?;
+        self.record("restore", &url_map, None, Some(&url_map.url), actor, Utc::now()).await?;
+        Ok(url_map)
+    }
+
+    async fn purge_url_map(&mut self, tenant: &str, domain: &str, key: &str, actor: Option<&str>) -> Result<UrlMap, sqlx::Error> {
+        let url_map = sqlx::query_as::<_, UrlMap>("DELETE FROM url_maps WHERE domain = ? AND key = ? AND tenant = ? AND deleted_at IS NOT NULL RETURNING *")
+            .bind(domain)
+            .bind(key)
+            .bind(tenant)
+            .fetch_one(&mut self.tx)
+            .await?;
+        self.record("purge", &url_map, Some(&url_map.url), None, actor, Utc::now()).await?;
+        Ok(url_map)
+    }
+
+    async fn purge_trash(&mut self, deleted_before: DateTime<Utc>) -> Result<Vec<UrlMap>, sqlx::Error> {
+        let now = Utc::now();
+        let url_maps = sqlx::query_as::<_, UrlMap>("DELETE FROM url_maps WHERE deleted_at < ? RETURNING *")
+            .bind(deleted_before)
+            .fetch_all(&mut self.tx)
+            .await?;
+        for url_map in &url_maps {
+            self.record("purge", url_map, Some(&url_map.url), None, None, now).await?;
+        }
+        Ok(url_maps)
+    }
+
+    async fn archive_expired(&mut self, expired_before: DateTime<Utc>) -> Result<Vec<UrlMap>, sqlx::Error> {
+        let now = Utc::now();
+        let url_maps = sqlx::query_as::<_, UrlMap>("UPDATE url_maps SET deleted_at = ? WHERE expires_at < ? AND deleted_at IS NULL RETURNING *")
+            .bind(now)
+            .bind(expired_before)
+            .fetch_all(&mut self.tx)
+            .await?;
+        for url_map in &url_maps {
+            self.record("archive", url_map, Some(&url_map.url), None, None, now).await?;
+        }
+        Ok(url_maps)
+    }
+
+    async fn get_url_map_change(&mut self, tenant: &str, domain: &str, key: &str, id: i64) -> Result<Option<UrlMapChange>, sqlx::Error> {
+        sqlx::query_as::<_, UrlMapChange>("SELECT * FROM url_map_history WHERE tenant = ? AND domain = ? AND key = ? AND id = ?")
+            .bind(tenant)
+            .bind(domain)
+            .bind(key)
+            .bind(id)
+            .fetch_optional(&mut self.tx)
+            .await
+    }
+
+    async fn get_domain(&mut self, name: &str) -> Result<Option<Domain>, sqlx::Error> {
+        sqlx::query_as::<_, Domain>("SELECT * FROM domains WHERE name = ?")
+            .bind(name)
+            .fetch_optional(&mut self.tx)
+            .await
+    }
+
+    async fn savepoint(&mut self) -> Result<(), sqlx::Error> {
+        sqlx::query("SAVEPOINT batch_step").execute(&mut self.tx).await?;
+        Ok(())
+    }
+
+    async fn release_savepoint(&mut self) -> Result<(), sqlx::Error> {
+        sqlx::query("RELEASE SAVEPOINT batch_step").execute(&mut self.tx).await?;
+        Ok(())
+    }
+
+    async fn rollback_to_savepoint(&mut self) -> Result<(), sqlx::Error> {
+        sqlx::query("ROLLBACK TO SAVEPOINT batch_step").execute(&mut self.tx).await?;
+        Ok(())
+    }
+
+    async fn commit(self: Box<Self>) -> Result<(), sqlx::Error> {
+        self.tx.commit().await
+    }
+
+    async fn rollback(self: Box<Self>) -> Result<(), sqlx::Error> {
+        self.tx.rollback().await
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn begin(&self) -> Result<Box<dyn Transaction>, sqlx::Error> {
+        let tx = self.pool.begin().await?;
+        Ok(Box::new(SqliteTransaction { tx }))
+    }
+
+    async fn get_url_maps(&self, tenant: String, query: UrlMapQuery) -> Result<UrlMapPage, sqlx::Error> {
+        let (sql, binds) = query.to_sql(&tenant);
+        let mut url_maps = sqlx::query_as::<_, UrlMap>(&sql);
+        for bind in binds {
+            url_maps = match bind {
+                Bind::Text(value) => url_maps.bind(value),
+                Bind::Timestamp(value) => url_maps.bind(value),
+            };
+        }
+        let url_maps = url_maps.fetch_all(&self.pool).await?;
+        Ok(query.page(url_maps))
+    }
+
+    async fn get_url_map(&self, domain: String, key: String) -> Result<UrlMap, sqlx::Error> {
+        sqlx::query_as::<_, UrlMap>("SELECT * FROM url_maps WHERE domain = ? AND key = ? AND deleted_at IS NULL")
+            .bind(domain)
+            .bind(key)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn resolve_url_map(&self, domain: String, path: String) -> Result<UrlMap, sqlx::Error> {
+        let keys = UrlMap::candidate_keys(&path);
+        let sql = format!("SELECT * FROM url_maps WHERE domain = ? AND key IN ({}) AND (key = ? OR prefix) AND deleted_at IS NULL ORDER BY length(key) DESC LIMIT 1", vec!["?"; keys.len()].join(", "));
+        let mut url_map = sqlx::query_as::<_, UrlMap>(&sql).bind(domain);
+        for key in keys {
+            url_map = url_map.bind(key);
+        }
+        url_map.bind(&path).fetch_one(&self.pool).await
+    }
+
+    async fn use_click(&self, domain: String, key: String) -> Result<Option<UrlMap>, sqlx::Error> {
+        sqlx::query_as::<_, UrlMap>("UPDATE url_maps SET used_clicks = used_clicks + 1 WHERE domain = ? AND key = ? AND deleted_at IS NULL AND (max_clicks IS NULL OR used_clicks < max_clicks) RETURNING *")
+            .bind(domain)
+            .bind(key)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    async fn get_url_map_history(&self, tenant: String, domain: String, key: String) -> Result<Vec<UrlMapChange>, sqlx::Error> {
+        sqlx::query_as::<_, UrlMapChange>("SELECT * FROM url_map_history WHERE domain = ? AND key = ? AND tenant = ? ORDER BY id DESC")
+            .bind(domain)
+            .bind(key)
+            .bind(tenant)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn get_url_map_version(&self, tenant: String, domain: String, key: String, at: DateTime<Utc>) -> Result<UrlMapChange, sqlx::Error> {
+        sqlx::query_as::<_, UrlMapChange>("SELECT * FROM url_map_history WHERE domain = ? AND key = ? AND tenant = ? AND changed_at <= ? ORDER BY changed_at DESC, id DESC LIMIT 1")
+            .bind(domain)
+            .bind(key)
+            .bind(tenant)
+            .bind(at)
+            .fetch_optional(&self.pool)
+            .await?
+            .filter(|change| change.new_url.is_some())
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    async fn next_key_sequence(&self) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar("INSERT INTO url_map_key_sequence DEFAULT VALUES RETURNING id")
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn get_domains(&self, tenant: String) -> Result<Vec<Domain>, sqlx::Error> {
+        sqlx::query_as::<_, Domain>("SELECT * FROM domains WHERE name <> '' AND tenant = ? ORDER BY name")
+            .bind(tenant)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn resolve_domains(&self) -> Result<Vec<Domain>, sqlx::Error> {
+        sqlx::query_as::<_, Domain>("SELECT * FROM domains WHERE name <> ''")
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    // Run to completion rather than with RETURNING, as an unknown tenant is
+    // only caught by its foreign key.
+    async fn create_domain(&self, tenant: String, name: String, actor: Option<String>) -> Result<Domain, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("INSERT INTO domains (name, created_at, created_by, tenant) VALUES (?, ?, ?, ?)")
+            .bind(&name)
+            .bind(Utc::now())
+            .bind(actor)
+            .bind(tenant)
+            .execute(&mut tx)
+            .await?;
+        let domain = sqlx::query_as::<_, Domain>("SELECT * FROM domains WHERE name = ?")
+            .bind(&name)
+            .fetch_one(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(domain)
+    }
+
+    // Run to completion rather than with RETURNING, for the same reason as
+    // `create_url_map`.
+    async fn delete_domain(&self, tenant: String, name: String) -> Result<Domain, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let domain = sqlx::query_as::<_, Domain>("SELECT * FROM domains WHERE name = ? AND tenant = ?")
+            .bind(&name)
+            .bind(&tenant)
+            .fetch_one(&mut tx)
+            .await?;
+        sqlx::query("DELETE FROM domains WHERE name = ?")
+            .bind(&name)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(domain)
+    }
+
+    async fn get_tenants(&self) -> Result<Vec<Tenant>, sqlx::Error> {
+        sqlx::query_as::<_, Tenant>("SELECT * FROM tenants ORDER BY name")
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn create_tenant(&self, name: String, actor: Option<String>) -> Result<Tenant, sqlx::Error> {
+        sqlx::query_as::<_, Tenant>("INSERT INTO tenants (name, created_at, created_by) VALUES (?, ?, ?) RETURNING *")
+            .bind(name)
+            .bind(Utc::now())
+            .bind(actor)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    // Run to completion rather than with RETURNING, so a tenant that still
+    // owns anything is refused by its foreign keys.
+    async fn delete_tenant(&self, name: String) -> Result<Tenant, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let tenant = sqlx::query_as::<_, Tenant>("SELECT * FROM tenants WHERE name = ? AND name <> ?")
+            .bind(&name)
+            .bind(DEFAULT_TENANT)
+            .fetch_one(&mut tx)
+            .await?;
+        sqlx::query("DELETE FROM tenants WHERE name = ?")
+            .bind(&name)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(tenant)
+    }
+
+    async fn get_api_tokens(&self, tenant: String) -> Result<Vec<ApiToken>, sqlx::Error> {
+        sqlx::query_as::<_, ApiToken>("SELECT id, tenant, name, created_at, created_by FROM api_tokens WHERE tenant = ? ORDER BY id")
+            .bind(tenant)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn create_api_token(&self, tenant: String, name: String, token_hash: String, actor: Option<String>) -> Result<ApiToken, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("INSERT INTO api_tokens (tenant, name, token_hash, created_at, created_by) VALUES (?, ?, ?, ?, ?)")
+            .bind(tenant)
+            .bind(name)
+            .bind(&token_hash)
+            .bind(Utc::now())
+            .bind(actor)
+            .execute(&mut tx)
+            .await?;
+        let api_token = sqlx::query_as::<_, ApiToken>("SELECT id, tenant, name, created_at, created_by FROM api_tokens WHERE token_hash = ?")
+            .bind(&token_hash)
+            .fetch_one(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(api_token)
+    }
+
+    async fn delete_api_token(&self, tenant: String, id: i64) -> Result<ApiToken, sqlx::Error> {
+        sqlx::query_as::<_, ApiToken>("DELETE FROM api_tokens WHERE tenant = ? AND id = ? RETURNING id, tenant, name, created_at, created_by")
+            .bind(tenant)
+            .bind(id)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn find_api_token(&self, token_hash: String) -> Result<Option<ApiToken>, sqlx::Error> {
+        sqlx::query_as::<_, ApiToken>("SELECT id, tenant, name, created_at, created_by FROM api_tokens WHERE token_hash = ?")
+            .bind(token_hash)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    async fn export_url_maps(&self, tenant: String, sender: Sender<Result<UrlMap, sqlx::Error>>) {
+        let mut url_maps = sqlx::query_as::<_, UrlMap>("SELECT * FROM url_maps WHERE tenant = ? AND deleted_at IS NULL ORDER BY domain, key")
+            .bind(tenant)
+            .fetch(&self.pool);
+        while let Some(url_map) = url_maps.next().await {
+            if sender.send(url_map).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    async fn export_clicks(&self, tenant: String, export: &ClickExport, sender: Sender<Result<ExportedClick, sqlx::Error>>) {
+        let mut clicks = sqlx::query_as::<_, ExportedClick>("SELECT key, clicked_at, referrer, user_agent, utm_source, country, region, is_bot, domain FROM clicks WHERE tenant = ?5 AND (?1 IS NULL OR key = ?1) AND (?2 IS NULL OR clicked_at >= ?2) AND (?3 IS NULL OR clicked_at < ?3) AND (?4 IS NULL OR domain = ?4) ORDER BY clicked_at, id")
+            .bind(&export.key)
+            .bind(export.from)
+            .bind(export.to)
+            .bind(&export.domain)
+            .bind(tenant)
+            .fetch(&self.pool);
+        while let Some(click) = clicks.next().await {
+            if sender.send(click).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    async fn export_rollups(&self, tenant: String, export: &ClickExport, sender: Sender<Result<ExportedRollup, sqlx::Error>>) {
+        let sql = format!("SELECT key, bucket, CASE field WHEN '' THEN 'total' ELSE field END AS field, NULLIF(value, '') AS value, is_bot, clicks, domain FROM {} WHERE tenant = ?5 AND (?1 IS NULL OR key = ?1) AND (?2 IS NULL OR bucket >= ?2) AND (?3 IS NULL OR bucket < ?3) AND (?4 IS NULL OR domain = ?4) ORDER BY bucket, domain, key, field, value, is_bot", export.granularity.table());
+        let mut rollups = sqlx::query_as::<_, ExportedRollup>(&sql)
+            .bind(&export.key)
+            .bind(export.from)
+            .bind(export.to)
+            .bind(&export.domain)
+            .bind(tenant)
+            .fetch(&self.pool);
+        while let Some(rollup) = rollups.next().await {
+            if sender.send(rollup).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    async fn insert_clicks(&self, clicks: &[Click]) -> Result<(), sqlx::Error> {
+        if clicks.is_empty() {
+            return Ok(());
+        }
+        let values = vec!["(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"; clicks.len()].join(", ");
+        let sql = format!("INSERT INTO clicks (tenant, domain, key, clicked_at, referrer, user_agent, ip, utm_source, country, region, is_bot) VALUES {}", values);
+        let mut query = sqlx::query(&sql);
+        for click in clicks {
+            query = query
+                .bind(&click.tenant)
+                .bind(&click.domain)
+                .bind(&click.key)
+                .bind(click.clicked_at)
+                .bind(&click.referrer)
+                .bind(&click.user_agent)
+                .bind(&click.ip)
+                .bind(&click.utm_source)
+                .bind(&click.country)
+                .bind(&click.region)
+                .bind(click.is_bot);
+        }
+        query.execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn purge_clicks(&self, clicked_before: DateTime<Utc>, rolled_up: bool) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM clicks WHERE clicked_at < ? AND rolled_up = ?")
+            .bind(clicked_before)
+            .bind(rolled_up)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn merge_visitors(&self, mut visitors: Vec<DailyVisitors>) -> Result<(), sqlx::Error> {
+        // The insert takes the database's write lock up front, so concurrent
+        // merges cannot read the same sketch.
+        visitors.sort_by(|a, b| (&a.tenant, &a.domain, &a.key, a.day).cmp(&(&b.tenant, &b.domain, &b.key, b.day)));
+        let mut tx = self.pool.begin().await?;
+        for DailyVisitors { tenant, domain, key, day, sketch } in visitors {
+            sqlx::query("INSERT INTO click_visitors (tenant, domain, key, day, sketch) VALUES (?, ?, ?, ?, ?) ON CONFLICT (tenant, domain, key, day) DO NOTHING")
+                .bind(&tenant)
+                .bind(&domain)
+                .bind(&key)
+                .bind(day)
+                .bind(HyperLogLog::default().as_bytes())
+                .execute(&mut tx)
+                .await?;
+            let stored: Vec<u8> = sqlx::query_scalar("SELECT sketch FROM click_visitors WHERE tenant = ? AND domain = ? AND key = ? AND day = ?")
+                .bind(&tenant)
+                .bind(&domain)
+                .bind(&key)
+                .bind(day)
+                .fetch_one(&mut tx)
+                .await?;
+            let mut stored = HyperLogLog::from_bytes(stored);
+            stored.merge(&sketch);
+            sqlx::query("UPDATE click_visitors SET sketch = ? WHERE tenant = ? AND domain = ? AND key = ? AND day = ?")
+                .bind(stored.as_bytes())
+                .bind(&tenant)
+                .bind(&domain)
+                .bind(&key)
+                .bind(day)
+                .execute(&mut tx)
+                .await?;
+        }
+        tx.commit().await
+    }
+
+    async fn get_visitors(&self, tenant: String, domain: String, key: String, from: NaiveDate, to: NaiveDate) -> Result<Vec<DailyVisitors>, sqlx::Error> {
+        let rows: Vec<(NaiveDate, Vec<u8>)> = sqlx::query_as("SELECT day, sketch FROM click_visitors WHERE tenant = ? AND domain = ? AND key = ? AND day >= ? AND day < ? ORDER BY day")
+            .bind(&tenant)
+            .bind(&domain)
+            .bind(&key)
+            .bind(from)
+            .bind(to)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.into_iter()
+            .map(|(day, sketch)| DailyVisitors { tenant: tenant.clone(), domain: domain.clone(), key: key.clone(), day, sketch: HyperLogLog::from_bytes(sketch) })
+            .collect())
+    }
+
+    async fn get_rolled_until(&self) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+        sqlx::query_scalar("SELECT rolled_until FROM click_rollups WHERE id = 1")
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    // Buckets are computed on the text timestamps.
+    async fn roll_up_clicks(&self, until: DateTime<Utc>) -> Result<DateTime<Utc>, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let rolled_until: Option<DateTime<Utc>> = sqlx::query_scalar("SELECT rolled_until FROM click_rollups WHERE id = 1")
+            .fetch_one(&mut tx)
+            .await?;
+        let first: Option<DateTime<Utc>> = sqlx::query_scalar("SELECT MIN(clicked_at) FROM clicks WHERE NOT rolled_up AND clicked_at < ?")
+            .bind(until)
+            .fetch_one(&mut tx)
+            .await?;
+        let to = match first {
+            Some(first) => until.min(Interval::Hour.truncate(first) + Duration::days(1)),
+            None => until,
+        };
+        if first.is_some() {
+            let fields = std::iter::once(("''".to_string(), "''".to_string()))
+                .chain(ClickField::ALL.iter().map(|field| {
+                    (format!("'{}'", field.column()), format!("substr(COALESCE({}, ''), 1, 512)", field.column()))
+                }));
+            for (field, value) in fields {
+                for (table, bucket) in [("clicks_hourly", "%Y-%m-%d %H:00:00"), ("clicks_daily", "%Y-%m-%d 00:00:00")] {
+                    let sql = format!("INSERT INTO {0} (tenant, domain, key, bucket, field, value, is_bot, clicks) SELECT tenant, domain, key, strftime('{1}', clicked_at), {2}, {3}, is_bot, COUNT(*) FROM clicks WHERE NOT rolled_up AND clicked_at < ? GROUP BY 1, 2, 3, 4, 5, 6, 7 ON CONFLICT (tenant, domain, key, bucket, field, value, is_bot) DO UPDATE SET clicks = {0}.clicks + excluded.clicks", table, bucket, field, value);
+                    sqlx::query(&sql)
+                        .bind(to)
+                        .execute(&mut tx)
+                        .await?;
+                }
+            }
+            sqlx::query("UPDATE clicks SET rolled_up = TRUE WHERE NOT rolled_up AND clicked_at < ?")
+                .bind(to)
+                .execute(&mut tx)
+                .await?;
+        }
+        sqlx::query("UPDATE click_rollups SET rolled_until = ? WHERE id = 1")
+            .bind(rolled_until.map_or(to, |rolled_until| rolled_until.max(to)))
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(to)
+    }
+
+    async fn purge_hourly_clicks(&self, before: DateTime<Utc>) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM clicks_hourly WHERE bucket < ?")
+            .bind(before)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    // Weeks start on Monday.
+    async fn get_click_series(&self, tenant: String, domain: String, key: String, interval: Interval, range: &ClickRange) -> Result<Vec<ClickBucket>, sqlx::Error> {
+        let source = range.source;
+        let time = source.time_column();
+        let bucket = match interval {
+            Interval::Hour => format!("strftime('%Y-%m-%d %H:00:00', {})", time),
+            Interval::Day => format!("strftime('%Y-%m-%d 00:00:00', {})", time),
+            Interval::Week => format!("strftime('%Y-%m-%d 00:00:00', {}, 'weekday 0', '-6 days')", time),
+        };
+        let sql = format!(
+            "SELECT {0} AS at, {1} AS clicks FROM {2} WHERE tenant = ? AND domain = ? AND key = ? AND {3} AND {4} >= ? AND {4} < ? AND (? OR NOT is_bot) GROUP BY at ORDER BY at",
+            bucket, source.count(), source.table(), source.totals(), time,
+        );
+        sqlx::query_as::<_, ClickBucket>(&sql)
+            .bind(tenant)
+            .bind(domain)
+            .bind(key)
+            .bind(range.from)
+            .bind(range.to)
+            .bind(range.include_bots)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn get_click_counts(&self, tenant: String, domain: String, key: String, field: ClickField, range: &ClickRange) -> Result<Vec<ClickCount>, sqlx::Error> {
+        let source = range.source;
+        let (value, condition) = source.values(field);
+        let sql = format!(
+            "SELECT {0} AS value, {1} AS clicks FROM {2} WHERE tenant = ? AND domain = ? AND key = ? AND {3} AND {4} >= ? AND {4} < ? AND (? OR NOT is_bot) GROUP BY 1",
+            value, source.count(), source.table(), condition, source.time_column(),
+        );
+        sqlx::query_as::<_, ClickCount>(&sql)
+            .bind(tenant)
+            .bind(domain)
+            .bind(key)
+            .bind(range.from)
+            .bind(range.to)
+            .bind(range.include_bots)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn get_link_clicks(&self, tenant: String, range: &ClickRange) -> Result<Vec<TopLink>, sqlx::Error> {
+        let source = range.source;
+        let sql = format!(
+            "SELECT domain, key, {0} AS clicks FROM {1} WHERE tenant = ? AND {2} AND {3} >= ? AND {3} < ? AND (? OR NOT is_bot) GROUP BY domain, key",
+            source.count(), source.table(), source.totals(), source.time_column(),
+        );
+        sqlx::query_as::<_, TopLink>(&sql)
+            .bind(tenant)
+            .bind(range.from)
+            .bind(range.to)
+            .bind(range.include_bots)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn close(&self) {
+        self.pool.close().await
+    }
+}
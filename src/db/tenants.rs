@@ -0,0 +1,70 @@
+use chrono::{DateTime, Utc};
+use rand::{Rng, distributions::Alphanumeric};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use sqlx::FromRow;
+
+// Owner of everything created before tenants existed, and of requests made
+// with the global `auth_token` that do not name a tenant.
+pub const DEFAULT_TENANT: &str = "default";
+
+// Length of generated api tokens, in base62 characters.
+const TOKEN_LENGTH: usize = 40;
+
+// A workspace with its own url maps, domains, clicks and api tokens, none of
+// which other tenants can see or change.
+#[derive(Debug, FromRow, Clone, Serialize, Deserialize)]
+pub struct Tenant {
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub created_by: Option<String>,
+}
+
+impl Tenant {
+    // Lower cases `name` and checks that it is a short slug, so it can be
+    // sent as is in the `X-Tenant` header and admin cookie.
+    pub fn normalize(name: &str) -> Result<String, String> {
+        let name = name.trim().to_lowercase();
+        if name.is_empty() || name.len() > 63 {
+            return Err("tenant must be between 1 and 63 characters".into());
+        }
+        if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err("tenant may only contain letters, digits, '-' and '_'".into());
+        }
+        Ok(name)
+    }
+}
+
+// Token authenticating requests as `tenant`. Only the hash of the token is
+// stored, so it is never part of the row.
+#[derive(Debug, FromRow, Clone, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub id: i64,
+    pub tenant: String,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub created_by: Option<String>,
+}
+
+// A token as returned when it is created, the only time it is shown.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewApiToken {
+    pub token: String,
+    #[serde(flatten)]
+    pub api_token: ApiToken,
+}
+
+impl ApiToken {
+    pub fn generate() -> String {
+        rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(TOKEN_LENGTH)
+            .map(char::from)
+            .collect()
+    }
+
+    // Hex encoded SHA-256 of the token, what is stored and looked up.
+    pub fn hash(token: &str) -> String {
+        format!("{:x}", Sha256::digest(token.as_bytes()))
+    }
+}
@@ -0,0 +1,6 @@
+#[macro_use]
+mod macros;
+
+pub mod config;
+pub mod db;
+pub mod server;
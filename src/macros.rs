@@ -64,6 +64,21 @@ macro_rules! json_response {
     };
 }
 
+#[macro_export]
+macro_rules! parse_failed {
+    ($m: expr) => {
+        match $m {
+            Ok(d) => d,
+            Err(e) => {
+                return Ok(Response::builder()
+                    .status(hyper::StatusCode::BAD_REQUEST)
+                    .body(Body::from(e.to_string()))
+                    .unwrap());
+            }
+        }
+    };
+}
+
 #[macro_export]
 macro_rules! sender_failed_json {
     ($m: expr, $f: tt) => {
@@ -91,3 +106,19 @@ macro_rules! recv_failed_json {
         }
     }
 }
+
+#[macro_export]
+macro_rules! parse_failed_json {
+    ($m: expr) => {
+        match $m {
+            Ok(d) => d,
+            Err(e) => {
+                return Ok(json_response!(
+                        status: hyper::StatusCode::BAD_REQUEST,
+                        body: &serde_json::json!({
+                            "error": e.to_string(),
+                        })))
+            }
+        }
+    }
+}
@@ -1,26 +1,29 @@
-use anyhow::Result;
-use crate::db::{DB, Manager};
+use anyhow::{anyhow, Result};
+use url_mapper_rs::{config::CONFIG, db::{Cache, ClickExport, ClickRecorder, ClickSource, DB, DEFAULT_TENANT, ExportRecord, ExportWriter, Manager}, server::Server};
 use tracing::subscriber::set_global_default;
 use tracing_subscriber::FmtSubscriber;
-use server::Server;
-use std::process;
-
-#[macro_use]
-mod macros;
-
-mod config;
-mod db;
-mod server;
+use std::{env, fs::File, io::{self, BufWriter, Write}, process};
+use tokio::sync::mpsc::{self, Receiver};
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if let Some("export-clicks") = args.first().map(String::as_str) {
+        // Logs go to stderr so that the export can be written to stdout.
+        set_global_default(FmtSubscriber::builder().with_writer(io::stderr).finish())?;
+        return export_clicks(&args[1..]).await;
+    }
+
     let subscriber = FmtSubscriber::new();
     set_global_default(subscriber)?;
 
     let db = DB::new().await.unwrap();
-    let (db_tx, db_rx) = tokio::sync::mpsc::channel(32);
+    let cache = Cache::new(&CONFIG.cache);
+    let (db_tx, db_rx) = tokio::sync::mpsc::channel(CONFIG.manager.channel_capacity);
+    let (clicks, clicks_rx) = ClickRecorder::new(&CONFIG.clicks, &CONFIG.privacy);
+    let manager_cache = cache.clone();
     tokio::spawn(async move {
-        let mut manager = Manager::new(db, db_rx);
+        let mut manager = Manager::new(db, db_rx, manager_cache, clicks_rx);
         manager.listen().await;
     });
 
@@ -41,7 +44,62 @@ async fn main() -> Result<()> {
         process::exit(0);
     });
 
-    Server::new(db_tx).listen().await?;
+    Server::new(db_tx, cache, clicks).listen().await?;
+
+    Ok(())
+}
+
+// `export-clicks [--tenant NAME] [--granularity raw|hourly|daily] [--domain NAME]
+// [--key KEY] [--from TIME] [--to TIME] [--format csv|parquet] [--output PATH]`
+// takes the same options as `GET /api/stats/export` and writes to stdout unless
+// given an output.
+async fn export_clicks(args: &[String]) -> Result<()> {
+    let mut tenant = DEFAULT_TENANT.to_string();
+    let mut output = None;
+    let mut query = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let name = arg.strip_prefix("--").ok_or_else(|| anyhow!("Unexpected argument {}", arg))?;
+        let value = args.next().ok_or_else(|| anyhow!("Missing value for {}", arg))?;
+        match name {
+            "output" => output = Some(value),
+            "tenant" => tenant = value.to_lowercase(),
+            "granularity" | "domain" | "key" | "from" | "to" | "format" => query.push((name, value.as_str())),
+            _ => return Err(anyhow!("Unknown option {}", arg)),
+        }
+    }
+    let export = ClickExport::parse(Some(&serde_urlencoded::to_string(&query)?))?;
+    let out: Box<dyn Write> = match output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout()),
+    };
+
+    let storage = DB::new().await?.storage;
+    let format = export.format;
+    match export.granularity {
+        ClickSource::Raw => {
+            let (tx, rx) = mpsc::channel(64);
+            tokio::spawn(async move { storage.export_clicks(tenant, &export, tx).await });
+            write_export(rx, ExportWriter::new(format)?, out).await
+        }
+        ClickSource::Hourly | ClickSource::Daily => {
+            let (tx, rx) = mpsc::channel(64);
+            tokio::spawn(async move { storage.export_rollups(tenant, &export, tx).await });
+            write_export(rx, ExportWriter::new(format)?, out).await
+        }
+    }
+}
 
+async fn write_export<R: ExportRecord>(
+    mut rows: Receiver<Result<R, sqlx::Error>>,
+    mut writer: ExportWriter<R>,
+    out: Box<dyn Write>,
+) -> Result<()> {
+    let mut out = BufWriter::new(out);
+    while let Some(row) = rows.recv().await {
+        out.write_all(&writer.write(row?)?)?;
+    }
+    out.write_all(&writer.finish()?)?;
+    out.flush()?;
     Ok(())
 }
@@ -1,6 +1,9 @@
 mod routes;
+#[allow(clippy::module_inception)]
 mod server;
 mod state;
+mod testing;
 
 pub use server::Server;
 pub use state::State;
+pub use testing::{InMemoryService, in_memory_service};
@@ -0,0 +1,26 @@
+use crate::{db::Message, server::{State, routes::api::tenant}};
+use hyper::{Body, Request, Response};
+use anyhow::Result;
+use routerify::ext::RequestExt;
+use tera::Context;
+
+pub async fn index(req: Request<Body>) -> Result<Response<Body>> {
+    let state = req.data::<State>().unwrap();
+    let sender = state.db_sender();
+    let tera = state.tera();
+
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    sender_failed!(
+        sender
+        .send(Message::GetDomains { tenant: tenant(&req), resp: tx })
+        .await, "GetDomains");
+    let domains = recv_failed!(rx.await.unwrap());
+
+    let mut context = Context::new();
+    context.insert("domains", &domains);
+    let index_html = tera.render("domains/index.html", &context)?;
+
+    Ok(Response::builder()
+       .body(Body::from(index_html))
+       .unwrap())
+}
@@ -1,11 +1,58 @@
-use crate::config::CONFIG;
-use anyhow::{Result, Error};
+use crate::{config::CONFIG, server::{State, routes::api::validate_token}};
+use anyhow::{anyhow, Result, Error};
 use hyper::{Body, Request, Response};
-use routerify::Router;
+use routerify::{Router, Middleware, ext::RequestExt};
 use std::fs::read_to_string;
+use tera::Context;
 
+mod domains;
+mod tenants;
 mod url_maps;
 
+// Pages are opened by the browser, which cannot send headers, so the token
+// and tenant entered in the page header are also kept in cookies.
+fn cookie<'a>(req: &'a Request<Body>, name: &str) -> Option<&'a str> {
+    req.headers()
+        .get_all(hyper::header::COOKIE)
+        .iter()
+        .filter_map(|cookie| cookie.to_str().ok())
+        .flat_map(|cookie| cookie.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(cookie, _)| *cookie == name)
+        .map(|(_, value)| value.trim())
+        .filter(|value| !value.is_empty())
+}
+
+// Authenticates the pages just like the api, the tenant they act for is the
+// one the token was validated for.
+async fn auth_middleware(req: Request<Body>) -> Result<Request<Body>> {
+    if matches!(req.uri().path(), "/admin/index.js" | "/admin/style.css" | "/admin/login") {
+        return Ok(req);
+    }
+
+    let token = match req.headers().get(hyper::header::AUTHORIZATION) {
+        Some(token) => Some(token.to_str()?),
+        None => cookie(&req, "authorization"),
+    };
+    let token = token.ok_or_else(|| anyhow!("Unauthorized Access"))?;
+    let requested = req.headers()
+        .get("x-tenant")
+        .and_then(|tenant| tenant.to_str().ok())
+        .or_else(|| cookie(&req, "tenant"));
+    let auth = validate_token(&req, token, requested).await?;
+    req.set_context(auth);
+    Ok(req)
+}
+
+// Asks for the token, unauthenticated admin pages are redirected here.
+async fn login_handler(req: Request<Body>) -> Result<Response<Body>> {
+    let state = req.data::<State>().unwrap();
+    let login_html = state.tera().render("login.html", &Context::new())?;
+    Ok(Response::builder()
+       .body(Body::from(login_html))
+       .unwrap())
+}
+
 async fn css_handler(_req: Request<Body>) -> Result<Response<Body>> {
     let css = match CONFIG.env.as_str() {
         "development" => read_to_string("client/tera/style.css")?,
@@ -28,8 +75,12 @@ async fn js_handler(_req: Request<Body>) -> Result<Response<Body>> {
 
 pub fn router() -> Router<Body, Error> {
     Router::builder()
+        .middleware(Middleware::pre(auth_middleware))
         .get("/index.js", js_handler)
+        .get("/login", login_handler)
         .get("/style.css", css_handler)
+        .scope("/domains", domains::router())
+        .scope("/tenants", tenants::router())
         .scope("/url_maps", url_maps::router())
         .build()
         .unwrap()
@@ -0,0 +1,42 @@
+use crate::{db::Message, server::{State, routes::api::{operator, tenant}}};
+use hyper::{Body, Request, Response};
+use anyhow::Result;
+use routerify::ext::RequestExt;
+use tera::Context;
+
+pub async fn index(req: Request<Body>) -> Result<Response<Body>> {
+    let state = req.data::<State>().unwrap();
+    let sender = state.db_sender();
+    let tera = state.tera();
+    let tenant = tenant(&req);
+    let operator = operator(&req).is_ok();
+
+    // Only the operator may see the other tenants.
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    sender_failed!(
+        sender
+        .send(Message::GetTenants { resp: tx })
+        .await, "GetTenants");
+    let tenants = recv_failed!(rx.await.unwrap())
+        .into_iter()
+        .filter(|t| operator || t.name == tenant)
+        .collect::<Vec<_>>();
+
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    sender_failed!(
+        sender
+        .send(Message::GetApiTokens { tenant: tenant.clone(), resp: tx })
+        .await, "GetApiTokens");
+    let api_tokens = recv_failed!(rx.await.unwrap());
+
+    let mut context = Context::new();
+    context.insert("tenants", &tenants);
+    context.insert("tenant", &tenant);
+    context.insert("operator", &operator);
+    context.insert("api_tokens", &api_tokens);
+    let index_html = tera.render("tenants/index.html", &context)?;
+
+    Ok(Response::builder()
+       .body(Body::from(index_html))
+       .unwrap())
+}
@@ -1,23 +1,35 @@
-use crate::{db::Message, server::State};
+use crate::{db::{Domain, Message, UrlMapQuery}, server::{routes::{wildcard, api::{domain, tenant}}, State}};
 use hyper::{Body, Request, Response};
 use anyhow::Result;
 use routerify::ext::RequestExt;
 use tera::Context;
 
+// Registered domains, offered next to the default domain wherever url maps
+// are listed or created.
+async fn domains(state: &State, tenant: String) -> Result<Vec<Domain>> {
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    state.db_sender().send(Message::GetDomains { tenant, resp: tx }).await?;
+    Ok(rx.await??)
+}
+
 pub async fn index(req: Request<Body>) -> Result<Response<Body>> {
     let state = req.data::<State>().unwrap();
     let sender = state.db_sender();
     let tera = state.tera();
+    let query = parse_failed!(UrlMapQuery::parse(req.uri().query()));
 
     let (tx, rx) = tokio::sync::oneshot::channel();
     sender_failed!(
         sender
-        .send(Message::GetUrlMaps { resp: tx })
+        .send(Message::GetUrlMaps { tenant: tenant(&req), query: query.clone(), resp: tx })
         .await, "GetUrlMaps");
-    let url_maps = recv_failed!(rx.await.unwrap());
+    let page = recv_failed!(rx.await.unwrap());
 
     let mut context = Context::new();
-    context.insert("url_maps", &url_maps);
+    context.insert("url_maps", &page.url_maps);
+    context.insert("domains", &domains(state, tenant(&req)).await?);
+    context.insert("query", &query);
+    context.insert("next", &page.next.map(|next| query.to_query_string(&next)));
     let index_html = tera.render("url_maps/index.html", &context)?;
 
     Ok(Response::builder()
@@ -25,32 +37,82 @@ pub async fn index(req: Request<Body>) -> Result<Response<Body>> {
        .unwrap())
 }
 
+pub async fn trash(req: Request<Body>) -> Result<Response<Body>> {
+    let state = req.data::<State>().unwrap();
+    let sender = state.db_sender();
+    let tera = state.tera();
+    let mut query = parse_failed!(UrlMapQuery::parse(req.uri().query()));
+    query.trashed = true;
+
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    sender_failed!(
+        sender
+        .send(Message::GetUrlMaps { tenant: tenant(&req), query: query.clone(), resp: tx })
+        .await, "GetUrlMaps");
+    let page = recv_failed!(rx.await.unwrap());
+
+    let mut context = Context::new();
+    context.insert("url_maps", &page.url_maps);
+    context.insert("domains", &domains(state, tenant(&req)).await?);
+    context.insert("query", &query);
+    context.insert("next", &page.next.map(|next| query.to_query_string(&next)));
+    let trash_html = tera.render("url_maps/trash.html", &context)?;
+
+    Ok(Response::builder()
+       .body(Body::from(trash_html))
+       .unwrap())
+}
+
 pub async fn new(req: Request<Body>) -> Result<Response<Body>> {
     let state = req.data::<State>().unwrap();
     let tera = state.tera();
+    let domain = parse_failed!(domain(&req));
 
-    let new_html = tera.render("url_maps/new.html", &Context::new())?;
+    let mut context = Context::new();
+    context.insert("domains", &domains(state, tenant(&req)).await?);
+    context.insert("domain", &domain);
+    let new_html = tera.render("url_maps/new.html", &context)?;
 
     Ok(Response::builder()
        .body(Body::from(new_html))
        .unwrap())
 }
 
+pub async fn import(req: Request<Body>) -> Result<Response<Body>> {
+    let state = req.data::<State>().unwrap();
+    let tera = state.tera();
+
+    let import_html = tera.render("url_maps/import.html", &Context::new())?;
+
+    Ok(Response::builder()
+       .body(Body::from(import_html))
+       .unwrap())
+}
+
 pub async fn edit(req: Request<Body>) -> Result<Response<Body>> {
     let state = req.data::<State>().unwrap();
     let sender = state.db_sender();
     let tera = state.tera();
-    let key = req.param("key").unwrap();
+    let key = wildcard(&req);
+    let domain = parse_failed!(domain(&req));
 
     let (tx, rx) = tokio::sync::oneshot::channel();
     sender_failed!(
         sender
-        .send(Message::GetUrlMap { key: key.into(), resp: tx })
+        .send(Message::GetUrlMap { tenant: tenant(&req), domain: domain.clone(), key: key.clone(), resp: tx })
         .await, "GetUrlMap");
     let url_map = recv_failed!(rx.await.unwrap());
 
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    sender_failed!(
+        sender
+        .send(Message::GetUrlMapHistory { tenant: tenant(&req), domain, key, resp: tx })
+        .await, "GetUrlMapHistory");
+    let history = recv_failed!(rx.await.unwrap());
+
     let mut context = Context::new();
     context.insert("url_map", &url_map);
+    context.insert("history", &history);
     let edit_html = tera.render("url_maps/edit.html", &context)?;
 
     Ok(Response::builder()
@@ -8,7 +8,9 @@ pub fn router() -> Router<Body, Error> {
     Router::builder()
         .get("/", handlers::index)
         .get("/new", handlers::new)
-        .get("/:key/edit", handlers::edit)
+        .get("/import", handlers::import)
+        .get("/trash", handlers::trash)
+        .get("/*/edit", handlers::edit)
         .build()
         .unwrap()
 }
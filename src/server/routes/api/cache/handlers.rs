@@ -0,0 +1,9 @@
+use anyhow::Result;
+use hyper::{Body, Request, Response};
+use routerify::ext::RequestExt;
+use crate::server::State;
+
+pub async fn get_stats(req: Request<Body>) -> Result<Response<Body>> {
+    let state = req.data::<State>().unwrap();
+    Ok(json_response!(body: &state.cache().stats()))
+}
@@ -0,0 +1,12 @@
+use anyhow::Error;
+use hyper::Body;
+use routerify::Router;
+
+mod handlers;
+
+pub fn router() -> Router<Body, Error> {
+    Router::builder()
+        .get("/", handlers::get_stats)
+        .build()
+        .unwrap()
+}
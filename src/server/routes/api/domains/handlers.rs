@@ -0,0 +1,65 @@
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+use hyper::{Body, Request, Response, body::to_bytes};
+use routerify::ext::RequestExt;
+use crate::{db::{Domain, Message, is_foreign_key_violation}, server::{State, routes::api::{actor, tenant}}};
+
+pub async fn get_domains(req: Request<Body>) -> Result<Response<Body>> {
+    let state = req.data::<State>().unwrap();
+    let sender = state.db_sender();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    sender_failed_json!(
+        sender
+        .send(Message::GetDomains { tenant: tenant(&req), resp: tx })
+        .await, "GetDomains");
+    let domains = recv_failed_json!(rx.await.unwrap(), hyper::StatusCode::INTERNAL_SERVER_ERROR);
+    Ok(json_response!(body: &domains))
+}
+
+pub async fn create_domain(mut req: Request<Body>) -> Result<Response<Body>> {
+    #[derive(Debug, Serialize, Deserialize)]
+    struct NewDomain {
+        name: String,
+    }
+
+    let body = to_bytes(req.body_mut()).await?;
+    let domain = parse_failed_json!(serde_json::from_slice::<NewDomain>(&body));
+    let name = parse_failed_json!(Domain::normalize(&domain.name));
+    let state = req.data::<State>().unwrap();
+    let sender = state.db_sender();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    sender_failed_json!(
+        sender
+        .send(Message::CreateDomain { tenant: tenant(&req), name, actor: actor(&req), resp: tx })
+        .await, "CreateDomain");
+    let domain = recv_failed_json!(rx.await.unwrap(), hyper::StatusCode::UNPROCESSABLE_ENTITY);
+    Ok(json_response!(body: &domain))
+}
+
+pub async fn delete_domain(req: Request<Body>) -> Result<Response<Body>> {
+    let name = req.param("name").unwrap();
+    let state = req.data::<State>().unwrap();
+    let sender = state.db_sender();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    sender_failed_json!(
+        sender
+        .send(Message::DeleteDomain { tenant: tenant(&req), name: name.to_lowercase(), resp: tx })
+        .await, "DeleteDomain");
+    let domain = match rx.await.unwrap() {
+        Ok(domain) => domain,
+        Err(e) if is_foreign_key_violation(&e) => {
+            return Ok(json_response!(
+                    status: hyper::StatusCode::CONFLICT,
+                    body: &serde_json::json!({
+                        "error": "Domain still has url maps, purge them first",
+                    })));
+        }
+        Err(e) => {
+            tracing::error!("Database Manager returned error: {}", e);
+            return Ok(json_response!(
+                    status: hyper::StatusCode::NOT_FOUND,
+                    body: &e.to_string()));
+        }
+    };
+    Ok(json_response!(body: &domain))
+}
@@ -0,0 +1,14 @@
+use anyhow::Error;
+use hyper::Body;
+use routerify::Router;
+
+mod handlers;
+
+pub fn router() -> Router<Body, Error> {
+    Router::builder()
+        .get("/", handlers::get_domains)
+        .post("/", handlers::create_domain)
+        .delete("/:name", handlers::delete_domain)
+        .build()
+        .unwrap()
+}
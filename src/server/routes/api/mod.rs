@@ -1,19 +1,89 @@
 use anyhow::{anyhow, Error, Result};
 use hyper::{Body, Request};
-use routerify::{Router, Middleware};
+use routerify::{Router, Middleware, ext::RequestExt};
 use base64::decode;
 use std::str::from_utf8;
-use crate::config::CONFIG;
+use serde::Deserialize;
+use crate::{config::CONFIG, db::{ApiToken, DEFAULT_TENANT, Message}, server::State};
 
+mod cache;
+mod domains;
+mod stats;
+mod tenants;
 mod url_maps;
 
-fn validate_token(encoded_token: &str) -> Result<()> {
-    let auth_token_bytes = decode(&encoded_token)?;
+// Who a request was authenticated as, set by `auth_middleware` here and by
+// the one of the admin pages.
+#[derive(Debug, Clone)]
+pub struct Auth {
+    tenant: String,
+    // Made with the global `auth_token`, which may act for any tenant and
+    // manage tenants and their tokens.
+    operator: bool,
+}
+
+// Checks the base64 encoded token, acting for the `requested` tenant when
+// given one.
+pub async fn validate_token(req: &Request<Body>, encoded_token: &str, requested: Option<&str>) -> Result<Auth> {
+    let auth_token_bytes = decode(encoded_token)?;
     let auth_token = from_utf8(&auth_token_bytes)?;
-    if auth_token != CONFIG.auth_token.as_str() {
-        return Err(anyhow!("Unauthorized Access"));
+    let requested = requested
+        .map(|tenant| tenant.trim().to_lowercase())
+        .filter(|tenant| !tenant.is_empty());
+    if auth_token == CONFIG.auth_token.as_str() {
+        return Ok(Auth {
+            tenant: requested.unwrap_or_else(|| DEFAULT_TENANT.into()),
+            operator: true,
+        });
+    }
+
+    let state = req.data::<State>().unwrap();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    state.db_sender()
+        .send(Message::FindApiToken { token_hash: ApiToken::hash(auth_token), resp: tx })
+        .await
+        .map_err(|e| anyhow!("Database Manager failed to get FindApiToken! error: {}", e))?;
+    let api_token = rx.await??.ok_or_else(|| anyhow!("Unauthorized Access"))?;
+    if requested.is_some_and(|tenant| tenant != api_token.tenant) {
+        return Err(anyhow!("Forbidden"));
     }
-    Ok(())
+    Ok(Auth { tenant: api_token.tenant, operator: false })
+}
+
+// Tenant the request acts for, the one of its api token or the one named in
+// `X-Tenant` by the operator.
+pub fn tenant(req: &Request<Body>) -> String {
+    req.context::<Auth>().map(|auth| auth.tenant).unwrap_or_else(|| DEFAULT_TENANT.into())
+}
+
+// Fails unless the request was made with the global `auth_token`.
+pub fn operator(req: &Request<Body>) -> Result<()> {
+    match req.context::<Auth>() {
+        Some(auth) if auth.operator => Ok(()),
+        _ => Err(anyhow!("Forbidden")),
+    }
+}
+
+// Name recorded as the author of changes, sent by clients in `X-User`.
+pub fn actor(req: &Request<Body>) -> Option<String> {
+    req.headers()
+        .get("x-user")
+        .and_then(|user| user.to_str().ok())
+        .map(|user| user.trim().to_string())
+        .filter(|user| !user.is_empty())
+}
+
+// Domain of the url map named in the path, given as `?domain=` and the default
+// domain when left out.
+pub fn domain(req: &Request<Body>) -> Result<String> {
+    #[derive(Debug, Deserialize)]
+    struct DomainQuery {
+        #[serde(default)]
+        domain: String,
+    }
+
+    let query: DomainQuery = serde_urlencoded::from_str(req.uri().query().unwrap_or(""))?;
+    Ok(query.domain)
 }
 
 async fn auth_middleware(req: Request<Body>) -> Result<Request<Body>> {
@@ -26,7 +96,9 @@ async fn auth_middleware(req: Request<Body>) -> Result<Request<Body>> {
         None => Err(anyhow!("Unauthorized Access")),
         Some(auth_token) => {
             let token = auth_token.to_str()?;
-            validate_token(token)?;
+            let requested = req.headers().get("x-tenant").and_then(|tenant| tenant.to_str().ok());
+            let auth = validate_token(&req, token, requested).await?;
+            req.set_context(auth);
             Ok(req)
         }
     }
@@ -35,6 +107,10 @@ async fn auth_middleware(req: Request<Body>) -> Result<Request<Body>> {
 pub fn router() -> Router<Body, Error> {
     Router::builder()
         .middleware(Middleware::pre(auth_middleware))
+        .scope("/cache", cache::router())
+        .scope("/domains", domains::router())
+        .scope("/stats", stats::router())
+        .scope("/tenants", tenants::router())
         .scope("/url_maps", url_maps::router())
         .build()
         .unwrap()
@@ -0,0 +1,82 @@
+use anyhow::Result;
+use hyper::{Body, Request, Response};
+use routerify::ext::RequestExt;
+use crate::{db::{ClickExport, ExportFormat, ExportRecord, ExportRows, ExportWriter, Message, StatsQuery}, server::{State, routes::api::tenant}};
+use tokio::sync::mpsc::Receiver;
+
+pub async fn get_top_links(req: Request<Body>) -> Result<Response<Body>> {
+    let query = parse_failed_json!(StatsQuery::parse(req.uri().query()));
+    let state = req.data::<State>().unwrap();
+    let sender = state.db_sender();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    sender_failed_json!(
+        sender
+        .send(Message::GetTopLinks { tenant: tenant(&req), query, resp: tx })
+        .await, "GetTopLinks");
+    let links = recv_failed_json!(rx.await.unwrap(), hyper::StatusCode::INTERNAL_SERVER_ERROR);
+    Ok(json_response!(body: &links))
+}
+
+pub async fn export_clicks(req: Request<Body>) -> Result<Response<Body>> {
+    let export = parse_failed_json!(ClickExport::parse(req.uri().query()));
+    let format = export.format;
+    let name = export.name();
+    let state = req.data::<State>().unwrap();
+    let sender = state.db_sender();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    sender_failed_json!(
+        sender
+        .send(Message::ExportClicks { tenant: tenant(&req), export, resp: tx })
+        .await, "ExportClicks");
+    let rows = recv_failed_json!(rx.await.unwrap(), hyper::StatusCode::INTERNAL_SERVER_ERROR);
+
+    let (body_tx, body) = Body::channel();
+    match rows {
+        ExportRows::Clicks(rows) => tokio::spawn(stream_export(rows, format, body_tx)),
+        ExportRows::Rollups(rows) => tokio::spawn(stream_export(rows, format, body_tx)),
+    };
+    Ok(Response::builder()
+       .header(hyper::header::CONTENT_TYPE, format.content_type())
+       .header(hyper::header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}.{}\"", name, format.extension()))
+       .body(body)
+       .unwrap())
+}
+
+async fn stream_export<R: ExportRecord>(
+    mut rows: Receiver<Result<R, sqlx::Error>>,
+    format: ExportFormat,
+    mut body_tx: hyper::body::Sender,
+) {
+    let mut writer = match ExportWriter::<R>::new(format) {
+        Ok(writer) => writer,
+        Err(e) => {
+            tracing::error!("Failed to start the export, error: {}", e);
+            body_tx.abort();
+            return;
+        }
+    };
+    while let Some(row) = rows.recv().await {
+        let bytes = match row.map_err(anyhow::Error::from).and_then(|row| writer.write(row)) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                // Abort so the client sees a broken transfer instead of a
+                // truncated file that looks complete.
+                tracing::error!("Failed to export clicks, error: {}", e);
+                body_tx.abort();
+                return;
+            }
+        };
+        if !bytes.is_empty() && body_tx.send_data(bytes.into()).await.is_err() {
+            return;
+        }
+    }
+    match writer.finish() {
+        Ok(bytes) => {
+            let _ = body_tx.send_data(bytes.into()).await;
+        }
+        Err(e) => {
+            tracing::error!("Failed to export clicks, error: {}", e);
+            body_tx.abort();
+        }
+    }
+}
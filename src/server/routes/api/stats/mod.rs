@@ -0,0 +1,13 @@
+use anyhow::Error;
+use hyper::Body;
+use routerify::Router;
+
+mod handlers;
+
+pub fn router() -> Router<Body, Error> {
+    Router::builder()
+        .get("/top", handlers::get_top_links)
+        .get("/export", handlers::export_clicks)
+        .build()
+        .unwrap()
+}
@@ -0,0 +1,135 @@
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+use hyper::{Body, Request, Response, body::to_bytes};
+use routerify::ext::RequestExt;
+use crate::{db::{Message, Tenant, is_foreign_key_violation}, server::{State, routes::api::{actor, operator, tenant}}};
+
+// Tokens of a tenant are managed by the operator, or with a token of that
+// same tenant.
+fn manages(req: &Request<Body>, name: &str) -> Result<()> {
+    match tenant(req) == name {
+        true => Ok(()),
+        false => operator(req),
+    }
+}
+
+pub async fn get_tenants(req: Request<Body>) -> Result<Response<Body>> {
+    operator(&req)?;
+    let state = req.data::<State>().unwrap();
+    let sender = state.db_sender();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    sender_failed_json!(
+        sender
+        .send(Message::GetTenants { resp: tx })
+        .await, "GetTenants");
+    let tenants = recv_failed_json!(rx.await.unwrap(), hyper::StatusCode::INTERNAL_SERVER_ERROR);
+    Ok(json_response!(body: &tenants))
+}
+
+pub async fn create_tenant(mut req: Request<Body>) -> Result<Response<Body>> {
+    #[derive(Debug, Serialize, Deserialize)]
+    struct NewTenant {
+        name: String,
+    }
+
+    operator(&req)?;
+    let body = to_bytes(req.body_mut()).await?;
+    let tenant = parse_failed_json!(serde_json::from_slice::<NewTenant>(&body));
+    let name = parse_failed_json!(Tenant::normalize(&tenant.name));
+    let state = req.data::<State>().unwrap();
+    let sender = state.db_sender();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    sender_failed_json!(
+        sender
+        .send(Message::CreateTenant { name, actor: actor(&req), resp: tx })
+        .await, "CreateTenant");
+    let tenant = recv_failed_json!(rx.await.unwrap(), hyper::StatusCode::UNPROCESSABLE_ENTITY);
+    Ok(json_response!(body: &tenant))
+}
+
+pub async fn delete_tenant(req: Request<Body>) -> Result<Response<Body>> {
+    operator(&req)?;
+    let name = req.param("name").unwrap();
+    let state = req.data::<State>().unwrap();
+    let sender = state.db_sender();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    sender_failed_json!(
+        sender
+        .send(Message::DeleteTenant { name: name.to_lowercase(), resp: tx })
+        .await, "DeleteTenant");
+    let tenant = match rx.await.unwrap() {
+        Ok(tenant) => tenant,
+        Err(e) if is_foreign_key_violation(&e) => {
+            return Ok(json_response!(
+                    status: hyper::StatusCode::CONFLICT,
+                    body: &serde_json::json!({
+                        "error": "Tenant still has url maps, domains or api tokens, remove them first",
+                    })));
+        }
+        Err(e) => {
+            tracing::error!("Database Manager returned error: {}", e);
+            return Ok(json_response!(
+                    status: hyper::StatusCode::NOT_FOUND,
+                    body: &e.to_string()));
+        }
+    };
+    Ok(json_response!(body: &tenant))
+}
+
+pub async fn get_api_tokens(req: Request<Body>) -> Result<Response<Body>> {
+    let name = req.param("name").unwrap().to_lowercase();
+    manages(&req, &name)?;
+    let state = req.data::<State>().unwrap();
+    let sender = state.db_sender();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    sender_failed_json!(
+        sender
+        .send(Message::GetApiTokens { tenant: name, resp: tx })
+        .await, "GetApiTokens");
+    let api_tokens = recv_failed_json!(rx.await.unwrap(), hyper::StatusCode::INTERNAL_SERVER_ERROR);
+    Ok(json_response!(body: &api_tokens))
+}
+
+pub async fn create_api_token(mut req: Request<Body>) -> Result<Response<Body>> {
+    #[derive(Debug, Serialize, Deserialize)]
+    struct NewToken {
+        name: String,
+    }
+
+    let name = req.param("name").unwrap().to_lowercase();
+    manages(&req, &name)?;
+    let body = to_bytes(req.body_mut()).await?;
+    let token = parse_failed_json!(serde_json::from_slice::<NewToken>(&body));
+    let token_name = token.name.trim().to_string();
+    if token_name.is_empty() {
+        return Ok(json_response!(
+                status: hyper::StatusCode::BAD_REQUEST,
+                body: &serde_json::json!({
+                    "error": "token name must not be empty",
+                })));
+    }
+    let state = req.data::<State>().unwrap();
+    let sender = state.db_sender();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    sender_failed_json!(
+        sender
+        .send(Message::CreateApiToken { tenant: name, name: token_name, actor: actor(&req), resp: tx })
+        .await, "CreateApiToken");
+    let api_token = recv_failed_json!(rx.await.unwrap(), hyper::StatusCode::UNPROCESSABLE_ENTITY);
+    Ok(json_response!(body: &api_token))
+}
+
+pub async fn delete_api_token(req: Request<Body>) -> Result<Response<Body>> {
+    let name = req.param("name").unwrap().to_lowercase();
+    manages(&req, &name)?;
+    let id = parse_failed_json!(req.param("id").unwrap().parse::<i64>());
+    let state = req.data::<State>().unwrap();
+    let sender = state.db_sender();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    sender_failed_json!(
+        sender
+        .send(Message::DeleteApiToken { tenant: name, id, resp: tx })
+        .await, "DeleteApiToken");
+    let api_token = recv_failed_json!(rx.await.unwrap(), hyper::StatusCode::NOT_FOUND);
+    Ok(json_response!(body: &api_token))
+}
@@ -0,0 +1,17 @@
+use anyhow::Error;
+use hyper::Body;
+use routerify::Router;
+
+mod handlers;
+
+pub fn router() -> Router<Body, Error> {
+    Router::builder()
+        .get("/", handlers::get_tenants)
+        .post("/", handlers::create_tenant)
+        .delete("/:name", handlers::delete_tenant)
+        .get("/:name/tokens", handlers::get_api_tokens)
+        .post("/:name/tokens", handlers::create_api_token)
+        .delete("/:name/tokens/:id", handlers::delete_api_token)
+        .build()
+        .unwrap()
+}
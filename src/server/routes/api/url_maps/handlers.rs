@@ -1,29 +1,256 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
 use serde::{Serialize, Deserialize};
 use hyper::{Body, Request, Response, body::to_bytes};
 use routerify::ext::RequestExt;
-use crate::{db::{UrlMap, Message}, server::State};
+use crate::{db::{BatchRequest, ExportedUrlMap, ImportMode, ImportOptions, NewUrlMap, StatsQuery, UrlMap, UrlMapQuery, Message}, server::{State, routes::{wildcard, api::{actor, domain, tenant}}}};
 
 pub async fn get_url_maps(req: Request<Body>) -> Result<Response<Body>> {
+    let query = parse_failed_json!(UrlMapQuery::parse(req.uri().query()));
     let (tx, rx) = tokio::sync::oneshot::channel();
     let state = req.data::<State>().unwrap();
     let sender = state.db_sender();
     sender_failed_json!(
         sender
-        .send(Message::GetUrlMaps { resp: tx })
+        .send(Message::GetUrlMaps { tenant: tenant(&req), query, resp: tx })
         .await, "GetUrlMaps");
     let url_maps = recv_failed_json!(rx.await.unwrap(), hyper::StatusCode::INTERNAL_SERVER_ERROR);
     Ok(json_response!(body: &url_maps))
 }
 
+pub async fn get_trashed_url_maps(req: Request<Body>) -> Result<Response<Body>> {
+    let mut query = parse_failed_json!(UrlMapQuery::parse(req.uri().query()));
+    query.trashed = true;
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let state = req.data::<State>().unwrap();
+    let sender = state.db_sender();
+    sender_failed_json!(
+        sender
+        .send(Message::GetUrlMaps { tenant: tenant(&req), query, resp: tx })
+        .await, "GetUrlMaps");
+    let url_maps = recv_failed_json!(rx.await.unwrap(), hyper::StatusCode::INTERNAL_SERVER_ERROR);
+    Ok(json_response!(body: &url_maps))
+}
+
+pub async fn restore_url_map(req: Request<Body>) -> Result<Response<Body>> {
+    let key = wildcard(&req);
+    let domain = parse_failed_json!(domain(&req));
+    let state = req.data::<State>().unwrap();
+    let sender = state.db_sender();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    sender_failed_json!(
+        sender
+        .send(Message::RestoreUrlMap { tenant: tenant(&req), domain, key, actor: actor(&req), resp: tx })
+        .await, "RestoreUrlMap");
+    let url_map = recv_failed_json!(rx.await.unwrap(), hyper::StatusCode::NOT_FOUND);
+    Ok(json_response!(body: &url_map))
+}
+
+pub async fn purge_url_map(req: Request<Body>) -> Result<Response<Body>> {
+    let key = wildcard(&req);
+    let domain = parse_failed_json!(domain(&req));
+    let state = req.data::<State>().unwrap();
+    let sender = state.db_sender();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    sender_failed_json!(
+        sender
+        .send(Message::PurgeUrlMap { tenant: tenant(&req), domain, key, actor: actor(&req), resp: tx })
+        .await, "PurgeUrlMap");
+    let url_map = recv_failed_json!(rx.await.unwrap(), hyper::StatusCode::NOT_FOUND);
+    Ok(json_response!(body: &url_map))
+}
+
+pub async fn export_url_maps(req: Request<Body>) -> Result<Response<Body>> {
+    #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    enum Format {
+        Ndjson,
+        Csv,
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct ExportQuery {
+        format: Option<Format>,
+    }
+
+    let query = parse_failed_json!(serde_urlencoded::from_str::<ExportQuery>(req.uri().query().unwrap_or("")));
+    let format = query.format.unwrap_or(Format::Ndjson);
+    let state = req.data::<State>().unwrap();
+    let sender = state.db_sender();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    sender_failed_json!(
+        sender
+        .send(Message::ExportUrlMaps { tenant: tenant(&req), resp: tx })
+        .await, "ExportUrlMaps");
+    let mut url_maps = recv_failed_json!(rx.await.unwrap(), hyper::StatusCode::INTERNAL_SERVER_ERROR);
+
+    let (mut body_tx, body) = Body::channel();
+    tokio::spawn(async move {
+        if let Format::Csv = format {
+            let mut writer = csv::Writer::from_writer(vec![]);
+            writer.write_record(ExportedUrlMap::HEADERS).unwrap();
+            if body_tx.send_data(writer.into_inner().unwrap().into()).await.is_err() {
+                return;
+            }
+        }
+        while let Some(url_map) = url_maps.recv().await {
+            let url_map = match url_map {
+                Ok(url_map) => url_map,
+                Err(e) => {
+                    // Abort so the client sees a broken transfer instead of a
+                    // truncated file that looks complete.
+                    tracing::error!("Database Manager returned error: {}", e);
+                    body_tx.abort();
+                    return;
+                }
+            };
+            let line = match format {
+                Format::Ndjson => {
+                    let mut line = serde_json::to_vec(&url_map).unwrap();
+                    line.push(b'\n');
+                    line
+                }
+                Format::Csv => {
+                    let mut writer = csv::WriterBuilder::new()
+                        .has_headers(false)
+                        .from_writer(vec![]);
+                    writer.serialize(ExportedUrlMap::from(url_map)).unwrap();
+                    writer.into_inner().unwrap()
+                }
+            };
+            if body_tx.send_data(line.into()).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    let (content_type, extension) = match format {
+        Format::Ndjson => ("application/x-ndjson", "ndjson"),
+        Format::Csv => ("text/csv", "csv"),
+    };
+    Ok(Response::builder()
+       .header(hyper::header::CONTENT_TYPE, content_type)
+       .header(hyper::header::CONTENT_DISPOSITION, format!("attachment; filename=\"url_maps.{}\"", extension))
+       .body(body)
+       .unwrap())
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ImportFormat {
+    Csv,
+    Json,
+    Ndjson,
+}
+
+impl ImportFormat {
+    fn from_content_type(content_type: &str) -> Option<Self> {
+        match content_type.split(';').next().unwrap_or("").trim() {
+            "text/csv" => Some(Self::Csv),
+            "application/json" => Some(Self::Json),
+            "application/x-ndjson" => Some(Self::Ndjson),
+            _ => None,
+        }
+    }
+
+    // Only errors that make the whole document unreadable are returned as
+    // `Err`, a malformed record becomes a rejected row in the report.
+    fn parse(&self, bytes: &[u8]) -> Result<Vec<Result<UrlMap, String>>> {
+        let rows = match self {
+            Self::Csv => csv::Reader::from_reader(bytes)
+                .deserialize::<UrlMap>()
+                .map(|row| row.map_err(|e| e.to_string()))
+                .collect(),
+            Self::Json => serde_json::from_slice::<Vec<serde_json::Value>>(bytes)?
+                .into_iter()
+                .map(|row| serde_json::from_value::<UrlMap>(row).map_err(|e| e.to_string()))
+                .collect(),
+            Self::Ndjson => std::str::from_utf8(bytes)?
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(|line| serde_json::from_str::<UrlMap>(line).map_err(|e| e.to_string()))
+                .collect(),
+        };
+        Ok(rows)
+    }
+}
+
+pub async fn import_url_maps(mut req: Request<Body>) -> Result<Response<Body>> {
+    #[derive(Debug, Serialize, Deserialize)]
+    struct ImportQuery {
+        format: Option<ImportFormat>,
+        mode: Option<ImportMode>,
+        #[serde(default)]
+        dry_run: bool,
+    }
+
+    let query = parse_failed_json!(serde_urlencoded::from_str::<ImportQuery>(req.uri().query().unwrap_or("")));
+    let format = query.format.or_else(|| {
+        req.headers()
+            .get(hyper::header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .and_then(ImportFormat::from_content_type)
+    });
+    let format = parse_failed_json!(format.ok_or_else(|| anyhow!("Unknown import format, use format=csv|json|ndjson")));
+    let body = to_bytes(req.body_mut()).await?;
+    let rows = parse_failed_json!(format.parse(&body));
+    let options = ImportOptions {
+        mode: query.mode.unwrap_or(ImportMode::Skip),
+        dry_run: query.dry_run,
+    };
+
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let state = req.data::<State>().unwrap();
+    let sender = state.db_sender();
+    sender_failed_json!(
+        sender
+        .send(Message::ImportUrlMaps { tenant: tenant(&req), rows, options, actor: actor(&req), resp: tx })
+        .await, "ImportUrlMaps");
+    let report = recv_failed_json!(rx.await.unwrap(), hyper::StatusCode::INTERNAL_SERVER_ERROR);
+    let status = match report.applied || report.dry_run {
+        true => hyper::StatusCode::OK,
+        false => hyper::StatusCode::CONFLICT,
+    };
+    Ok(json_response!(status: status, body: &report))
+}
+
+pub async fn apply_batch(mut req: Request<Body>) -> Result<Response<Body>> {
+    const MAX_OPERATIONS: usize = 1000;
+
+    let body = to_bytes(req.body_mut()).await?;
+    let request = parse_failed_json!(serde_json::from_slice::<BatchRequest>(&body));
+    if request.operations.len() > MAX_OPERATIONS {
+        return Ok(json_response!(
+                status: hyper::StatusCode::BAD_REQUEST,
+                body: &serde_json::json!({
+                    "error": format!("A batch can have at most {} operations", MAX_OPERATIONS),
+                })));
+    }
+
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let state = req.data::<State>().unwrap();
+    let sender = state.db_sender();
+    sender_failed_json!(
+        sender
+        .send(Message::ApplyBatch { tenant: tenant(&req), request, actor: actor(&req), resp: tx })
+        .await, "ApplyBatch");
+    let report = recv_failed_json!(rx.await.unwrap(), hyper::StatusCode::INTERNAL_SERVER_ERROR);
+    let status = match report.committed {
+        true => hyper::StatusCode::OK,
+        false => hyper::StatusCode::CONFLICT,
+    };
+    Ok(json_response!(status: status, body: &report))
+}
+
 pub async fn get_url_map(req: Request<Body>) -> Result<Response<Body>> {
     let state = req.data::<State>().unwrap();
     let sender = state.db_sender();
     let (tx, rx) = tokio::sync::oneshot::channel();
-    let key = req.param("key").unwrap();
+    let key = wildcard(&req);
+    let domain = parse_failed_json!(domain(&req));
     sender_failed_json!(
         sender
-        .send(Message::GetUrlMap { key: key.into(), resp: tx })
+        .send(Message::GetUrlMap { tenant: tenant(&req), domain, key, resp: tx })
         .await, "GetUrlMap");
     let url_map = recv_failed_json!(rx.await.unwrap(), hyper::StatusCode::NOT_FOUND);
     Ok(json_response!(body: &url_map))
@@ -32,15 +259,27 @@ pub async fn get_url_map(req: Request<Body>) -> Result<Response<Body>> {
 pub async fn create_url_map(mut req: Request<Body>) -> Result<Response<Body>> {
     let body = req.body_mut();
     let url_map_bytes = to_bytes(body).await?;
-    let url_map = serde_json::from_slice::<UrlMap>(&url_map_bytes)?;
+    let mut url_map = serde_json::from_slice::<NewUrlMap>(&url_map_bytes)?;
+    url_map.key = url_map.key.filter(|key| !key.is_empty());
+    parse_failed_json!(url_map.validate());
     let (tx, rx) = tokio::sync::oneshot::channel();
     let state = req.data::<State>().unwrap();
     let sender = state.db_sender();
     sender_failed_json!(
         sender
-        .send(Message::CreateUrlMap { url_map, resp: tx })
+        .send(Message::CreateUrlMap { tenant: tenant(&req), url_map, actor: actor(&req), resp: tx })
         .await, "CreateUrlMap");
-    let url_map = recv_failed_json!(rx.await.unwrap(), hyper::StatusCode::UNPROCESSABLE_ENTITY);
+    let url_map = match rx.await.unwrap() {
+        // Nothing is inserted on a domain the tenant cannot use.
+        Err(sqlx::Error::RowNotFound) => {
+            return Ok(json_response!(
+                    status: hyper::StatusCode::UNPROCESSABLE_ENTITY,
+                    body: &serde_json::json!({
+                        "error": "domain does not exist",
+                    })));
+        }
+        url_map => recv_failed_json!(url_map, hyper::StatusCode::UNPROCESSABLE_ENTITY),
+    };
     Ok(json_response!(body: &url_map))
 }
 
@@ -48,35 +287,123 @@ pub async fn update_url_map(mut req: Request<Body>) -> Result<Response<Body>> {
     #[derive(Debug, Serialize, Deserialize)]
     struct UrlMapUrl {
         url: String,
+        #[serde(default)]
+        expires_at: Option<DateTime<Utc>>,
+        #[serde(default)]
+        not_before: Option<DateTime<Utc>>,
+        #[serde(default)]
+        max_clicks: Option<i64>,
+        #[serde(default)]
+        prefix: bool,
     }
 
     let body = req.body_mut();
     let url_map_url_bytes = to_bytes(body).await?;
     let url_map_url = serde_json::from_slice::<UrlMapUrl>(&url_map_url_bytes)?;
-    let key = req.param("key").unwrap();
-    let url_map = UrlMap::new(key.into(), url_map_url.url);
+    let key = wildcard(&req);
+    let domain = parse_failed_json!(domain(&req));
+    let url_map = UrlMap {
+        expires_at: url_map_url.expires_at,
+        not_before: url_map_url.not_before,
+        max_clicks: url_map_url.max_clicks,
+        prefix: url_map_url.prefix,
+        domain,
+        ..UrlMap::new(key, url_map_url.url)
+    };
+    parse_failed_json!(url_map.validate());
     let (tx, rx) = tokio::sync::oneshot::channel();
     let state = req.data::<State>().unwrap();
     let sender = state.db_sender();
     sender_failed_json!(
         sender
-        .send(Message::UpdateUrlMap { url_map, resp: tx })
+        .send(Message::UpdateUrlMap { tenant: tenant(&req), url_map, actor: actor(&req), resp: tx })
         .await, "UpdateUrlMap");
     let url_map = recv_failed_json!(rx.await.unwrap(), hyper::StatusCode::UNPROCESSABLE_ENTITY);
     Ok(json_response!(body: &url_map))
 }
 
 pub async fn delete_url_map(req: Request<Body>) -> Result<Response<Body>> {
-    let key = req.param("key").unwrap();
+    let key = wildcard(&req);
+    let domain = parse_failed_json!(domain(&req));
     let state = req.data::<State>().unwrap();
     let sender = state.db_sender();
     let (tx, rx) = tokio::sync::oneshot::channel();
     sender_failed_json!(
         sender
-        .send(Message::DeleteUrlMap { key: key.into(), resp: tx })
+        .send(Message::DeleteUrlMap { tenant: tenant(&req), domain, key, actor: actor(&req), resp: tx })
         .await, "DeleteUrlMap");
     recv_failed_json!(rx.await.unwrap(), hyper::StatusCode::NOT_FOUND);
     Ok(json_response!(body: &serde_json::json!({
         "ok": "true"
     }).to_string()))
 }
+
+pub async fn get_url_map_history(req: Request<Body>) -> Result<Response<Body>> {
+    let state = req.data::<State>().unwrap();
+    let sender = state.db_sender();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let key = wildcard(&req);
+    let domain = parse_failed_json!(domain(&req));
+    sender_failed_json!(
+        sender
+        .send(Message::GetUrlMapHistory { tenant: tenant(&req), domain, key, resp: tx })
+        .await, "GetUrlMapHistory");
+    let history = recv_failed_json!(rx.await.unwrap(), hyper::StatusCode::INTERNAL_SERVER_ERROR);
+    Ok(json_response!(body: &history))
+}
+
+pub async fn get_url_map_version(req: Request<Body>) -> Result<Response<Body>> {
+    #[derive(Debug, Serialize, Deserialize)]
+    struct VersionQuery {
+        at: DateTime<Utc>,
+    }
+
+    let query = parse_failed_json!(serde_urlencoded::from_str::<VersionQuery>(req.uri().query().unwrap_or("")));
+    let state = req.data::<State>().unwrap();
+    let sender = state.db_sender();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let key = wildcard(&req);
+    let domain = parse_failed_json!(domain(&req));
+    sender_failed_json!(
+        sender
+        .send(Message::GetUrlMapVersion { tenant: tenant(&req), domain, key, at: query.at, resp: tx })
+        .await, "GetUrlMapVersion");
+    let change = recv_failed_json!(rx.await.unwrap(), hyper::StatusCode::NOT_FOUND);
+    Ok(json_response!(body: &change))
+}
+
+pub async fn revert_url_map(mut req: Request<Body>) -> Result<Response<Body>> {
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Revert {
+        version: i64,
+    }
+
+    let body = to_bytes(req.body_mut()).await?;
+    let revert = parse_failed_json!(serde_json::from_slice::<Revert>(&body));
+    let state = req.data::<State>().unwrap();
+    let sender = state.db_sender();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let key = wildcard(&req);
+    let domain = parse_failed_json!(domain(&req));
+    sender_failed_json!(
+        sender
+        .send(Message::RevertUrlMap { tenant: tenant(&req), domain, key, version: revert.version, actor: actor(&req), resp: tx })
+        .await, "RevertUrlMap");
+    let url_map = recv_failed_json!(rx.await.unwrap(), hyper::StatusCode::NOT_FOUND);
+    Ok(json_response!(body: &url_map))
+}
+
+pub async fn get_url_map_stats(req: Request<Body>) -> Result<Response<Body>> {
+    let query = parse_failed_json!(StatsQuery::parse(req.uri().query()));
+    let state = req.data::<State>().unwrap();
+    let sender = state.db_sender();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let key = wildcard(&req);
+    let domain = parse_failed_json!(domain(&req));
+    sender_failed_json!(
+        sender
+        .send(Message::GetUrlMapStats { tenant: tenant(&req), domain, key, query, resp: tx })
+        .await, "GetUrlMapStats");
+    let stats = recv_failed_json!(rx.await.unwrap(), hyper::StatusCode::NOT_FOUND);
+    Ok(json_response!(body: &stats))
+}
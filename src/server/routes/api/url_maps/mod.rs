@@ -8,9 +8,21 @@ pub fn router() -> Router<Body, Error> {
     Router::builder()
         .get("/", handlers::get_url_maps)
         .post("/", handlers::create_url_map)
-        .get("/:key", handlers::get_url_map)
-        .put("/:key", handlers::update_url_map)
-        .delete("/:key", handlers::delete_url_map)
+        .get("/export", handlers::export_url_maps)
+        .post("/import", handlers::import_url_maps)
+        .post("/batch", handlers::apply_batch)
+        .get("/trash", handlers::get_trashed_url_maps)
+        // Keys may contain `/`, so the routes ending in a fixed segment come
+        // before the ones that take the rest of the path as the key.
+        .post("/trash/*/restore", handlers::restore_url_map)
+        .delete("/trash/*", handlers::purge_url_map)
+        .get("/*/history", handlers::get_url_map_history)
+        .get("/*/version", handlers::get_url_map_version)
+        .post("/*/revert", handlers::revert_url_map)
+        .get("/*/stats", handlers::get_url_map_stats)
+        .get("/*", handlers::get_url_map)
+        .put("/*", handlers::update_url_map)
+        .delete("/*", handlers::delete_url_map)
         .build()
         .unwrap()
 }
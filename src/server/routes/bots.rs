@@ -0,0 +1,174 @@
+use hyper::{Body, Request, header};
+
+// Lowercase fragments of the user agents sent by crawlers and link unfurlers,
+// which fetch links to preview or index them. Each is specific to one of
+// them, except for `+http`, the link to a description of the crawler that
+// crawlers conventionally put in their user agent and browsers never do.
+const PREVIEW_AGENTS: &[&str] = &[
+    "+http",
+    "googlebot",
+    "bingbot",
+    "yandexbot",
+    "baiduspider",
+    "duckduckbot",
+    "applebot",
+    "ahrefsbot",
+    "semrushbot",
+    "petalbot",
+    "slurp",
+    "twitterbot",
+    "slackbot",
+    "slack-imgproxy",
+    "discordbot",
+    "linkedinbot",
+    "telegrambot",
+    "redditbot",
+    "facebookexternalhit",
+    "facebookcatalog",
+    "whatsapp/",
+    "embedly",
+    "quora link preview",
+    "skypeuripreview",
+    "vkshare",
+    "pinterestbot",
+];
+
+// Lowercase fragments of the user agents sent by headless browsers,
+// monitoring services and scripted clients, which are automated but fetch
+// links on someone's behalf.
+const SCRIPTED_AGENTS: &[&str] = &[
+    "headlesschrome",
+    "phantomjs",
+    "chrome-lighthouse",
+    "pingdom",
+    "uptimerobot",
+    "curl/",
+    "wget/",
+    "python-requests",
+    "python-urllib",
+    "go-http-client",
+    "okhttp",
+    "java/",
+    "libwww-perl",
+    "apache-httpclient",
+    "axios/",
+    "node-fetch",
+];
+
+// Headers browsers send on speculative requests that are not real clicks.
+const PREFETCH_HEADERS: &[&str] = &["purpose", "sec-purpose", "x-purpose", "x-moz"];
+
+fn user_agent(req: &Request<Body>) -> Option<String> {
+    req.headers()
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .filter(|user_agent| !user_agent.trim().is_empty())
+        .map(str::to_lowercase)
+}
+
+// Whether the redirect only previews the link, for a crawler, an unfurler or
+// a browser prefetching it, rather than following it for someone.
+pub fn is_preview(req: &Request<Body>) -> bool {
+    if let Some(user_agent) = user_agent(req) {
+        if PREVIEW_AGENTS.iter().any(|agent| user_agent.contains(agent)) {
+            return true;
+        }
+    }
+    PREFETCH_HEADERS.iter()
+        .filter_map(|name| req.headers().get(*name))
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| {
+            let value = value.to_lowercase();
+            value.contains("prefetch") || value.contains("preview")
+        })
+}
+
+// Best effort classification of a redirect as automated, from its user agent
+// and the headers browsers send on prefetches.
+pub fn is_bot(req: &Request<Body>) -> bool {
+    match user_agent(req) {
+        Some(user_agent) => SCRIPTED_AGENTS.iter().any(|agent| user_agent.contains(agent)) || is_preview(req),
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> Request<Body> {
+        let mut request = Request::builder().uri("/gh");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    fn agent(user_agent: &str) -> bool {
+        is_bot(&request(&[("user-agent", user_agent)]))
+    }
+
+    #[test]
+    fn browsers_are_not_bots() {
+        for user_agent in [
+            "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/91.0.4472.124 Safari/537.36",
+            "Mozilla/5.0 (iPhone; CPU iPhone OS 14_6 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/14.1.1 Mobile/15E148 Safari/604.1",
+            "Mozilla/5.0 (X11; Ubuntu; Linux x86_64; rv:89.0) Gecko/20100101 Firefox/89.0",
+            "Mozilla/5.0 (Linux; Android 11; Cubot X30) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/91.0.4472.120 Mobile Safari/537.36",
+            "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/91.0 Safari/537.36 Edg/91.0 PreviewApp Monitor/2",
+        ] {
+            assert!(!agent(user_agent), "{}", user_agent);
+        }
+    }
+
+    #[test]
+    fn crawlers_unfurlers_and_scripts_are_bots() {
+        for user_agent in [
+            "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)",
+            "Slackbot-LinkExpanding 1.0 (+https://api.slack.com/robots)",
+            "Twitterbot/1.0",
+            "facebookexternalhit/1.1 (+http://www.facebook.com/externalhit_uatext.php)",
+            "WhatsApp/2.21.12.21 A",
+            "Mozilla/5.0 (compatible; Discordbot/2.0; +https://discordapp.com)",
+            "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) HeadlessChrome/91.0.4472.0 Safari/537.36",
+            "curl/7.68.0",
+            "python-requests/2.25.1",
+        ] {
+            assert!(agent(user_agent), "{}", user_agent);
+        }
+    }
+
+    #[test]
+    fn requests_without_a_user_agent_are_bots() {
+        assert!(is_bot(&request(&[])));
+        assert!(is_bot(&request(&[("user-agent", " ")])));
+    }
+
+    #[test]
+    fn prefetches_are_bots() {
+        let chrome = "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/91.0 Safari/537.36";
+        assert!(is_bot(&request(&[("user-agent", chrome), ("sec-purpose", "prefetch")])));
+        assert!(is_bot(&request(&[("user-agent", chrome), ("purpose", "preview")])));
+    }
+
+    #[test]
+    fn crawlers_unfurlers_and_prefetches_are_previews() {
+        for user_agent in [
+            "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)",
+            "Slackbot-LinkExpanding 1.0 (+https://api.slack.com/robots)",
+            "Twitterbot/1.0",
+            "WhatsApp/2.21.12.21 A",
+        ] {
+            assert!(is_preview(&request(&[("user-agent", user_agent)])), "{}", user_agent);
+        }
+        assert!(is_preview(&request(&[("sec-purpose", "prefetch;prerender")])));
+    }
+
+    #[test]
+    fn scripts_and_requests_without_a_user_agent_are_not_previews() {
+        for user_agent in ["curl/8.0.1", "Wget/1.21", "python-requests/2.25.1", "okhttp/4.9.0", "Go-http-client/1.1"] {
+            assert!(!is_preview(&request(&[("user-agent", user_agent)])), "{}", user_agent);
+        }
+        assert!(!is_preview(&request(&[])));
+    }
+}
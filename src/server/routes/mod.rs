@@ -1,4 +1,6 @@
-use crate::{db::Message, server::State};
+use crate::{config::CONFIG, db::{Click, Domain, Message}, server::State};
+use chrono::Utc;
+use tera::Context;
 use hyper::{
     Body,
     Request,
@@ -12,11 +14,12 @@ use routerify::{
     ext::RequestExt,
     RequestInfo
 };
-use anyhow::{Error, Result};
+use anyhow::{anyhow, Error, Result};
 use tracing::{info, error};
 
 mod api;
 mod admin;
+mod bots;
 
 async fn logger(req: Request<Body>) -> Result<Request<Body>> {
     info!("{} {} {}", req.remote_addr(), req.method(), req.uri().path());
@@ -36,32 +39,178 @@ async fn home_handler(_: Request<Body>) -> Result<Response<Body>> {
     Ok(Response::new(Body::from("Url Mapper in Rust!")))
 }
 
-async fn error_handler(err: routerify::RouteError, _: RequestInfo) -> Response<Body> {
+async fn error_handler(err: routerify::RouteError, info: RequestInfo) -> Response<Body> {
     error!("{}", err);
     let status = match err.to_string().as_str() {
         "Unauthorized Access" => hyper::StatusCode::UNAUTHORIZED,
+        "Forbidden" => hyper::StatusCode::FORBIDDEN,
         _ => hyper::StatusCode::INTERNAL_SERVER_ERROR,
     };
+    // Admin pages are opened in the browser, which is sent to enter a token.
+    if status == hyper::StatusCode::UNAUTHORIZED && info.uri().path().starts_with("/admin/") {
+        return Response::builder()
+            .header(hyper::header::LOCATION, "/admin/login")
+            .status(hyper::StatusCode::SEE_OTHER)
+            .body(Body::empty())
+            .unwrap();
+    }
     Response::builder()
         .status(status)
         .body(Body::from(format!("Something went wrong: {}", err)))
         .unwrap()
 }
 
+fn header(req: &Request<Body>, name: hyper::header::HeaderName) -> Option<String> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+}
+
+// Key, or path below a prefix key, matched by a `*` route. Routerify matches
+// against the path with a `/` appended, which ends up in the parameter.
+pub fn wildcard(req: &Request<Body>) -> String {
+    let path = req.param("*").unwrap();
+    path.strip_suffix('/').unwrap_or(path).into()
+}
+
+// `utm_source` given on the short link itself, as in `/key?utm_source=mail`.
+fn utm_source(req: &Request<Body>) -> Option<String> {
+    serde_urlencoded::from_str::<Vec<(String, String)>>(req.uri().query()?)
+        .ok()?
+        .into_iter()
+        .find(|(name, value)| name == "utm_source" && !value.is_empty())
+        .map(|(_, value)| value)
+}
+
+// Registered domain the request was made to, the default domain for any other
+// host. HTTP/2 requests carry the host in the uri instead of a `Host` header.
+async fn request_domain(req: &Request<Body>, state: &State) -> Result<String> {
+    let host = match req.headers().get(hyper::header::HOST) {
+        Some(host) => Domain::host(host.to_str()?),
+        None => Domain::host(req.uri().host().unwrap_or("")),
+    };
+    let cache = state.cache();
+    let registered = match cache.domains() {
+        Some(domains) => domains.contains(&host),
+        None => {
+            let generation = cache.generation();
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            state.db_sender()
+                .send(Message::ResolveDomains { resp: tx })
+                .await
+                .map_err(|e| anyhow!("Database Manager failed to get ResolveDomains! error: {}", e))?;
+            let names = rx.await??.into_iter().map(|domain| domain.name).collect::<std::collections::HashSet<_>>();
+            let registered = names.contains(&host);
+            cache.insert_domains(generation, names);
+            registered
+        }
+    };
+    Ok(if registered { host } else { String::new() })
+}
+
 async fn redirect_handler(req: Request<Body>) -> Result<Response<Body>> {
     let state = req.data::<State>().unwrap();
     let sender = state.db_sender();
-    let key = req.param("key").unwrap();
-    let (tx, rx) = tokio::sync::oneshot::channel();
-    sender_failed!(
-        sender
-        .send(Message::GetUrlMap { key: key.clone(), resp: tx})
-        .await, "GetUrlMap");
-    let url_map = recv_failed!(rx.await.unwrap());
+    let cache = state.cache();
+    let path = wildcard(&req);
+    let domain = request_domain(&req, state).await?;
+    // Only exact keys are cached, any other path is resolved in the database
+    // against the prefix url maps.
+    let url_map = match cache.get(&domain, &path) {
+        Some(url_map) => url_map,
+        None => {
+            let generation = cache.generation();
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            sender_failed!(
+                sender
+                .send(Message::ResolveUrlMap { domain: domain.clone(), path: path.clone(), resp: tx})
+                .await, "ResolveUrlMap");
+            let url_map = recv_failed!(rx.await.unwrap());
+            cache.insert(generation, url_map.clone());
+            url_map
+        }
+    };
+    let now = Utc::now();
+    if url_map.is_pending(now) {
+        return Ok(Response::builder()
+           .status(hyper::StatusCode::NOT_FOUND)
+           .body(Body::from("Key does not exist"))
+           .unwrap());
+    }
+    if url_map.is_expired(now) {
+        return Ok(match &CONFIG.expiry.fallback_url {
+            Some(fallback_url) => Response::builder()
+                .header(hyper::header::LOCATION, fallback_url.clone())
+                .status(hyper::StatusCode::SEE_OTHER)
+                .body(Body::from(format!("redirecting to url: {}", fallback_url)))
+                .unwrap(),
+            None => Response::builder()
+                .status(hyper::StatusCode::GONE)
+                .body(Body::from("Link has expired"))
+                .unwrap(),
+        });
+    }
+    // Click limited url maps are counted in the database on every redirect,
+    // whether or not they are cached. Previews, such as chat apps unfurling a
+    // pasted link, would use them up before anyone clicked, so they are
+    // redirected without counting for as long as clicks are left.
+    let url_map = match url_map.max_clicks {
+        None => url_map,
+        Some(max_clicks) => {
+            let followed = match bots::is_preview(&req) {
+                true => {
+                    let (tx, rx) = tokio::sync::oneshot::channel();
+                    sender_failed!(
+                        sender
+                        .send(Message::ResolveUrlMap { domain: domain.clone(), path: path.clone(), resp: tx })
+                        .await, "ResolveUrlMap");
+                    Some(recv_failed!(rx.await.unwrap())).filter(|url_map| !url_map.is_used_up())
+                }
+                false => {
+                    let (tx, rx) = tokio::sync::oneshot::channel();
+                    sender_failed!(
+                        sender
+                        .send(Message::UseClick { domain: domain.clone(), key: url_map.key.clone(), resp: tx })
+                        .await, "UseClick");
+                    recv_failed!(rx.await.unwrap())
+                }
+            };
+            match followed {
+                Some(url_map) => url_map,
+                None => {
+                    let mut context = Context::new();
+                    context.insert("key", &url_map.key);
+                    context.insert("max_clicks", &max_clicks);
+                    let exhausted_html = state.tera().render("exhausted.html", &context)?;
+                    return Ok(Response::builder()
+                       .status(hyper::StatusCode::GONE)
+                       .header(hyper::header::CONTENT_TYPE, "text/html; charset=utf-8")
+                       .body(Body::from(exhausted_html))
+                       .unwrap());
+                }
+            }
+        }
+    };
+    state.clicks().record(Click {
+        tenant: url_map.tenant.clone(),
+        domain: url_map.domain.clone(),
+        key: url_map.key.clone(),
+        clicked_at: now,
+        referrer: header(&req, hyper::header::REFERER),
+        user_agent: header(&req, hyper::header::USER_AGENT),
+        ip: Some(req.remote_addr().ip().to_string()),
+        utm_source: utm_source(&req),
+        country: None,
+        region: None,
+        is_bot: bots::is_bot(&req),
+        visitor: None,
+    });
+    let url = url_map.target(&path);
     Ok(Response::builder()
-       .header(hyper::header::LOCATION, url_map.url.clone())
+       .header(hyper::header::LOCATION, url.clone())
        .status(hyper::StatusCode::SEE_OTHER)
-       .body(Body::from(format!("redirecting to url: {}", url_map.url)))
+       .body(Body::from(format!("redirecting to url: {}", url)))
        .unwrap())
 }
 
@@ -70,8 +219,9 @@ pub fn router() -> RouterBuilder<Body, Error> {
         .middleware(Middleware::pre(logger))
         .middleware(Middleware::post(cors))
         .get("/", home_handler)
-        .get("/:key", redirect_handler)
         .scope("/api", api::router())
         .scope("/admin", admin::router())
+        // Last, so keys with a `/` never shadow the routes above.
+        .get("/*", redirect_handler)
         .err_handler_with_info(error_handler)
 }
@@ -1,27 +1,33 @@
-use hyper::Server as HyperServer;
-use crate::{db::Message, config::CONFIG};
-use routerify::RouterService;
-use anyhow::Result;
+use hyper::{Body, Server as HyperServer};
+use crate::{db::{Cache, ClickRecorder, Message}, config::CONFIG};
+use routerify::{Router, RouterService};
+use anyhow::{Error, Result};
 use tracing::info;
 use super::{State, routes};
 use tokio::sync::mpsc::Sender;
 
 pub struct Server {
     db_sender: Sender<Message>,
+    cache: Cache,
+    clicks: ClickRecorder,
 }
 
 impl Server {
-    pub fn new(db_sender: Sender<Message>) -> Self {
-        Self { db_sender }
+    pub fn new(db_sender: Sender<Message>, cache: Cache, clicks: ClickRecorder) -> Self {
+        Self { db_sender, cache, clicks }
     }
 
-    pub async fn listen(&self) -> Result<()> {
-        let state = State::new(self.db_sender.clone())?;
+    pub fn router(&self) -> Result<Router<Body, Error>> {
+        let state = State::new(self.db_sender.clone(), self.cache.clone(), self.clicks.clone())?;
         let router = routes::router()
             .data(state)
             .build()
             .unwrap();
-        let service = RouterService::new(router).unwrap();
+        Ok(router)
+    }
+
+    pub async fn listen(&self) -> Result<()> {
+        let service = RouterService::new(self.router()?).unwrap();
         let addr = format!("{}:{}", CONFIG.host, CONFIG.port)
             .parse()?;
         let server = HyperServer::bind(&addr).serve(service);
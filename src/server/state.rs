@@ -1,4 +1,4 @@
-use crate::{config::CONFIG, db::Message};
+use crate::{config::CONFIG, db::{Cache, ClickRecorder, Message}};
 use anyhow::Result;
 use tera::Tera;
 use tokio::sync::mpsc::Sender;
@@ -6,19 +6,29 @@ use tokio::sync::mpsc::Sender;
 #[derive(Debug)]
 pub struct State {
     db_sender: Sender<Message>,
+    cache: Cache,
+    clicks: ClickRecorder,
     tera: Tera,
 }
 
 impl State {
-    pub fn new(db_sender: Sender<Message>) -> Result<Self> {
+    pub fn new(db_sender: Sender<Message>, cache: Cache, clicks: ClickRecorder) -> Result<Self> {
         let tera = Tera::new("client/tera/**/*.html")?;
-        Ok(Self { db_sender, tera })
+        Ok(Self { db_sender, cache, clicks, tera })
     }
 
     pub fn db_sender(&self) -> Sender<Message> {
         self.db_sender.clone()
     }
 
+    pub fn cache(&self) -> &Cache {
+        &self.cache
+    }
+
+    pub fn clicks(&self) -> &ClickRecorder {
+        &self.clicks
+    }
+
     pub fn tera(&self) -> Tera {
         let mut tera = self.tera.clone();
         if CONFIG.env.as_str() == "development" {
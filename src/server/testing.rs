@@ -0,0 +1,52 @@
+use crate::{config::CONFIG, db::{Cache, ClickRecorder, DB, Manager}};
+use super::Server;
+use anyhow::{Error, Result};
+use hyper::Body;
+use routerify::{RequestService, RequestServiceBuilder};
+use std::net::SocketAddr;
+use tokio::{sync::oneshot, task::JoinHandle};
+
+// The full application router on top of a fresh in-memory database, with its
+// own `Manager` running in the background on the caller's runtime.
+pub struct InMemoryService {
+    builder: RequestServiceBuilder<Body, Error>,
+    stop: oneshot::Sender<()>,
+    manager: JoinHandle<()>,
+}
+
+impl InMemoryService {
+    // The returned service implements `hyper::service::Service<Request<Body>>`,
+    // so handlers can be exercised without binding a socket.
+    pub fn build(&self, remote_addr: SocketAddr) -> RequestService<Body, Error> {
+        self.builder.build(remote_addr)
+    }
+
+    // Stops the `Manager` once it has handled the messages already sent and
+    // written the clicks already recorded, then closes the database. Dropping
+    // the service stops it as well, without waiting for it.
+    pub async fn shutdown(self) -> Result<()> {
+        let _ = self.stop.send(());
+        self.manager.await?;
+        Ok(())
+    }
+}
+
+// SQLite needs the multi-threaded tokio runtime, and config and templates are
+// still loaded relative to the working directory.
+pub async fn in_memory_service() -> Result<InMemoryService> {
+    let db = DB::in_memory().await?;
+    let cache = Cache::new(&CONFIG.cache);
+    let (db_tx, db_rx) = tokio::sync::mpsc::channel(CONFIG.manager.channel_capacity);
+    let (clicks, clicks_rx) = ClickRecorder::new(&CONFIG.clicks, &CONFIG.privacy);
+    let (stop, stopped) = oneshot::channel::<()>();
+    let manager_cache = cache.clone();
+    let manager = tokio::spawn(async move {
+        let mut manager = Manager::new(db, db_rx, manager_cache, clicks_rx);
+        manager.listen_until(async {
+            let _ = stopped.await;
+        }).await;
+    });
+
+    let router = Server::new(db_tx, cache, clicks).router()?;
+    Ok(InMemoryService { builder: RequestServiceBuilder::new(router).unwrap(), stop, manager })
+}
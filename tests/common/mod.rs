@@ -0,0 +1,66 @@
+// Shared by the integration tests, each of which only uses part of it.
+#![allow(dead_code)]
+
+use hyper::{Body, Request, body::to_bytes, service::Service};
+use serde_json::Value;
+use url_mapper_rs::{config::CONFIG, server::{InMemoryService, in_memory_service}};
+
+pub struct Response {
+    pub status: u16,
+    pub location: Option<String>,
+    pub body: String,
+}
+
+impl Response {
+    pub fn json(&self) -> Value {
+        serde_json::from_str(&self.body).unwrap_or_else(|e| panic!("{}: {}", e, self.body))
+    }
+}
+
+// The full router on a fresh in-memory database, each test shuts it down once
+// done.
+pub struct App {
+    service: InMemoryService,
+}
+
+pub fn encode(token: &str) -> String {
+    base64::encode(token)
+}
+
+pub fn operator_token() -> String {
+    encode(&CONFIG.auth_token)
+}
+
+impl App {
+    pub async fn new() -> Self {
+        Self { service: in_memory_service().await.unwrap() }
+    }
+
+    pub async fn shutdown(self) {
+        self.service.shutdown().await.unwrap();
+    }
+
+    pub async fn request(&self, method: &str, uri: &str, headers: &[(&str, &str)], body: &str) -> Response {
+        let mut service = self.service.build("127.0.0.1:1234".parse().unwrap());
+        let mut request = Request::builder().method(method).uri(uri);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let response = service.call(request.body(Body::from(body.to_string())).unwrap()).await.unwrap();
+        let status = response.status().as_u16();
+        let location = response.headers()
+            .get(hyper::header::LOCATION)
+            .map(|location| location.to_str().unwrap().to_string());
+        let body = to_bytes(response.into_body()).await.unwrap();
+        Response { status, location, body: String::from_utf8_lossy(&body).into() }
+    }
+
+    // Made with the operator token, acting for the default tenant.
+    pub async fn api(&self, method: &str, uri: &str, body: &str) -> Response {
+        self.request(method, uri, &[("authorization", &operator_token()), ("x-user", "alice")], body).await
+    }
+
+    pub async fn get(&self, uri: &str) -> Response {
+        self.request("GET", uri, &[], "").await
+    }
+}
@@ -0,0 +1,77 @@
+mod common;
+
+use common::{App, encode};
+
+// Creates the `sales` tenant and returns an encoded token of it.
+async fn sales(app: &App) -> String {
+    assert_eq!(app.api("POST", "/api/tenants", r#"{"name":"sales"}"#).await.status, 200);
+    let token = app.api("POST", "/api/tenants/sales/tokens", r#"{"name":"ci"}"#).await;
+    assert_eq!(token.status, 200, "{}", token.body);
+    encode(token.json()["token"].as_str().unwrap())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn tenants_only_see_their_own_url_maps() {
+    let app = App::new().await;
+    let token = sales(&app).await;
+    let headers = [("authorization", token.as_str()), ("x-user", "bob")];
+    let created = app.request("POST", "/api/url_maps", &headers, r#"{"key":"q3","url":"https://example.com/q3"}"#).await;
+    assert_eq!(created.status, 200, "{}", created.body);
+    app.api("POST", "/api/url_maps", r#"{"key":"gh","url":"https://github.com"}"#).await;
+
+    assert_eq!(app.request("GET", "/api/url_maps/q3", &headers, "").await.status, 200);
+    assert_eq!(app.request("GET", "/api/url_maps/gh", &headers, "").await.status, 404);
+    assert_eq!(app.api("GET", "/api/url_maps/q3", "").await.status, 404);
+    let page = app.request("GET", "/api/url_maps", &headers, "").await.json();
+    let keys = page["url_maps"].as_array().unwrap().iter().map(|url_map| url_map["key"].clone()).collect::<Vec<_>>();
+    assert_eq!(keys, vec!["q3"]);
+
+    let other = [("authorization", token.as_str()), ("x-tenant", "default")];
+    assert_eq!(app.request("GET", "/api/url_maps/gh", &other, "").await.status, 403);
+    app.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn tenants_only_see_their_own_domains() {
+    let app = App::new().await;
+    let token = sales(&app).await;
+    let headers = [("authorization", token.as_str())];
+    let created = app.request("POST", "/api/domains", &headers, r#"{"name":"sales.example.com"}"#).await;
+    assert_eq!(created.status, 200, "{}", created.body);
+    assert_eq!(app.api("POST", "/api/domains", r#"{"name":"go.example.com"}"#).await.status, 200);
+
+    let names = |domains: serde_json::Value| domains.as_array().unwrap().iter().map(|domain| domain["name"].clone()).collect::<Vec<_>>();
+    assert_eq!(names(app.request("GET", "/api/domains", &headers, "").await.json()), vec!["sales.example.com"]);
+    assert_eq!(names(app.api("GET", "/api/domains", "").await.json()), vec!["go.example.com"]);
+    app.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn admin_pages_take_the_tenant_from_the_token() {
+    let app = App::new().await;
+    let token = sales(&app).await;
+    app.api("POST", "/api/url_maps", r#"{"key":"gh","url":"https://github.com"}"#).await;
+    let headers = [("authorization", token.as_str())];
+    app.request("POST", "/api/url_maps", &headers, r#"{"key":"q3","url":"https://example.com/q3"}"#).await;
+
+    let cookie = app.request("GET", "/admin/url_maps", &[("cookie", "tenant=sales")], "").await;
+    assert_eq!(cookie.status, 303);
+    assert_eq!(cookie.location.as_deref(), Some("/admin/login"));
+
+    let cookie = format!("authorization={}; tenant=default", token);
+    let denied = app.request("GET", "/admin/url_maps", &[("cookie", &cookie)], "").await;
+    assert_eq!(denied.status, 403);
+
+    let cookie = format!("authorization={}", token);
+    let url_maps = app.request("GET", "/admin/url_maps", &[("cookie", &cookie)], "").await;
+    assert_eq!(url_maps.status, 200);
+    assert!(url_maps.body.contains("<td>q3</td>"));
+    assert!(!url_maps.body.contains("<td>gh</td>"));
+
+    let tenants = app.request("GET", "/admin/tenants", &[("cookie", &cookie)], "").await;
+    assert_eq!(tenants.status, 200);
+    assert!(tenants.body.contains("sales (current)"));
+    assert!(!tenants.body.contains("<td>default"));
+    assert!(!tenants.body.contains("create_tenant_form"));
+    app.shutdown().await;
+}
@@ -0,0 +1,195 @@
+mod common;
+
+use common::App;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn csv_export_is_imported_back() {
+    let app = App::new().await;
+    assert_eq!(app.api("POST", "/api/domains", r#"{"name":"go.example.com"}"#).await.status, 200);
+    for url_map in [
+        r#"{"key":"gh","url":"https://github.com"}"#,
+        r#"{"key":"docs","url":"https://docs.rs","prefix":true}"#,
+        r#"{"key":"sale","url":"https://example.com/sale","expires_at":"2030-01-01T00:00:00Z","not_before":"2029-01-01T00:00:00Z"}"#,
+        r#"{"key":"once","url":"https://example.com/once","max_clicks":1}"#,
+        r#"{"domain":"go.example.com","key":"gh","url":"https://github.com/go"}"#,
+    ] {
+        assert_eq!(app.api("POST", "/api/url_maps", url_map).await.status, 200);
+    }
+
+    let export = app.api("GET", "/api/url_maps/export?format=csv", "").await;
+    assert_eq!(export.status, 200);
+    let mut lines = export.body.lines();
+    assert_eq!(
+        lines.next().unwrap(),
+        "key,url,created_at,updated_at,created_by,updated_by,expires_at,not_before,max_clicks,used_clicks,domain,prefix",
+    );
+    let rows = csv::Reader::from_reader(export.body.as_bytes())
+        .records()
+        .map(|row| row.unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(rows.len(), 5);
+    assert!(rows.iter().all(|row| row.len() == 12));
+
+    let other = App::new().await;
+    assert_eq!(other.api("POST", "/api/domains", r#"{"name":"go.example.com"}"#).await.status, 200);
+    let import = other.api("POST", "/api/url_maps/import?format=csv", &export.body).await;
+    assert_eq!(import.status, 200, "{}", import.body);
+    let report = import.json();
+    assert_eq!(report["created"], 5, "{}", import.body);
+    assert_eq!(report["rejected"], 0);
+
+    let docs = other.api("GET", "/api/url_maps/docs", "").await.json();
+    assert_eq!(docs["prefix"], true);
+    let sale = other.api("GET", "/api/url_maps/sale", "").await.json();
+    assert_eq!(sale["expires_at"], "2030-01-01T00:00:00Z");
+    assert_eq!(sale["not_before"], "2029-01-01T00:00:00Z");
+    let once = other.api("GET", "/api/url_maps/once", "").await.json();
+    assert_eq!(once["max_clicks"], 1);
+    let gh = other.api("GET", "/api/url_maps/gh?domain=go.example.com", "").await.json();
+    assert_eq!(gh["url"], "https://github.com/go");
+    other.shutdown().await;
+    app.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reserved_keys_are_rejected() {
+    let app = App::new().await;
+    for key in ["export", "import", "batch", "trash", "admin", "api"] {
+        let body = format!(r#"{{"key":"{}","url":"https://example.com"}}"#, key);
+        assert_eq!(app.api("POST", "/api/url_maps", &body).await.status, 400, "{}", key);
+    }
+    assert_eq!(app.api("GET", "/api/url_maps/export", "").await.status, 200);
+    app.shutdown().await;
+}
+
+const BROWSER: &str = "Mozilla/5.0 (X11; Linux x86_64; rv:89.0) Gecko/20100101 Firefox/89.0";
+
+#[tokio::test(flavor = "multi_thread")]
+async fn previews_do_not_use_up_click_limited_links() {
+    let app = App::new().await;
+    app.api("POST", "/api/url_maps", r#"{"key":"once","url":"https://example.com/once","max_clicks":1}"#).await;
+    let slack = [("user-agent", "Slackbot-LinkExpanding 1.0 (+https://api.slack.com/robots)")];
+
+    let unfurl = app.request("GET", "/once", &slack, "").await;
+    assert_eq!(unfurl.status, 303);
+    assert_eq!(unfurl.location.as_deref(), Some("https://example.com/once"));
+    assert_eq!(app.api("GET", "/api/url_maps/once", "").await.json()["used_clicks"], 0);
+
+    let click = app.request("GET", "/once", &[("user-agent", BROWSER)], "").await;
+    assert_eq!(click.status, 303);
+    assert_eq!(click.location.as_deref(), Some("https://example.com/once"));
+    assert_eq!(app.request("GET", "/once", &[("user-agent", BROWSER)], "").await.status, 410);
+    assert_eq!(app.request("GET", "/once", &slack, "").await.status, 410);
+    app.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn scripted_clients_follow_one_time_links() {
+    let app = App::new().await;
+    app.api("POST", "/api/url_maps", r#"{"key":"download","url":"https://example.com/download","max_clicks":1}"#).await;
+
+    let download = app.request("GET", "/download", &[("user-agent", "curl/8.0.1")], "").await;
+    assert_eq!(download.status, 303);
+    assert_eq!(download.location.as_deref(), Some("https://example.com/download"));
+    assert_eq!(app.request("GET", "/download", &[("user-agent", "curl/8.0.1")], "").await.status, 410);
+    app.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn writes_invalidate_cached_redirects() {
+    let app = App::new().await;
+    app.api("POST", "/api/url_maps", r#"{"key":"gh","url":"https://github.com"}"#).await;
+    let redirect = app.request("GET", "/gh", &[("user-agent", BROWSER)], "").await;
+    assert_eq!(redirect.location.as_deref(), Some("https://github.com"));
+
+    app.api("PUT", "/api/url_maps/gh", r#"{"url":"https://gitlab.com"}"#).await;
+    let redirect = app.request("GET", "/gh", &[("user-agent", BROWSER)], "").await;
+    assert_eq!(redirect.location.as_deref(), Some("https://gitlab.com"));
+
+    app.api("DELETE", "/api/url_maps/gh", "").await;
+    assert_eq!(app.request("GET", "/gh", &[("user-agent", BROWSER)], "").await.status, 404);
+    app.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn cursors_page_through_every_url_map_once() {
+    let app = App::new().await;
+    for key in ["e", "a", "d", "b", "c"] {
+        let body = format!(r#"{{"key":"{}","url":"https://example.com/{}"}}"#, key, key);
+        app.api("POST", "/api/url_maps", &body).await;
+    }
+
+    let mut keys = vec![];
+    let mut uri = "/api/url_maps?limit=2&sort=key&order=desc".to_string();
+    loop {
+        let page = app.api("GET", &uri, "").await.json();
+        keys.extend(page["url_maps"].as_array().unwrap().iter().map(|url_map| url_map["key"].as_str().unwrap().to_string()));
+        match page["next"].as_str() {
+            Some(next) => uri = format!("/api/url_maps?limit=2&sort=key&order=desc&cursor={}", next),
+            None => break,
+        }
+    }
+    assert_eq!(keys, vec!["e", "d", "c", "b", "a"]);
+    app.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn failed_imports_roll_back() {
+    let app = App::new().await;
+    app.api("POST", "/api/url_maps", r#"{"key":"gh","url":"https://github.com"}"#).await;
+
+    let csv = "key,url\nnew,https://example.com/new\ngh,https://gitlab.com\n";
+    let import = app.api("POST", "/api/url_maps/import?format=csv&mode=fail", csv).await;
+    assert_eq!(import.status, 409);
+    assert_eq!(import.json()["applied"], false);
+    assert_eq!(app.api("GET", "/api/url_maps/new", "").await.status, 404);
+    assert_eq!(app.api("GET", "/api/url_maps/gh", "").await.json()["url"], "https://github.com");
+    app.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn failed_batches_roll_back() {
+    let app = App::new().await;
+    app.api("POST", "/api/url_maps", r#"{"key":"gh","url":"https://github.com"}"#).await;
+
+    let batch = r#"{"operations":[
+        {"op":"create","key":"other","url":"https://example.com/other"},
+        {"op":"update","key":"gh","url":"https://gitlab.com"},
+        {"op":"delete","key":"missing"}
+    ]}"#;
+    let batch = app.api("POST", "/api/url_maps/batch", batch).await;
+    assert_eq!(batch.status, 409);
+    assert_eq!(app.api("GET", "/api/url_maps/other", "").await.status, 404);
+    assert_eq!(app.api("GET", "/api/url_maps/gh", "").await.json()["url"], "https://github.com");
+    app.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn concurrent_clicks_never_exceed_max_clicks() {
+    let app = App::new().await;
+    app.api("POST", "/api/url_maps", r#"{"key":"once","url":"https://example.com/once","max_clicks":1}"#).await;
+
+    let clicks = (0..8).map(|_| app.request("GET", "/once", &[("user-agent", BROWSER)], ""));
+    let statuses = futures::future::join_all(clicks).await
+        .into_iter()
+        .map(|click| click.status)
+        .collect::<Vec<_>>();
+    assert_eq!(statuses.iter().filter(|status| **status == 303).count(), 1, "{:?}", statuses);
+    assert!(statuses.iter().all(|status| *status == 303 || *status == 410), "{:?}", statuses);
+    assert_eq!(app.api("GET", "/api/url_maps/once", "").await.json()["used_clicks"], 1);
+    app.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn prefix_url_maps_redirect_paths_below_them() {
+    let app = App::new().await;
+    app.api("POST", "/api/url_maps", r#"{"key":"docs","url":"https://example.com/manual?lang=en","prefix":true}"#).await;
+    app.api("POST", "/api/url_maps", r#"{"key":"docs/api","url":"https://api.example.com"}"#).await;
+
+    let redirect = app.request("GET", "/docs/install/linux", &[("user-agent", BROWSER)], "").await;
+    assert_eq!(redirect.location.as_deref(), Some("https://example.com/manual/install/linux?lang=en"));
+    let redirect = app.request("GET", "/docs/api", &[("user-agent", BROWSER)], "").await;
+    assert_eq!(redirect.location.as_deref(), Some("https://api.example.com"));
+    assert_eq!(app.api("GET", "/api/url_maps/docs/api", "").await.json()["url"], "https://api.example.com");
+    app.shutdown().await;
+}